@@ -1,22 +1,74 @@
-use std::{error::Error, ptr::NonNull, mem::size_of};
+use std::{alloc::Layout, error::Error, os::fd::RawFd, ptr::NonNull, mem::{align_of, size_of, MaybeUninit}};
 
 mod sys {
-    use std::{error::Error, ptr::NonNull};
+    use std::{error::Error, ptr::NonNull, sync::OnceLock};
 
     pub type AnyNonNull = NonNull<libc::c_void>;
 
+    static PAGESIZE: OnceLock<usize> = OnceLock::new();
+
+    #[cfg(test)]
+    pub static SYSCONF_CALLS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    #[cfg(test)]
+    thread_local! {
+        /// Stands in for `sysconf` on this thread, to try other page sizes.
+        pub static PAGESIZE_OVERRIDE: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+
+        /// Makes the next `commit` on this thread fail after this many bytes,
+        /// as a kernel running short partway through would.
+        pub static COMMIT_FAILS_AFTER: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+
+        /// Counts the calls to `commit` on this thread.
+        pub static COMMIT_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    /// Asks `sysconf` once and caches the answer; a failed call is not
+    /// cached, so the next caller asks again.
     pub unsafe fn get_pagesize() -> Result<usize, Box<dyn Error>> {
+        #[cfg(test)]
+        if let Some(pagesize) = PAGESIZE_OVERRIDE.get() {
+            return checked_pagesize(pagesize);
+        }
+        if let Some(&pagesize) = PAGESIZE.get() {
+            return Ok(pagesize);
+        }
+        #[cfg(test)]
+        SYSCONF_CALLS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         let pagesize = libc::sysconf(libc::_SC_PAGE_SIZE);
         if pagesize < 0 {
-            Err(std::io::Error::last_os_error().into())
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let pagesize = checked_pagesize(pagesize as usize)?;
+        Ok(*PAGESIZE.get_or_init(|| pagesize))
+    }
+
+    fn checked_pagesize(pagesize: usize) -> Result<usize, Box<dyn Error>> {
+        if pagesize.is_power_of_two() {
+            Ok(pagesize)
+        } else {
+            // All the alignment math masks with `pagesize - 1`.
+            Err(format!("Page size {} is not a power of two.", pagesize).into())
+        }
+    }
+
+    /// Returns the soft `RLIMIT_AS` limit, or `None` if it is unlimited.
+    pub unsafe fn get_address_space_limit() -> Result<Option<usize>, Box<dyn Error>> {
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        if libc::getrlimit(libc::RLIMIT_AS, &mut limit) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if limit.rlim_cur == libc::RLIM_INFINITY {
+            Ok(None)
         } else {
-            Ok(pagesize as usize)
+            Ok(Some(usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX)))
         }
     }
 
-    pub unsafe fn reserve(len: usize) -> Result<AnyNonNull, Box<dyn Error>> {
+    /// * `hint` - An advisory base address, or null to let the kernel choose.
+    pub unsafe fn reserve(len: usize, hint: *mut libc::c_void) -> Result<AnyNonNull, Box<dyn Error>> {
         let ptr = libc::mmap(
-            std::ptr::null_mut(),
+            hint,
             len,
             libc::PROT_NONE,
             libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
@@ -30,6 +82,28 @@ mod sys {
         }
     }
 
+    /// Reserves exactly at `addr`, failing if anything is mapped there.
+    pub unsafe fn reserve_at(addr: AnyNonNull, len: usize) -> Result<(), Box<dyn Error>> {
+        // MAP_FIXED_NOREPLACE was added in Linux 4.17.
+        // Older kernels take it as a hint, so the address is checked too.
+        let ptr = libc::mmap(
+            addr.as_ptr(),
+            len,
+            libc::PROT_NONE,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_FIXED_NOREPLACE,
+            -1,
+            0
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if ptr != addr.as_ptr() {
+            let _ = release(NonNull::new_unchecked(ptr), len);
+            return Err("Address range is already in use.".into());
+        }
+        Ok(())
+    }
+
     #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
     pub enum CommitStrategy {
         Mprotect,
@@ -41,6 +115,13 @@ mod sys {
         len: usize,
         prefer_strategy: CommitStrategy,
     ) -> Result<CommitStrategy, Box<dyn Error>> {
+        #[cfg(test)]
+        COMMIT_CALLS.set(COMMIT_CALLS.get() + 1);
+        #[cfg(test)]
+        if let Some(committed) = COMMIT_FAILS_AFTER.take() {
+            libc::mprotect(addr.as_ptr(), committed.min(len), libc::PROT_READ | libc::PROT_WRITE);
+            return Err(roll_back_commit(addr, len, "Injected partial commit failure.".into()));
+        }
         if prefer_strategy <= CommitStrategy::Mprotect {
             // mprotect was added in Linux 4.9.
             let result = libc::mprotect(
@@ -64,20 +145,25 @@ mod sys {
             0,
         );
         if ptr == libc::MAP_FAILED {
-            Err(std::io::Error::last_os_error().into())
+            Err(roll_back_commit(addr, len, std::io::Error::last_os_error().into()))
         } else {
             Ok(CommitStrategy::MmapFixed)
         }
     }
 
-    #[allow(unused)]
+    /// mprotect may have made some of the pages accessible before failing,
+    /// so take them all back to keep the commit all-or-nothing.
+    unsafe fn roll_back_commit(addr: AnyNonNull, len: usize, error: Box<dyn Error>) -> Box<dyn Error> {
+        let _ = decommit(addr, len, DecommitStrategy::Mprotect);
+        error
+    }
+
     #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
     pub enum DecommitStrategy {
         Mprotect,
         MmapFixed,
     }
 
-    #[allow(unused)]
     pub unsafe fn decommit(
         addr: AnyNonNull,
         len: usize,
@@ -112,6 +198,43 @@ mod sys {
         }
     }
 
+    #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+    pub enum PopulateStrategy {
+        Madvise,
+        Touch,
+    }
+
+    pub unsafe fn populate(
+        addr: AnyNonNull,
+        len: usize,
+        pagesize: usize,
+        prefer_strategy: PopulateStrategy,
+    ) -> Result<PopulateStrategy, Box<dyn Error>> {
+        if prefer_strategy <= PopulateStrategy::Madvise {
+            // MADV_POPULATE_WRITE was added in Linux 5.14.
+            let result = libc::madvise(
+                addr.as_ptr(),
+                len,
+                libc::MADV_POPULATE_WRITE,
+            );
+            if result == 0 {
+                return Ok(PopulateStrategy::Madvise);
+            }
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EINVAL) {
+                return Err(error.into());
+            }
+        }
+
+        // Touching pages cannot report failures, but works on any kernel.
+        let begin = addr.as_ptr() as *mut u8;
+        for offset in (0..len).step_by(pagesize) {
+            let page = begin.add(offset);
+            page.write_volatile(page.read_volatile());
+        }
+        Ok(PopulateStrategy::Touch)
+    }
+
     pub unsafe fn alloc(len: usize) -> Result<AnyNonNull, Box<dyn Error>> {
         let ptr = libc::mmap(
             std::ptr::null_mut(),
@@ -128,6 +251,134 @@ mod sys {
         }
     }
 
+    /// Maps `len` bytes of huge pages over `addr`, which must be huge-page aligned.
+    pub unsafe fn map_hugepages(addr: AnyNonNull, len: usize) -> Result<(), Box<dyn Error>> {
+        let ptr = libc::mmap(
+            addr.as_ptr(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_FIXED | libc::MAP_HUGETLB,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            Err(std::io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Maps `len` bytes for a stack whose lowest `guard_len` bytes are inaccessible.
+    pub unsafe fn alloc_stack(len: usize, guard_len: usize) -> Result<AnyNonNull, Box<dyn Error>> {
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_STACK,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let result = libc::mprotect(ptr, guard_len, libc::PROT_NONE);
+        if result != 0 {
+            let error = std::io::Error::last_os_error();
+            libc::munmap(ptr, len);
+            return Err(error.into());
+        }
+        Ok(NonNull::new_unchecked(ptr))
+    }
+
+    pub unsafe fn protect(addr: AnyNonNull, len: usize, prot: libc::c_int) -> Result<(), Box<dyn Error>> {
+        let result = libc::mprotect(addr.as_ptr(), len, prot);
+        if result != 0 {
+            Err(std::io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Maps `len` bytes of `fd` from `offset` as shared memory over `addr`.
+    pub unsafe fn map_file(
+        addr: AnyNonNull,
+        len: usize,
+        fd: libc::c_int,
+        offset: libc::off_t,
+    ) -> Result<(), Box<dyn Error>> {
+        let ptr = libc::mmap(
+            addr.as_ptr(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_FIXED,
+            fd,
+            offset,
+        );
+        if ptr == libc::MAP_FAILED {
+            Err(std::io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub unsafe fn sync(addr: AnyNonNull, len: usize, async_: bool) -> Result<(), Box<dyn Error>> {
+        let flags = if async_ { libc::MS_ASYNC } else { libc::MS_SYNC };
+        let result = libc::msync(addr.as_ptr(), len, flags);
+        if result != 0 {
+            Err(std::io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Lets the kernel drop the pages when memory runs short, while keeping
+    /// them mapped; a later access faults in either the old data or zeroes.
+    pub unsafe fn advise_free(addr: AnyNonNull, len: usize) -> Result<(), Box<dyn Error>> {
+        if libc::madvise(addr.as_ptr(), len, libc::MADV_FREE) != 0 {
+            Err(std::io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Makes `len` bytes at `addr` readable and writable without charging
+    /// them against the commit limit, so the kernel backs them on first write.
+    pub unsafe fn map_lazily(addr: AnyNonNull, len: usize) -> Result<(), Box<dyn Error>> {
+        let ptr = libc::mmap(
+            addr.as_ptr(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_FIXED | libc::MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            Err(std::io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Drops the pages at `addr`, which stay accessible and read back as zeroes.
+    pub unsafe fn discard(addr: AnyNonNull, len: usize) -> Result<(), Box<dyn Error>> {
+        if libc::madvise(addr.as_ptr(), len, libc::MADV_DONTNEED) != 0 {
+            Err(std::io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Moves or grows the mapping at `addr` to `new_len` bytes without copying.
+    pub unsafe fn remap(addr: AnyNonNull, old_len: usize, new_len: usize) -> Result<AnyNonNull, Box<dyn Error>> {
+        let ptr = libc::mremap(addr.as_ptr(), old_len, new_len, libc::MREMAP_MAYMOVE);
+        if ptr == libc::MAP_FAILED {
+            Err(std::io::Error::last_os_error().into())
+        } else {
+            Ok(NonNull::new_unchecked(ptr))
+        }
+    }
+
     pub unsafe fn release(addr: AnyNonNull, len: usize) -> Result<(), Box<dyn Error>> {
         let result = libc::munmap(addr.as_ptr(), len);
         if result != 0 {
@@ -138,152 +389,7121 @@ mod sys {
     }
 }
 
+#[derive(Debug)]
+pub enum AllocError {
+    OutOfReservedSpace,
+    CommitFailed(Box<dyn Error>),
+    #[allow(unused)]
+    ClassMismatch,
+    #[allow(unused)]
+    ForeignPointer,
+    #[allow(unused)]
+    DoubleFree,
+    ReleaseFailed(Box<dyn Error>),
+    TooLarge,
+    #[allow(unused)]
+    InvalidMapping(&'static str),
+    MapFailed(Box<dyn Error>),
+    #[allow(unused)]
+    InvalidGranularity,
+}
+
+impl std::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AllocError::OutOfReservedSpace => write!(f, "Failed to extend heap size."),
+            AllocError::CommitFailed(error) => write!(f, "Failed to commit heap: {}", error),
+            AllocError::ClassMismatch => write!(f, "Header class does not match the carved block."),
+            AllocError::ForeignPointer => write!(f, "Pointer was not allocated by this allocator."),
+            AllocError::DoubleFree => write!(f, "Block is already on a free list."),
+            AllocError::ReleaseFailed(error) => write!(f, "Failed to release memory: {}", error),
+            AllocError::TooLarge => write!(f, "Requested size is too large."),
+            AllocError::InvalidMapping(reason) => write!(f, "Mapping cannot be adopted: {}", reason),
+            AllocError::MapFailed(error) => write!(f, "Failed to map memory: {}", error),
+            AllocError::InvalidGranularity => write!(f, "Commit granularity must be a nonzero multiple of the page size."),
+        }
+    }
+}
+
+impl Error for AllocError {}
+
 struct Header {
     size_or_class_of_subheap: usize,
+    #[cfg(feature = "debug-headers")]
+    tag: u32,
+    // Taken from the allocator's clock whenever the block is carved or
+    // freed, to catch stale `TrackedPtr`s.
+    #[cfg(feature = "debug-headers")]
+    generation: u32,
 }
 
 const MAX_HEAP_SIZE: usize = 2 << 40;
-const SUBHEAP_COUNT: usize = 7;
+// Classes past the default ones, up to 64 KiB blocks, only serve requests
+// once the external threshold is raised.
+const SUBHEAP_COUNT: usize = 13;
+const DEFAULT_SUBHEAP_COUNT: usize = 7;
 
 const fn block_size_of_subheap(class_of_subheap: usize) -> usize {
     2 << (class_of_subheap + 3)
 }
 
+/// The bytes a block of the class takes from the heap, header included.
+const fn carved_size_of_subheap(class_of_subheap: usize) -> usize {
+    size_of::<Header>() + block_size_of_subheap(class_of_subheap)
+}
+
 /// * `alignment` - A power of 2.
 const fn aligned_size(original: usize, alignment: usize) -> usize {
     let mask = alignment - 1;
-    original + (original.reverse_bits() & mask)
+    (original + mask) & !mask
+}
+
+// Also tells classes from external sizes in a header: every class index is
+// below it and every external size above it.
+const MAX_BLOCK_SIZE: usize = block_size_of_subheap(DEFAULT_SUBHEAP_COUNT - 1);
+
+const DEFERRED_FREE_CAPACITY: usize = 256;
+
+const CACHELINE_SIZE: usize = 64;
+
+// Freed external mappings kept for reuse at most.
+const EXTERNAL_CACHE_CAPACITY: usize = 8;
+
+// The size and alignment of blocks from `Allocator::alloc_hugepage_block`.
+const HUGEPAGE_SIZE: usize = 2 << 20;
+
+// How far ahead a small class commits once its bump pointer runs past
+// the committed end.
+const COALESCED_COMMIT_PAGES: usize = 4;
+
+// The page size of `Allocator::from_buffer` heaps. A buffer is never
+// committed, so its heap takes the smallest Linux page size instead of
+// asking `sysconf`.
+const BUFFER_PAGE_SIZE: usize = 4096;
+
+/// The system page size, without constructing an allocator. Panics if
+/// `sysconf` cannot tell it.
+#[allow(unused)]
+pub fn page_size() -> usize {
+    unsafe { sys::get_pagesize() }.expect("Failed to get the page size.")
+}
+
+/// Returns the size class serving `len` bytes, or `None` if it is too
+/// large for the subheaps and would be allocated externally under the
+/// default external threshold.
+#[allow(unused)]
+pub fn class_for_size(len: usize) -> Option<usize> {
+    (0..DEFAULT_SUBHEAP_COUNT).find(|&class_of_subheap| len <= block_size_of_subheap(class_of_subheap))
+}
+
+/// Returns the usable size of blocks in `class_of_subheap`.
+#[allow(unused)]
+pub fn block_size_for_class(class_of_subheap: usize) -> usize {
+    assert!(class_of_subheap < SUBHEAP_COUNT);
+    block_size_of_subheap(class_of_subheap)
+}
+
+/// Computes at most `max_classes` ascending block sizes serving every
+/// size in `sizes` with the least total slack, by splitting the sorted
+/// sizes into runs that each take the block size of their largest one.
+/// Block sizes are rounded up to keep headers aligned.
+///
+/// Fails if sizes are given but no class is allowed, or if the sizes are
+/// too large to sum up.
+///
+/// This is a tuning aid: the allocator's classes double in size, so the
+/// table tells how far a workload is from them, not a table to run with.
+#[allow(unused)]
+pub fn classes_for_sizes(sizes: &[usize], max_classes: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+    if max_classes == 0 && !sizes.is_empty() {
+        return Err("At least one class is needed to serve the sizes.".into());
+    }
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable();
+
+    // Sizes sharing a block size alone: the block size, then the prefix
+    // sums over them of the sizes and of their counts.
+    let mut block_sizes = Vec::new();
+    let mut size_sums = vec![0usize];
+    let mut counts = vec![0];
+    for &size in &sizes {
+        let mask = align_of::<Header>() - 1;
+        let block_size = size.max(1).checked_add(mask).ok_or(AllocError::TooLarge)? & !mask;
+        if block_sizes.last() != Some(&block_size) {
+            block_sizes.push(block_size);
+            size_sums.push(*size_sums.last().unwrap());
+            counts.push(*counts.last().unwrap());
+        }
+        let size_sum = size_sums.last_mut().unwrap();
+        *size_sum = size_sum.checked_add(size).ok_or(AllocError::TooLarge)?;
+        *counts.last_mut().unwrap() += 1;
+    }
+
+    // The slack of serving the runs `begin..end` from one class.
+    let slack = |begin: usize, end: usize| {
+        let served = block_sizes[end - 1].checked_mul(counts[end] - counts[begin])?;
+        Some(served - (size_sums[end] - size_sums[begin]))
+    };
+
+    // `least[c][end]` is the least slack serving the runs `..end` from
+    // `c` classes, the last of which starts at `starts[c][end]`.
+    let run_count = block_sizes.len();
+    let class_count = max_classes.min(run_count);
+    let mut least = vec![vec![usize::MAX; run_count + 1]; class_count + 1];
+    let mut starts = vec![vec![0; run_count + 1]; class_count + 1];
+    least[0][0] = 0;
+    for c in 1..=class_count {
+        for end in c..=run_count {
+            for begin in c - 1..end {
+                if least[c - 1][begin] == usize::MAX {
+                    continue;
+                }
+                let total = slack(begin, end)
+                    .and_then(|slack| least[c - 1][begin].checked_add(slack))
+                    .ok_or(AllocError::TooLarge)?;
+                if total < least[c][end] {
+                    least[c][end] = total;
+                    starts[c][end] = begin;
+                }
+            }
+        }
+    }
+
+    let mut classes = Vec::with_capacity(class_count);
+    let mut end = run_count;
+    for c in (1..=class_count).rev() {
+        classes.push(block_sizes[end - 1]);
+        end = starts[c][end];
+    }
+    classes.reverse();
+    Ok(classes)
+}
+
+fn random_secret() -> usize {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new().build_hasher().finish() as usize
+}
+
+/// Hints the CPU to pull `ptr` into cache. Does nothing where unsupported.
+#[inline(always)]
+#[allow(unused)]
+fn prefetch_read<T>(ptr: *const T) {
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse"))]
+    unsafe {
+        std::arch::x86_64::_mm_prefetch(ptr as *const i8, std::arch::x86_64::_MM_HINT_T0);
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        std::arch::asm!("prfm pldl1keep, [{0}]", in(reg) ptr, options(nostack, readonly, preserves_flags));
+    }
+}
+
+/// The bytes of `count` values of `T`, or `TooLarge` if that overflows.
+/// Every array size goes through here, so overflow is rejected the same way.
+fn array_bytes<T>(count: usize) -> Result<usize, AllocError> {
+    count.checked_mul(size_of::<T>()).ok_or(AllocError::TooLarge)
 }
 
-const MAX_BLOCK_SIZE: usize = block_size_of_subheap(SUBHEAP_COUNT - 1);
+/// The tag of every block on a free list, so that a header overwritten
+/// while its block was free shows up in `validate_free_list_bytes`.
+#[cfg(feature = "debug-headers")]
+const FREED_TAG: u32 = 0xdead_f4ee;
+
+unsafe fn header_of<T>(ptr: NonNull<T>) -> NonNull<Header> {
+    let allocated_ptr = (ptr.as_ptr() as *mut libc::c_void)
+        .offset(- (size_of::<Header>() as isize));
+    NonNull::new_unchecked(allocated_ptr as *mut Header)
+}
 
 pub struct Allocator {
     // immutable
     pagesize: usize,
+    heap_begin: sys::AnyNonNull,
     heap_end: sys::AnyNonNull,
+    base_hint_honored: bool,
+    owns_reservation: bool,
 
     // mutable
     free_lists: [*mut FreeHeader; SUBHEAP_COUNT],
+    free_policies: [FreePolicy; SUBHEAP_COUNT],
+    // Classes serving requests; larger requests are allocated externally.
+    subheap_count: usize,
+    // Pads each extension so that block data starts on a cache line.
+    cacheline_align: bool,
+    // One region per class when classes are carved apart, in place of the
+    // shared bump pointers above.
+    class_regions: Vec<BumpRegion>,
     active_heap_end: sys::AnyNonNull,
     commited_heap_end: sys::AnyNonNull,
+    // Where the shared bump pointer stops, below blocks from `allocate_at`.
+    carvable_heap_end: sys::AnyNonNull,
 
     prefer_commit_strategy: sys::CommitStrategy,
+    // Commits are rounded up to this multiple of the page size.
+    commit_granularity: usize,
+    prefer_decommit_strategy: sys::DecommitStrategy,
+
+    eager_commit: bool,
+    // The reservation is mapped writable up front, so commits are bookkeeping only.
+    lazy_commit: bool,
+    prefer_populate_strategy: sys::PopulateStrategy,
+    // Advises the pages inside freed blocks with MADV_FREE.
+    lazy_free: bool,
+    // Zeroes freed blocks, for memory that held secrets.
+    zero_on_free: bool,
+    // Blocks carved at once when a free list runs empty.
+    extension_batch: usize,
+
+    // Cap on committed subheap plus external bytes, below the real reservation.
+    soft_limit: Option<usize>,
+    high_watermark: Option<HighWatermark>,
+    error_hook: Option<ErrorHook>,
+    on_alloc: Option<AllocHook>,
+    on_free: Option<FreeHook>,
+    peak_used_bytes: usize,
+
+    free_list_secret: usize,
+    // The last generation given to a subheap block.
+    #[cfg(feature = "debug-headers")]
+    generation_clock: u32,
+
+    // Bytes of live subheap blocks, headers included.
+    live_subheap_bytes: usize,
+    // Carved ranges neither live nor on a free list, from address to size:
+    // split tails and the blocks of reclaimed pages.
+    detached_ranges: std::collections::BTreeMap<usize, usize>,
+    // Pages decommitted inside the carved heap by reclaiming, from page
+    // address to length.
+    decommitted_holes: std::collections::BTreeMap<usize, usize>,
+    // Detached ranges handed to shards, which are never uncarved.
+    shard_ranges: std::collections::BTreeSet<usize>,
+    // Blocks from `allocate_at`, from start address to page-rounded length.
+    placed_ranges: std::collections::BTreeMap<usize, usize>,
+    external_bytes: usize,
+    external_count: usize,
+    // Live external mappings, from base address to size.
+    external_regions: std::collections::BTreeMap<usize, usize>,
+    // Whether freed mid-sized external mappings are kept for reuse.
+    external_reuse: bool,
+    // Freed external mappings kept for reuse, as base address and size.
+    external_cache: Vec<(usize, usize)>,
+    // Bases of live mappings from `alloc_hugepage_block`.
+    hugepage_blocks: std::collections::BTreeSet<usize>,
+    // Bases of live external mappings made read-only or executable.
+    protected_externals: std::collections::BTreeSet<usize>,
+    // Bases of live mappings from `alloc_file_backed`.
+    file_backed_externals: std::collections::BTreeSet<usize>,
+    // Bases of freed huge-page blocks, kept for reuse.
+    hugepage_free_list: Vec<usize>,
+    // Huge-page blocks mapped with normal pages instead.
+    hugepage_fallbacks: usize,
+
+    handle_slots: Vec<HandleSlot>,
+    free_handle_slots: Vec<u32>,
+
+    deferred_frees: Vec<NonNull<u8>>,
+
+    recording: Option<Recording>,
+
+    // Classes of carved blocks, keyed by header address, to catch corrupted headers.
+    #[cfg(debug_assertions)]
+    carved_classes: std::collections::BTreeMap<usize, usize>,
+
+    #[cfg(feature = "timing")]
+    commit_latency: LatencyHistogram,
+    #[cfg(feature = "profile")]
+    syscall_counts: SyscallCounts,
+
+    #[cfg(feature = "fault-injection")]
+    fail_next_commit: bool,
+    #[cfg(feature = "fault-injection")]
+    oom_after: Option<usize>,
+}
+
+/// A part of the reservation carved by bumping `active`, committed up to `committed`.
+#[derive(Clone, Copy)]
+struct BumpRegion {
+    begin: sys::AnyNonNull,
+    end: sys::AnyNonNull,
+    active: sys::AnyNonNull,
+    committed: sys::AnyNonNull,
+    // Whether every block sits a whole number of carved sizes past `begin`,
+    // as in a class region until a carve is padded for alignment.
+    strided: bool,
+}
+
+struct HighWatermark {
+    bytes: usize,
+    crossed: bool,
+    callback: Box<dyn FnMut(usize) + Send>,
 }
 
-struct FreeHeader {
+/// An opaque reference to an allocation made by `alloc_handle`.
+/// Low 32 bits index a slot, high 32 bits are the slot generation.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct Handle(pub u64);
+
+impl Handle {
+    fn new(index: u32, generation: u32) -> Self {
+        Handle((generation as u64) << 32 | index as u64)
+    }
+
+    fn index(self) -> usize {
+        (self.0 & u32::MAX as u64) as usize
+    }
+
+    fn generation(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+}
+
+struct HandleSlot {
+    ptr: Option<NonNull<u8>>,
+    generation: u32,
+}
+
+/// The order in which freed blocks of a class are handed out again.
+///
+/// * `Lifo` - The most recently freed block first, which is still hot in cache.
+/// * `Fifo` - The oldest freed block first, which spreads reuse out and
+///   keeps a freed block untouched for longer, making use-after-free show up.
+/// * `AddressOrdered` - The lowest address first, which keeps the live blocks packed.
+///
+/// `Fifo` and `AddressOrdered` walk the free list on every free.
+#[allow(unused)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum FreePolicy {
+    Lifo,
+    Fifo,
+    AddressOrdered,
+}
+
+pub struct FreeHeader {
     #[allow(unused)]
     header: Header,
     next: *mut FreeHeader,
+    canary: usize,
 }
 
+// The free list link lives in the first word of the block data.
+const _: () = assert!(size_of::<FreeHeader>() <= size_of::<Header>() + block_size_of_subheap(0));
+
 impl Allocator {
     pub unsafe fn init() -> Result<Self, Box<dyn Error>> {
+        Self::with_base_hint(std::ptr::null_mut())
+    }
+
+    /// Reserves the heap preferring to place it at `hint`.
+    /// The hint is advisory; check `base_hint_honored` for the outcome.
+    pub unsafe fn with_base_hint(hint: *mut libc::c_void) -> Result<Self, Box<dyn Error>> {
         let pagesize = sys::get_pagesize()?;
-        assert!(MAX_HEAP_SIZE % pagesize == 0);
+        // Pages larger than usual may not divide the heap size.
+        let max_heap_size = aligned_size(MAX_HEAP_SIZE, pagesize);
 
-        let heap_begin = sys::reserve(MAX_HEAP_SIZE)?;
-        let heap_end = NonNull::new_unchecked(heap_begin.as_ptr().add(MAX_HEAP_SIZE));
-        let free_lists = [std::ptr::null_mut(); SUBHEAP_COUNT];
+        // Leave half of a limited address space for the rest of the process.
+        let heap_size = match sys::get_address_space_limit()? {
+            None => max_heap_size,
+            Some(limit) => max_heap_size.min((limit / 2) & !(pagesize - 1)),
+        };
+        if heap_size == 0 {
+            return Err("RLIMIT_AS is too low to reserve a heap.".into());
+        }
 
-        Ok(Self {
-            pagesize,
-            heap_end,
-            free_lists,
-            active_heap_end: heap_begin,
-            commited_heap_end: heap_begin,
-            prefer_commit_strategy: sys::CommitStrategy::Mprotect,
-        })
-    }
+        let heap_begin = sys::reserve(heap_size, hint)?;
+        let heap_end = NonNull::new_unchecked(heap_begin.as_ptr().add(heap_size));
 
-    pub unsafe fn alloc<T: Sized>(&mut self) -> Result<NonNull<T>, Box<dyn Error>> {
-        self.alloc_by_size(size_of::<T>())
+        let mut allocator = Self::with_heap(pagesize, heap_begin, heap_end, heap_begin);
+        #[cfg(feature = "profile")]
+        {
+            allocator.syscall_counts.reserve += 1;
+        }
+        allocator.base_hint_honored = !hint.is_null() && heap_begin.as_ptr() == hint;
+        allocator.owns_reservation = true;
+        Ok(allocator)
     }
 
-    pub unsafe fn alloc_by_size<T>(&mut self, len: usize) -> Result<NonNull<T>, Box<dyn Error>> {
-        if len <= MAX_BLOCK_SIZE {
-            for class_of_subheap in 0..SUBHEAP_COUNT {
-                if len <= block_size_of_subheap(class_of_subheap) {
-                    return self.alloc_on_subheap(class_of_subheap);
-                }
-            }
-            self.alloc_on_subheap(SUBHEAP_COUNT - 1)
-        } else {
-            self.alloc_on_external(len)
+    /// Reserves a `len` byte heap exactly at `base`, failing if the range is
+    /// mapped, so that tests can check exact block addresses.
+    ///
+    /// For tests only: a fixed address can collide with mappings made
+    /// later by anything else in the process.
+    #[allow(unused)]
+    pub unsafe fn init_at(base: *mut libc::c_void, len: usize) -> Result<Self, Box<dyn Error>> {
+        let pagesize = sys::get_pagesize()?;
+        if !len.is_multiple_of(pagesize) || !(base as usize).is_multiple_of(pagesize) {
+            return Err("Base and length must be page aligned.".into());
         }
-    }
+        let heap_begin = NonNull::new(base).ok_or("Base must not be null.")?;
+        let heap_end = NonNull::new(base.wrapping_add(len))
+            .filter(|&heap_end| heap_begin < heap_end)
+            .ok_or("Heap must not be empty or wrap around.")?;
+        sys::reserve_at(heap_begin, len)?;
 
-    pub unsafe fn free<T>(&mut self, ptr: NonNull<T>) -> Result<(), Box<dyn Error>> {
-        let allocated_ptr = (ptr.as_ptr() as *mut libc::c_void)
-            .offset(- (size_of::<Header>() as isize));
-        let allocated_ptr = NonNull::new_unchecked(allocated_ptr as *mut Header);
-        
-        let size_or_class_of_subheap = allocated_ptr.as_ref().size_or_class_of_subheap;
-        if size_or_class_of_subheap <= MAX_BLOCK_SIZE {
-            let class_of_subheap = size_or_class_of_subheap;
-            self.free_on_subheap(allocated_ptr, class_of_subheap)
-        } else {
-            let size = size_or_class_of_subheap;
-            self.free_on_external(allocated_ptr, size)
+        let mut allocator = Self::with_heap(pagesize, heap_begin, heap_end, heap_begin);
+        #[cfg(feature = "profile")]
+        {
+            allocator.syscall_counts.reserve += 1;
         }
+        allocator.base_hint_honored = true;
+        allocator.owns_reservation = true;
+        Ok(allocator)
     }
 
-    unsafe fn alloc_on_subheap<T>(&mut self, class_of_subheap: usize) -> Result<NonNull<T>, Box<dyn Error>> {
-        match NonNull::new(self.free_lists[class_of_subheap]) {
-            None => {
-                let allocated_ptr = self.extend_active_heap_end(class_of_subheap)?;
-                let allocated_ptr: NonNull<libc::c_void> = allocated_ptr.cast();
-                Ok(NonNull::new_unchecked(allocated_ptr.as_ptr().add(size_of::<Header>()) as *mut T))
-            }
-            Some(free_ptr) => {
-                self.free_lists[class_of_subheap] = free_ptr.as_ref().next;
-                let used_ptr: NonNull<libc::c_void> = free_ptr.cast();
-                Ok(NonNull::new_unchecked(used_ptr.as_ptr().add(size_of::<Header>()) as *mut T))
-            }
+    /// Builds a fresh allocator with its own reservation and the same
+    /// configuration as `self`, without any of its live state.
+    #[allow(unused)]
+    pub unsafe fn clone_config(&self) -> Result<Self, Box<dyn Error>> {
+        let mut allocator = Self::init()?;
+        allocator.prefer_commit_strategy = self.prefer_commit_strategy;
+        allocator.commit_granularity = self.commit_granularity;
+        allocator.prefer_decommit_strategy = self.prefer_decommit_strategy;
+        allocator.eager_commit = self.eager_commit;
+        allocator.lazy_free = self.lazy_free;
+        allocator.zero_on_free = self.zero_on_free;
+        allocator.extension_batch = self.extension_batch;
+        allocator.prefer_populate_strategy = self.prefer_populate_strategy;
+        allocator.free_policies = self.free_policies;
+        allocator.subheap_count = self.subheap_count;
+        allocator.cacheline_align = self.cacheline_align;
+        allocator.external_reuse = self.external_reuse;
+        if !self.class_regions.is_empty() {
+            allocator.set_class_regions()?;
         }
+        if self.lazy_commit {
+            allocator.set_lazy_commit()?;
+        }
+        allocator.soft_limit = self.soft_limit;
+        Ok(allocator)
     }
 
-    unsafe fn free_on_subheap(&mut self, addr: NonNull<Header>, class_of_subheap: usize) -> Result<(), Box<dyn Error>> {
-        let mut addr: NonNull<FreeHeader> = addr.cast();
-        addr.as_mut().next = self.free_lists[class_of_subheap];
-        self.free_lists[class_of_subheap] = addr.as_ptr();
+    /// Uses `buf` as the heap instead of reserving one with mmap.
+    /// The buffer is already usable, so it is never committed, and no
+    /// syscall is made until an allocation goes external. The page size
+    /// is `BUFFER_PAGE_SIZE`, whatever the system's.
+    /// `buf` must outlive the allocator.
+    #[allow(unused)]
+    pub unsafe fn from_buffer(buf: &mut [MaybeUninit<u8>]) -> Result<Self, Box<dyn Error>> {
+        let pagesize = BUFFER_PAGE_SIZE;
+
+        let buf_begin = buf.as_mut_ptr();
+        let padding = buf_begin.align_offset(align_of::<Header>());
+        if padding > buf.len() {
+            return Err("Buffer is too small.".into());
+        }
+
+        let heap_begin = NonNull::new_unchecked(buf_begin.add(padding) as *mut libc::c_void);
+        let heap_end = NonNull::new_unchecked(buf_begin.add(buf.len()) as *mut libc::c_void);
+        Ok(Self::with_heap(pagesize, heap_begin, heap_end, heap_end))
+    }
+
+    /// Manages `len` bytes at `base`, a region the caller reserved with
+    /// `PROT_NONE` and keeps ownership of: it is not released on drop.
+    /// `pagesize` must be a power of two and a multiple of the system page
+    /// size, and `base` and `len` multiples of it.
+    #[allow(unused)]
+    pub unsafe fn from_existing_mapping(
+        base: NonNull<libc::c_void>,
+        len: usize,
+        pagesize: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        if !pagesize.is_power_of_two() || !pagesize.is_multiple_of(sys::get_pagesize()?) {
+            return Err(AllocError::InvalidMapping("page size is not a multiple of the system page size").into());
+        }
+        if !(base.as_ptr() as usize).is_multiple_of(pagesize) {
+            return Err(AllocError::InvalidMapping("base is not page aligned").into());
+        }
+        if len == 0 || !len.is_multiple_of(pagesize) {
+            return Err(AllocError::InvalidMapping("length is not a nonzero multiple of the page size").into());
+        }
+
+        let heap_end = NonNull::new_unchecked(base.as_ptr().add(len));
+        Ok(Self::with_heap(pagesize, base, heap_end, base))
+    }
+
+    fn with_heap(
+        pagesize: usize,
+        heap_begin: sys::AnyNonNull,
+        heap_end: sys::AnyNonNull,
+        commited_heap_end: sys::AnyNonNull,
+    ) -> Self {
+        Self {
+            pagesize,
+            heap_begin,
+            heap_end,
+            base_hint_honored: false,
+            owns_reservation: false,
+            free_lists: [std::ptr::null_mut(); SUBHEAP_COUNT],
+            free_policies: [FreePolicy::Lifo; SUBHEAP_COUNT],
+            subheap_count: DEFAULT_SUBHEAP_COUNT,
+            cacheline_align: false,
+            class_regions: Vec::new(),
+            active_heap_end: heap_begin,
+            commited_heap_end,
+            carvable_heap_end: heap_end,
+            prefer_commit_strategy: sys::CommitStrategy::Mprotect,
+            commit_granularity: pagesize,
+            prefer_decommit_strategy: sys::DecommitStrategy::Mprotect,
+            eager_commit: false,
+            lazy_commit: false,
+            lazy_free: false,
+            zero_on_free: false,
+            extension_batch: 1,
+            prefer_populate_strategy: sys::PopulateStrategy::Madvise,
+            soft_limit: None,
+            high_watermark: None,
+            error_hook: None,
+            on_alloc: None,
+            on_free: None,
+            peak_used_bytes: 0,
+            free_list_secret: random_secret(),
+            #[cfg(feature = "debug-headers")]
+            generation_clock: 0,
+            live_subheap_bytes: 0,
+            detached_ranges: std::collections::BTreeMap::new(),
+            decommitted_holes: std::collections::BTreeMap::new(),
+            shard_ranges: std::collections::BTreeSet::new(),
+            placed_ranges: std::collections::BTreeMap::new(),
+            external_bytes: 0,
+            external_count: 0,
+            external_regions: std::collections::BTreeMap::new(),
+            external_reuse: false,
+            external_cache: Vec::new(),
+            hugepage_blocks: std::collections::BTreeSet::new(),
+            protected_externals: std::collections::BTreeSet::new(),
+            file_backed_externals: std::collections::BTreeSet::new(),
+            hugepage_free_list: Vec::new(),
+            hugepage_fallbacks: 0,
+            handle_slots: Vec::new(),
+            free_handle_slots: Vec::new(),
+            deferred_frees: Vec::new(),
+            recording: None,
+            #[cfg(debug_assertions)]
+            carved_classes: std::collections::BTreeMap::new(),
+            #[cfg(feature = "timing")]
+            commit_latency: LatencyHistogram::new(),
+            #[cfg(feature = "profile")]
+            syscall_counts: SyscallCounts::default(),
+            #[cfg(feature = "fault-injection")]
+            fail_next_commit: false,
+            #[cfg(feature = "fault-injection")]
+            oom_after: None,
+        }
+    }
+
+    /// Allocates room for a `T`, typed as uninitialized until it is written.
+    #[track_caller]
+    pub unsafe fn alloc_uninit<T: Sized>(&mut self) -> Result<NonNull<MaybeUninit<T>>, Box<dyn Error>> {
+        self.alloc_by_size(size_of::<T>())
+    }
+
+    #[track_caller]
+    pub unsafe fn alloc<T: Sized>(&mut self) -> Result<NonNull<T>, Box<dyn Error>> {
+        Ok(self.alloc_uninit::<T>()?.cast())
+    }
+
+    /// Allocates room for `count` values of `T`, typed as uninitialized.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn alloc_array<T: Sized>(&mut self, count: usize) -> Result<NonNull<MaybeUninit<T>>, Box<dyn Error>> {
+        self.alloc_by_size(array_bytes::<T>(count)?)
+    }
+
+    /// Allocates `count` values of `T` with every byte zeroed.
+    #[allow(unused)]
+    pub unsafe fn calloc<T: Sized>(&mut self, count: usize) -> Result<NonNull<T>, Box<dyn Error>> {
+        self.alloc_filled(array_bytes::<T>(count)?, 0)
+    }
+
+    /// Allocates room for `count` values of `T`, as an uninitialized slice.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn alloc_slice<T: Sized>(&mut self, count: usize) -> Result<NonNull<[MaybeUninit<T>]>, Box<dyn Error>> {
+        Ok(NonNull::slice_from_raw_parts(self.alloc_array::<T>(count)?, count))
+    }
+
+    /// The common case, popping a free list, is inlined into the caller;
+    /// everything else goes through the out-of-line slow path.
+    #[inline(always)]
+    #[track_caller]
+    pub unsafe fn alloc_by_size<T>(&mut self, len: usize) -> Result<NonNull<T>, Box<dyn Error>> {
+        if self.fast_path_enabled() {
+            // Rounds up to the power of two block size, from 16 bytes.
+            let class_of_subheap = ((len.max(1) - 1) | 15).ilog2() as usize - 3;
+            if class_of_subheap < self.subheap_count {
+                if let Some(ptr) = self.pop_free_list(class_of_subheap) {
+                    self.peak_used_bytes = self.peak_used_bytes.max(self.used_bytes());
+                    return Ok(ptr);
+                }
+            }
+        }
+        self.alloc_by_size_slow(len)
+    }
+
+    #[cold]
+    #[inline(never)]
+    #[track_caller]
+    unsafe fn alloc_by_size_slow<T>(&mut self, len: usize) -> Result<NonNull<T>, Box<dyn Error>> {
+        let error = match self.try_alloc_by_size(len) {
+            Ok(ptr) => return Ok(ptr),
+            Err(error) => error,
+        };
+        let action = match (&mut self.error_hook, error.downcast_ref::<AllocError>()) {
+            (Some(hook), Some(alloc_error)) => hook(alloc_error),
+            _ => ErrorAction::Propagate,
+        };
+        match action {
+            ErrorAction::Propagate => Err(error),
+            ErrorAction::Abort => std::process::abort(),
+            ErrorAction::RetryAfterTrim => {
+                self.release_to_os(ReleaseLevel::Aggressive)?;
+                self.try_alloc_by_size(len)
+            }
+        }
+    }
+
+    #[track_caller]
+    unsafe fn try_alloc_by_size<T>(&mut self, len: usize) -> Result<NonNull<T>, Box<dyn Error>> {
+        // Reject sizes whose header and page rounding would overflow.
+        if self.external_size(len).is_none() {
+            return Err(AllocError::TooLarge.into());
+        }
+
+        #[cfg(feature = "fault-injection")]
+        self.count_down_oom()?;
+
+        let ptr = match (0..self.subheap_count).find(|&class_of_subheap| len <= block_size_of_subheap(class_of_subheap)) {
+            Some(class_of_subheap) => self.alloc_on_subheap(class_of_subheap)?,
+            None => self.alloc_on_external(len)?,
+        };
+        self.note_alloc(ptr.cast(), len);
+        self.check_high_watermark();
+        Ok(ptr)
+    }
+
+    /// Allocates `len` bytes and fills the whole usable size with `byte`.
+    #[allow(unused)]
+    pub unsafe fn alloc_filled<T>(&mut self, len: usize, byte: u8) -> Result<NonNull<T>, Box<dyn Error>> {
+        let ptr: NonNull<u8> = self.alloc_by_size(len)?;
+        let header = header_of(ptr);
+        let is_external = header.as_ref().size_or_class_of_subheap > MAX_BLOCK_SIZE;
+        let usable_size = self.usable_size_of(header);
+
+        // Fresh external mappings are already zero-filled, reused ones are not.
+        if !is_external || byte != 0 || self.external_reuse {
+            std::ptr::write_bytes(ptr.as_ptr(), byte, usable_size);
+        }
+        Ok(ptr.cast())
+    }
+
+    /// Whether the reservation was placed at the requested base hint.
+    #[allow(unused)]
+    pub fn base_hint_honored(&self) -> bool {
+        self.base_hint_honored
+    }
+
+    /// Total bytes mapped by live external allocations, outside the reservation.
+    #[allow(unused)]
+    pub fn external_bytes(&self) -> usize {
+        self.external_bytes
+    }
+
+    /// Number of live external allocations.
+    #[allow(unused)]
+    pub fn external_count(&self) -> usize {
+        self.external_count
+    }
+
+    /// Number of blocks from `alloc_hugepage_block` mapped with normal
+    /// pages because no huge page was available.
+    #[allow(unused)]
+    pub fn hugepage_fallbacks(&self) -> usize {
+        self.hugepage_fallbacks
+    }
+
+    /// Bytes held by live allocations, subheap and external, including
+    /// their headers and page rounding. Freed blocks are not counted.
+    #[allow(unused)]
+    pub fn used_bytes(&self) -> usize {
+        self.live_subheap_bytes + self.external_bytes
+    }
+
+    /// The highest `used_bytes` seen after an allocation.
+    #[allow(unused)]
+    pub fn peak_used_bytes(&self) -> usize {
+        self.peak_used_bytes
+    }
+
+    /// The fraction of committed subheap bytes sitting on free lists, from
+    /// 0.0 when none are idle to 1.0 when all are, for deciding whether
+    /// to trim. An allocator with nothing committed reports 0.0.
+    #[allow(unused)]
+    pub fn free_list_utilization(&self) -> f64 {
+        let committed = self.committed_subheap_bytes();
+        if committed == 0 {
+            return 0.0;
+        }
+        let free_bytes = self.carved_bytes() - self.live_subheap_bytes - self.detached_ranges.values().sum::<usize>();
+        free_bytes as f64 / committed as f64
+    }
+
+    /// The block size of the largest class that can be served without a
+    /// new commit, from its free list or from committed space past its
+    /// bump pointer, or 0 if every class would need one. Larger requests
+    /// go to the OS either way.
+    #[allow(unused)]
+    pub fn largest_available_block(&self) -> usize {
+        let align = if self.cacheline_align { CACHELINE_SIZE } else { align_of::<Header>() };
+        (0..self.subheap_count)
+            .rev()
+            .find(|&class_of_subheap| {
+                if !self.free_lists[class_of_subheap].is_null() {
+                    return true;
+                }
+                let region = self.bump_region(class_of_subheap);
+                let data = region.active.as_ptr() as usize + size_of::<Header>();
+                let padding = aligned_size(data, align) - data;
+                let committed = region.committed.as_ptr() as usize - region.active.as_ptr() as usize;
+                carved_size_of_subheap(class_of_subheap) + padding <= committed
+            })
+            .map_or(0, block_size_of_subheap)
+    }
+
+    /// The number of live blocks of each class, as the blocks carved from
+    /// the heap less those on the free lists. Live external allocations
+    /// are counted by `external_count`. The heap is walked on every call.
+    #[allow(unused)]
+    pub fn live_class_histogram(&self) -> [usize; SUBHEAP_COUNT] {
+        let mut histogram = [0usize; SUBHEAP_COUNT];
+        for block in unsafe { self.carved_blocks() } {
+            histogram[unsafe { block.as_ref() }.size_or_class_of_subheap] += 1;
+        }
+        self.for_each_free_block(|class_of_subheap, _| {
+            histogram[class_of_subheap] = histogram[class_of_subheap].saturating_sub(1);
+        });
+        histogram
+    }
+
+    /// Reports the allocator's accounting as a JSON object, for scraping
+    /// by dashboards. Free counts come from walks capped as in
+    /// `for_each_free_block`.
+    #[allow(unused)]
+    pub fn stats_json(&self) -> String {
+        let mut free_counts = [0; SUBHEAP_COUNT];
+        self.for_each_free_block(|class_of_subheap, _| free_counts[class_of_subheap] += 1);
+        let classes: Vec<String> = (0..self.subheap_count)
+            .map(|class_of_subheap| {
+                format!(
+                    "{{\"class\":{},\"block_size\":{},\"free_count\":{}}}",
+                    class_of_subheap,
+                    block_size_of_subheap(class_of_subheap),
+                    free_counts[class_of_subheap],
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"committed_bytes\":{},\"active_bytes\":{},\"used_bytes\":{},\"peak_used_bytes\":{},\
+             \"external_count\":{},\"external_bytes\":{},\"commit_strategy\":\"{:?}\",\"classes\":[{}]}}",
+            self.committed_subheap_bytes(),
+            self.carved_bytes(),
+            self.used_bytes(),
+            self.peak_used_bytes,
+            self.external_count,
+            self.external_bytes,
+            self.prefer_commit_strategy,
+            classes.join(","),
+        )
+    }
+
+    /// Size of the whole heap, committed or not.
+    #[allow(unused)]
+    pub fn capacity_total(&self) -> usize {
+        self.heap_end.as_ptr() as usize - self.heap_begin.as_ptr() as usize
+    }
+
+    /// Base and length of the heap, for placing other mappings around it.
+    /// Every subheap block lies inside; external allocations lie outside.
+    #[allow(unused)]
+    pub fn reserved_range(&self) -> (NonNull<libc::c_void>, usize) {
+        (self.heap_begin, self.capacity_total())
+    }
+
+    /// Page size the allocator commits and maps with.
+    #[allow(unused)]
+    pub fn page_size(&self) -> usize {
+        self.pagesize
+    }
+
+    /// Largest request served from a subheap; larger ones take the external path.
+    #[allow(unused)]
+    pub fn max_block_size(&self) -> usize {
+        block_size_of_subheap(self.subheap_count - 1)
+    }
+
+    /// Number of subheap size classes serving requests.
+    #[allow(unused)]
+    pub fn subheap_count(&self) -> usize {
+        self.subheap_count
+    }
+
+    /// Serves requests up to `bytes` from the subheaps by enabling larger
+    /// size classes, instead of mapping each one externally.
+    ///
+    /// Classes keep doubling past `MAX_BLOCK_SIZE` up to 64 KiB blocks, so
+    /// the threshold is rounded down to a class block size and capped there.
+    /// Thresholds below `MAX_BLOCK_SIZE` keep the default classes. Blocks of
+    /// a class stay valid when the threshold is lowered again.
+    #[allow(unused)]
+    pub fn set_external_threshold(&mut self, bytes: usize) {
+        self.subheap_count = (DEFAULT_SUBHEAP_COUNT..=SUBHEAP_COUNT)
+            .take_while(|&count| block_size_of_subheap(count - 1) <= bytes)
+            .last()
+            .unwrap_or(DEFAULT_SUBHEAP_COUNT);
+    }
+
+    /// Iterates the committed memory: the committed part of the subheap
+    /// reservation first, split around the pages reclaimed from it, then
+    /// each live external mapping.
+    #[allow(unused)]
+    pub fn committed_ranges(&self) -> impl Iterator<Item = (NonNull<u8>, usize)> + '_ {
+        let mut subheap = Vec::new();
+        for region in self.bump_regions() {
+            let mut begin = region.begin.as_ptr() as usize;
+            let end = region.committed.as_ptr() as usize;
+            for (&hole, &len) in self.decommitted_holes.range(begin..end) {
+                if begin < hole {
+                    subheap.push((begin, hole - begin));
+                }
+                begin = hole + len;
+            }
+            if begin < end {
+                subheap.push((begin, end - begin));
+            }
+        }
+        let subheap = subheap.into_iter().map(|(addr, len)| {
+            (unsafe { NonNull::new_unchecked(addr as *mut u8) }, len)
+        });
+        let externals = self.external_regions.iter().map(|(&addr, &size)| {
+            (unsafe { NonNull::new_unchecked(addr as *mut u8) }, size)
+        });
+        subheap.chain(externals)
+    }
+
+    /// Carves every class from its own equal slice of the reservation, so
+    /// that classes do not interleave and each can be reclaimed alone.
+    ///
+    /// Nothing more is reserved, but a class can then only grow to its
+    /// slice of the reservation, and larger free blocks are no longer split
+    /// for smaller classes. Must be called before any block is carved.
+    #[allow(unused)]
+    pub fn set_class_regions(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.active_heap_end != self.heap_begin || !self.class_regions.is_empty() || !self.placed_ranges.is_empty() {
+            return Err("Blocks were already carved from the shared region.".into());
+        }
+        let region_size = (self.capacity_total() / SUBHEAP_COUNT) & !(self.pagesize - 1);
+        self.class_regions = (0..SUBHEAP_COUNT)
+            .map(|class_of_subheap| unsafe {
+                let begin = NonNull::new_unchecked(self.heap_begin.as_ptr().add(class_of_subheap * region_size));
+                let end = NonNull::new_unchecked(begin.as_ptr().add(region_size));
+                // `from_buffer` heaps are committed up front.
+                let committed = self.commited_heap_end.clamp(begin, end);
+                BumpRegion { begin, end, active: begin, committed, strided: true }
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Rounds every commit up to `bytes`, a multiple of the page size, to
+    /// trade memory for fewer commit syscalls. The page size, the default,
+    /// commits the least; the bytes committed past the carved end are
+    /// decommitted again by `release_to_os`. Other sizes fail with
+    /// `AllocError::InvalidGranularity`, leaving the granularity as it was.
+    #[allow(unused)]
+    pub fn set_commit_granularity(&mut self, bytes: usize) -> Result<(), AllocError> {
+        if bytes == 0 || !bytes.is_multiple_of(self.pagesize) {
+            return Err(AllocError::InvalidGranularity);
+        }
+        self.commit_granularity = bytes;
+        Ok(())
+    }
+
+    /// Carves `count` blocks at once, in a single commit, whenever a class's
+    /// free list is empty and no larger block can be split, leaving all but
+    /// one on the free list for the next allocations of the class. The
+    /// default of 1 carves one block at a time, as does cache line
+    /// alignment. Small classes still commit `COALESCED_COMMIT_PAGES` ahead
+    /// when they cross the committed end.
+    #[allow(unused)]
+    pub fn set_extension_batch(&mut self, count: usize) {
+        self.extension_batch = count.max(1);
+    }
+
+    /// Sets the order in which freed blocks of `class_of_subheap` are reused.
+    /// Blocks already on the free list keep their current order.
+    #[allow(unused)]
+    pub fn set_free_policy(&mut self, class_of_subheap: usize, policy: FreePolicy) {
+        self.free_policies[class_of_subheap] = policy;
+    }
+
+    /// Prepares the allocator for use in the child after `fork`.
+    ///
+    /// The child gets a copy-on-write copy of the heap, the free lists and
+    /// every private external mapping, so both processes can keep
+    /// allocating without seeing each other's blocks. File-backed
+    /// allocations stay shared with the parent, and freeing one in either
+    /// process only unmaps it there. The child is a fresh process, so the
+    /// cached commit and populate strategies are probed again, and with
+    /// `harden` the free lists are re-keyed so the two processes do not
+    /// share a secret.
+    #[allow(unused)]
+    pub fn on_fork_child(&mut self) {
+        self.prefer_commit_strategy = sys::CommitStrategy::Mprotect;
+        self.prefer_decommit_strategy = sys::DecommitStrategy::Mprotect;
+        self.prefer_populate_strategy = sys::PopulateStrategy::Madvise;
+
+        #[cfg(feature = "harden")]
+        {
+            let mut nodes = Vec::new();
+            for class_of_subheap in 0..SUBHEAP_COUNT {
+                let mut node = self.free_lists[class_of_subheap];
+                while let Some(free_ptr) = NonNull::new(node) {
+                    node = unsafe { self.next_of(free_ptr) };
+                    nodes.push((free_ptr, node));
+                }
+            }
+            self.free_list_secret = random_secret();
+            for (free_ptr, next) in nodes {
+                unsafe { self.set_next(free_ptr, next) };
+            }
+        }
+    }
+
+    /// Durations of the commit and decommit syscalls made so far.
+    #[cfg(feature = "timing")]
+    #[allow(unused)]
+    pub fn commit_latency_histogram(&self) -> &LatencyHistogram {
+        &self.commit_latency
+    }
+
+    /// Memory syscalls this allocator has made so far, by kind.
+    #[cfg(feature = "profile")]
+    #[allow(unused)]
+    pub fn syscall_counts(&self) -> SyscallCounts {
+        self.syscall_counts
+    }
+
+    /// Maps the rest of the reservation writable at once and lets the kernel
+    /// fault pages in on first write, so growing the heap makes no commit
+    /// syscalls. The allocator no longer controls what is committed: trims
+    /// and reclaims drop pages with `madvise` instead of decommitting them.
+    /// It cannot be turned off again, and heaps over a caller's buffer or
+    /// mapping are left alone.
+    #[allow(unused)]
+    pub unsafe fn set_lazy_commit(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.owns_reservation {
+            return Err("Only an owned reservation can be mapped up front.".into());
+        }
+        for region in self.bump_regions() {
+            let len = region.end.as_ptr() as usize - region.committed.as_ptr() as usize;
+            if len > 0 {
+                #[cfg(feature = "profile")]
+                {
+                    self.syscall_counts.map += 1;
+                }
+                sys::map_lazily(region.committed, len).map_err(AllocError::CommitFailed)?;
+            }
+        }
+        self.lazy_commit = true;
         Ok(())
     }
 
-    unsafe fn alloc_on_external<T>(&mut self, len: usize) -> Result<NonNull<T>, Box<dyn Error>> {
-        let allocated_size = aligned_size(len + size_of::<Header>(), self.pagesize);
-        let mut allocated_ptr: NonNull<Header> = sys::alloc(allocated_size)?.cast();
-        *allocated_ptr.as_mut() = Header {
-            size_or_class_of_subheap: allocated_size,
+    /// Makes every commit also fault in its pages up front.
+    ///
+    /// Committing only reserves address space, so with overcommit a later
+    /// first write can still get the process OOM-killed. In this mode the
+    /// pages are backed at commit time, and a shortage is returned from
+    /// `alloc` instead when the kernel supports `MADV_POPULATE_WRITE`.
+    /// Older kernels fall back to touching each page, which cannot report
+    /// a shortage and may still trigger the OOM killer, just earlier.
+    #[allow(unused)]
+    pub fn set_eager_commit(&mut self, enabled: bool) {
+        self.eager_commit = enabled;
+    }
+
+    /// Advises the whole pages inside each freed block with `MADV_FREE`,
+    /// keeping the block on its free list. The kernel may then reclaim the
+    /// pages under memory pressure, and a reused block re-faults them,
+    /// reading back either its old bytes or zeroes.
+    ///
+    /// The first bytes of a free block hold its link, so only blocks over a
+    /// page, of the classes above the default external threshold, have
+    /// pages to advise; `reclaim_empty_subheap_pages` handles smaller ones.
+    /// Heaps over a caller's buffer or mapping are left alone.
+    #[allow(unused)]
+    pub fn set_lazy_free(&mut self, enabled: bool) {
+        self.lazy_free = enabled;
+    }
+
+    /// Zeroes every block as it is freed, so secrets do not linger on the
+    /// free lists or in pages waiting to be reclaimed. Subheap blocks keep
+    /// only their free list link; external ones are zeroed before munmap,
+    /// except sealed, executable and file-backed ones.
+    #[allow(unused)]
+    pub fn set_zero_on_free(&mut self, enabled: bool) {
+        self.zero_on_free = enabled;
+    }
+
+    /// Fails allocations with `AllocError::OutOfReservedSpace` once the
+    /// committed subheap and external mappings would exceed `bytes`, to
+    /// simulate a constrained environment. Committed subheap pages are
+    /// kept, so only freeing external allocations or reusing freed blocks
+    /// makes room again.
+    #[allow(unused)]
+    pub fn set_soft_limit(&mut self, bytes: usize) {
+        self.soft_limit = Some(bytes);
+    }
+
+    /// Starts the data of newly carved blocks on a `CACHELINE_SIZE`
+    /// boundary, so that blocks used by different threads do not share a
+    /// cache line. The padding before each block is never reused, and
+    /// larger free blocks are no longer split as that would break the
+    /// alignment. Blocks carved before enabling it are not moved.
+    #[allow(unused)]
+    pub fn set_cacheline_align(&mut self, enabled: bool) {
+        self.cacheline_align = enabled;
+    }
+
+    /// Keeps the mappings of freed external allocations no larger than the
+    /// largest class could serve, up to `EXTERNAL_CACHE_CAPACITY` of them,
+    /// and hands one back to the next external allocation of the same size
+    /// instead of mapping anew. Reused mappings are not zero-filled.
+    #[allow(unused)]
+    pub fn set_external_reuse(&mut self, enabled: bool) {
+        self.external_reuse = enabled;
+    }
+
+    /// Calls `on_alloc` with the pointer, requested size and a call-site id
+    /// after each successful allocation of any kind, and `on_free` with the
+    /// pointer after each successful `free`, to drive an external heap
+    /// profiler. A `realloc` that moves a mapping reports a free and an
+    /// allocation. The call-site id is cheap but names only the direct
+    /// caller of the allocating method, not a whole stack.
+    ///
+    /// To feed heaptrack, forward the hooks to `heaptrack_report_alloc`
+    /// and `heaptrack_report_free` from its `heaptrack_api.h` and run the
+    /// program under `heaptrack`, which records its own backtraces.
+    /// The hooks are not copied by `clone_config`.
+    #[allow(unused)]
+    pub fn set_profiler_hooks(
+        &mut self,
+        on_alloc: impl FnMut(NonNull<u8>, usize, u64) + Send + 'static,
+        on_free: impl FnMut(NonNull<u8>) + Send + 'static,
+    ) {
+        self.on_alloc = Some(Box::new(on_alloc));
+        self.on_free = Some(Box::new(on_free));
+    }
+
+    /// Calls `callback` with `used_bytes` when an allocation first takes it
+    /// to `bytes` or above, so that a service can shed load before running
+    /// out. It is called again only after frees bring usage back below.
+    /// The callback is not copied by `clone_config`.
+    #[allow(unused)]
+    pub fn set_high_watermark(&mut self, bytes: usize, callback: impl FnMut(usize) + Send + 'static) {
+        self.high_watermark = Some(HighWatermark {
+            bytes,
+            crossed: false,
+            callback: Box::new(callback),
+        });
+    }
+
+    /// Calls `hook` when `alloc_by_size` fails with an `AllocError`, and
+    /// acts on its answer instead of leaving every caller to. A retry
+    /// after trimming is made once, and its failure is returned as is.
+    /// A failed mmap of an external allocation reaches the hook as
+    /// `AllocError::MapFailed`.
+    /// The hook is not copied by `clone_config`.
+    #[allow(unused)]
+    pub fn set_error_hook(&mut self, hook: impl FnMut(&AllocError) -> ErrorAction + Send + 'static) {
+        self.error_hook = Some(Box::new(hook));
+    }
+
+    /// Makes the next commit fail with `AllocError::CommitFailed`.
+    #[cfg(feature = "fault-injection")]
+    #[allow(unused)]
+    pub fn set_fail_next_commit(&mut self) {
+        self.fail_next_commit = true;
+    }
+
+    /// Lets `n` more allocations succeed, then fails the next one with
+    /// `AllocError::OutOfReservedSpace`.
+    #[cfg(feature = "fault-injection")]
+    #[allow(unused)]
+    pub fn set_oom_after(&mut self, n: usize) {
+        self.oom_after = Some(n);
+    }
+
+    /// Runs `f` with an allocator that frees everything it allocated
+    /// once `f` returns or unwinds.
+    #[allow(unused)]
+    pub fn scoped<R>(&mut self, f: impl FnOnce(&mut ScopedAllocator) -> R) -> R {
+        let mut scope = ScopedAllocator {
+            allocator: self,
+            allocated: Vec::new(),
+        };
+        f(&mut scope)
+    }
+
+    /// Allocates a `T` whose header records `tag`.
+    #[cfg(feature = "debug-headers")]
+    #[allow(unused)]
+    pub unsafe fn alloc_tagged<T: Sized>(&mut self, tag: u32) -> Result<NonNull<T>, Box<dyn Error>> {
+        let ptr: NonNull<T> = self.alloc()?;
+        header_of(ptr).as_mut().tag = tag;
+        Ok(ptr)
+    }
+
+    #[cfg(feature = "debug-headers")]
+    #[allow(unused)]
+    pub unsafe fn tag_of<T>(&self, ptr: NonNull<T>) -> u32 {
+        header_of(ptr).as_ref().tag
+    }
+
+    /// Allocates a `T` and remembers its header generation, so that a use
+    /// after free can be caught with `check`.
+    #[cfg(feature = "debug-headers")]
+    #[allow(unused)]
+    pub unsafe fn alloc_tracked<T: Sized>(&mut self) -> Result<TrackedPtr<T>, Box<dyn Error>> {
+        let ptr: NonNull<T> = self.alloc()?;
+        let generation = header_of(ptr).as_ref().generation;
+        Ok(TrackedPtr { ptr, generation })
+    }
+
+    /// Returns the pointer of `tracked`, or `None` if its block has been
+    /// freed since it was allocated.
+    ///
+    /// Freed external blocks are unmapped, so only subheap blocks can be
+    /// checked after they are freed.
+    #[cfg(feature = "debug-headers")]
+    #[allow(unused)]
+    pub unsafe fn check<T>(&self, tracked: TrackedPtr<T>) -> Option<NonNull<T>> {
+        if header_of(tracked.ptr).as_ref().generation == tracked.generation {
+            Some(tracked.ptr)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the bytes usable through `ptr`, including the slack
+    /// left by rounding external allocations up to whole pages.
+    #[allow(unused)]
+    pub unsafe fn usable_size<T>(&self, ptr: NonNull<T>) -> usize {
+        self.usable_size_of(header_of(ptr))
+    }
+
+    /// Describes the block `ptr` points into, or returns `None` if it is
+    /// not the data pointer of a block this allocator carved or mapped.
+    /// Nothing is changed, so this is safe to call on stale pointers.
+    #[allow(unused)]
+    pub fn inspect<T>(&self, ptr: NonNull<T>) -> Option<BlockInfo> {
+        let header_addr = (ptr.as_ptr() as usize).checked_sub(size_of::<Header>())?;
+        if let Some((&base, &size)) = self.external_regions.range(..=header_addr).next_back() {
+            if header_addr < base + size {
+                let header = unsafe { NonNull::new_unchecked(header_addr as *mut Header) };
+                return (unsafe { header.as_ref().size_or_class_of_subheap } == size).then(|| BlockInfo {
+                    class: None,
+                    block_size: unsafe { self.usable_size_of(header) },
+                    is_external: true,
+                    on_free_list: false,
+                });
+            }
+        }
+
+        let header = unsafe { self.carved_blocks() }
+            .into_iter()
+            .find(|block| block.as_ptr() as usize == header_addr)?;
+        let class_of_subheap = unsafe { header.as_ref().size_or_class_of_subheap };
+        let mut on_free_list = false;
+        self.for_each_free_block(|class, data| {
+            on_free_list |= class == class_of_subheap && data.cast() == ptr;
+        });
+        Some(BlockInfo {
+            class: Some(class_of_subheap),
+            block_size: block_size_of_subheap(class_of_subheap),
+            is_external: false,
+            on_free_list,
+        })
+    }
+
+    /// Yields every live allocation whose block serves `size_of::<T>()`
+    /// bytes, subheap blocks in address order first, then external ones.
+    ///
+    /// Blocks only record their class, so this is meaningful when the
+    /// allocator is a pool of `T` alone; any other allocation of a nearby
+    /// size is yielded as a `T` too. Pointers queued by `defer_free` count
+    /// as freed. The heap is walked on every call.
+    #[cfg(feature = "track-live")]
+    #[allow(unused)]
+    pub unsafe fn iter_live<T>(&self) -> impl Iterator<Item = NonNull<T>> {
+        let len = size_of::<T>();
+        let mut free: std::collections::BTreeSet<usize> =
+            self.deferred_frees.iter().map(|ptr| ptr.as_ptr() as usize).collect();
+        self.for_each_free_block(|_, data| {
+            free.insert(data.as_ptr() as usize);
+        });
+
+        let mut live = Vec::new();
+        match (0..self.subheap_count).find(|&class_of_subheap| len <= block_size_of_subheap(class_of_subheap)) {
+            Some(class_of_subheap) => {
+                for block in self.carved_blocks() {
+                    let data = block.as_ptr() as usize + size_of::<Header>();
+                    if block.as_ref().size_or_class_of_subheap == class_of_subheap && !free.contains(&data) {
+                        live.push(NonNull::new_unchecked(data as *mut T));
+                    }
+                }
+            }
+            None => {
+                for (&base, &size) in &self.external_regions {
+                    let data = base + size_of::<Header>();
+                    if Some(size) == self.external_size(len) && !free.contains(&data) {
+                        live.push(NonNull::new_unchecked(data as *mut T));
+                    }
+                }
+            }
+        }
+        live.into_iter()
+    }
+
+    /// Same as `usable_size`, under the name C code and collections know.
+    /// Only the header is read, since requested lengths are not stored.
+    #[allow(unused)]
+    pub unsafe fn malloc_usable_size<T>(&self, ptr: NonNull<T>) -> usize {
+        self.usable_size(ptr)
+    }
+
+    /// Returns an `io::Write` over the first `len` bytes of the block,
+    /// clamped to its usable size.
+    #[allow(unused)]
+    pub unsafe fn writer<T>(&self, ptr: NonNull<T>, len: usize) -> AllocWriter {
+        AllocWriter {
+            ptr: ptr.cast(),
+            len: len.min(self.usable_size(ptr)),
+            position: 0,
+        }
+    }
+
+    /// Returns an `io::Read` over the first `len` bytes of the block,
+    /// clamped to its usable size.
+    #[allow(unused)]
+    pub unsafe fn reader<T>(&self, ptr: NonNull<T>, len: usize) -> AllocReader {
+        AllocReader {
+            ptr: ptr.cast(),
+            len: len.min(self.usable_size(ptr)),
+            position: 0,
+        }
+    }
+
+    /// Resizes the allocation to `new_len` bytes.
+    /// The block is kept in place while `new_len` fits its usable size.
+    /// External allocations that stay external are grown with mremap, so
+    /// their pages move without a copy.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn realloc<T>(&mut self, ptr: NonNull<T>, new_len: usize) -> Result<NonNull<T>, Box<dyn Error>> {
+        let old_len = self.usable_size(ptr);
+        if new_len <= old_len {
+            return Ok(ptr);
+        }
+
+        let header = header_of(ptr);
+        let size = header.as_ref().size_or_class_of_subheap;
+        // Only plain external mappings, whose header is at the base; the
+        // page aligned ones may be backed by a file.
+        if size > MAX_BLOCK_SIZE && new_len > self.max_block_size() && self.external_base(header) == header.cast() {
+            return self.remap_external(header, size, new_len);
+        }
+
+        let new_ptr: NonNull<T> = self.alloc_by_size(new_len)?;
+        std::ptr::copy_nonoverlapping(ptr.as_ptr() as *const u8, new_ptr.as_ptr() as *mut u8, old_len);
+        self.free(ptr)?;
+        Ok(new_ptr)
+    }
+
+    /// Resizes the allocation to hold `new_count` values of `T`.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn realloc_array<T>(&mut self, ptr: NonNull<T>, new_count: usize) -> Result<NonNull<T>, Box<dyn Error>> {
+        self.realloc(ptr, array_bytes::<T>(new_count)?)
+    }
+
+    /// Resizes like `realloc`, and zeroes the grown bytes `[old_len, new_len)`.
+    #[allow(unused)]
+    pub unsafe fn realloc_zeroed<T>(
+        &mut self,
+        ptr: NonNull<T>,
+        old_len: usize,
+        new_len: usize,
+    ) -> Result<NonNull<T>, Box<dyn Error>> {
+        let new_ptr = self.realloc(ptr, new_len)?;
+        if old_len < new_len {
+            std::ptr::write_bytes((new_ptr.as_ptr() as *mut u8).add(old_len), 0, new_len - old_len);
+        }
+        Ok(new_ptr)
+    }
+
+    /// Shrinks the allocation to `new_len` bytes in place.
+    /// External allocations hand their trailing pages back to the OS,
+    /// except huge-page blocks, which are left as they are.
+    /// A subheap block fitting a class two or more classes down keeps its
+    /// front as a block of that class, and the rest is split into blocks
+    /// of it on the free list, as `alloc` splits larger free blocks; other
+    /// subheap blocks are left as they are.
+    #[allow(unused)]
+    pub unsafe fn shrink<T>(&mut self, ptr: NonNull<T>, new_len: usize) -> Result<(), Box<dyn Error>> {
+        let mut allocated_ptr = header_of(ptr);
+        let size_or_class_of_subheap = allocated_ptr.as_ref().size_or_class_of_subheap;
+        if size_or_class_of_subheap <= MAX_BLOCK_SIZE {
+            let class_of_subheap = size_or_class_of_subheap;
+            let Some(new_class) = (0..class_of_subheap).find(|&class| new_len <= block_size_of_subheap(class)) else {
+                return Ok(());
+            };
+            // Only where `split_larger_block` would split the block too.
+            if new_class + 2 > class_of_subheap || self.cacheline_align || !self.class_regions.is_empty() {
+                return Ok(());
+            }
+            self.check_carved_class(allocated_ptr, class_of_subheap)?;
+            allocated_ptr.as_mut().size_or_class_of_subheap = new_class;
+            self.split_block_tail(allocated_ptr, class_of_subheap, new_class)?;
+            self.live_subheap_bytes -= carved_size_of_subheap(class_of_subheap) - carved_size_of_subheap(new_class);
+            return Ok(());
+        }
+
+        let size = size_or_class_of_subheap;
+        let base = self.external_base(allocated_ptr);
+        // Huge-page blocks are recycled whole.
+        if self.hugepage_blocks.contains(&(base.as_ptr() as usize)) {
+            return Ok(());
+        }
+        let front_size = ptr.as_ptr() as usize - base.as_ptr() as usize;
+        let new_size = new_len.checked_add(front_size)
+            .filter(|&new_size| new_size < size)
+            .map(|new_size| aligned_size(new_size, self.pagesize))
+            .unwrap_or(size);
+        if new_size < size {
+            #[cfg(feature = "profile")]
+            {
+                self.syscall_counts.release += 1;
+            }
+            sys::release(NonNull::new_unchecked(base.as_ptr().add(new_size)), size - new_size)?;
+            self.external_bytes -= size - new_size;
+            self.external_regions.insert(base.as_ptr() as usize, new_size);
+            allocated_ptr.as_mut().size_or_class_of_subheap = new_size;
+        }
+        Ok(())
+    }
+
+    /// Makes the allocation read-only, so writes to it fault.
+    ///
+    /// Subheap blocks share pages with other blocks, so only external
+    /// allocations can be sealed. The header page is sealed too, so the
+    /// block must be unsealed before `shrink` or `realloc`.
+    #[allow(unused)]
+    pub unsafe fn seal<T>(&mut self, ptr: NonNull<T>) -> Result<(), Box<dyn Error>> {
+        self.protect_external(ptr, libc::PROT_READ)
+    }
+
+    /// Makes a sealed allocation writable again.
+    #[allow(unused)]
+    pub unsafe fn unseal<T>(&mut self, ptr: NonNull<T>) -> Result<(), Box<dyn Error>> {
+        self.protect_external(ptr, libc::PROT_READ | libc::PROT_WRITE)
+    }
+
+    unsafe fn protect_external<T>(&mut self, ptr: NonNull<T>, prot: libc::c_int) -> Result<(), Box<dyn Error>> {
+        let allocated_ptr = header_of(ptr);
+        let size_or_class_of_subheap = allocated_ptr.as_ref().size_or_class_of_subheap;
+        if size_or_class_of_subheap <= MAX_BLOCK_SIZE {
+            return Err("Only external allocations can change protection.".into());
+        }
+
+        let size = size_or_class_of_subheap;
+        let base = self.external_base(allocated_ptr);
+        sys::protect(base, size, prot)?;
+        if prot == libc::PROT_READ | libc::PROT_WRITE {
+            self.protected_externals.remove(&(base.as_ptr() as usize));
+        } else {
+            self.protected_externals.insert(base.as_ptr() as usize);
+        }
+        Ok(())
+    }
+
+    /// Maps `len` bytes of page-aligned memory, writable so that code can be
+    /// copied in. Call `make_executable` once the code is in place; the
+    /// pages are never writable and executable at once.
+    /// The memory is released with `free` on `ExecAlloc::ptr`.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn alloc_exec(&mut self, len: usize) -> Result<ExecAlloc, Box<dyn Error>> {
+        let ptr = self.alloc_on_external_page_aligned(len, |_, _| Ok(()))?;
+        let len = self.usable_size_of(header_of(ptr));
+        Ok(ExecAlloc { ptr, len })
+    }
+
+    /// Switches the pages of `alloc` from read-write to read-execute.
+    ///
+    /// Subheap blocks share pages with other blocks, so only external
+    /// allocations can be made executable. The header page stays writable.
+    #[allow(unused)]
+    pub unsafe fn make_executable(&mut self, alloc: &ExecAlloc) -> Result<(), Box<dyn Error>> {
+        if header_of(alloc.ptr).as_ref().size_or_class_of_subheap <= MAX_BLOCK_SIZE {
+            return Err("Only external allocations can be made executable.".into());
+        }
+        self.check_external(header_of(alloc.ptr))?;
+        sys::protect(alloc.ptr.cast(), alloc.len, libc::PROT_READ | libc::PROT_EXEC)?;
+        self.protected_externals.insert(self.external_base(header_of(alloc.ptr)).as_ptr() as usize);
+        Ok(())
+    }
+
+    /// Allocates a 2 MiB block aligned to 2 MiB, backed by a huge page
+    /// where the system has one to spare. `free` keeps the block for the
+    /// next call instead of unmapping it.
+    ///
+    /// Falls back to normal pages if huge pages are unavailable, as counted
+    /// by `hugepage_fallbacks`.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn alloc_hugepage_block(&mut self) -> Result<NonNull<u8>, Box<dyn Error>> {
+        // The header takes a normal page just below the aligned data.
+        let allocated_size = HUGEPAGE_SIZE + self.pagesize;
+        let base = match self.hugepage_free_list.pop() {
+            Some(base) => NonNull::new_unchecked(base as *mut libc::c_void),
+            None => {
+                self.check_soft_limit(allocated_size)?;
+                self.map_hugepage_block()?
+            }
+        };
+        let ptr = NonNull::new_unchecked(base.as_ptr().add(self.pagesize));
+
+        self.external_bytes += allocated_size;
+        self.external_count += 1;
+        self.external_regions.insert(base.as_ptr() as usize, allocated_size);
+        self.hugepage_blocks.insert(base.as_ptr() as usize);
+        *header_of(ptr).as_mut() = Header {
+            size_or_class_of_subheap: allocated_size,
+            #[cfg(feature = "debug-headers")]
+            tag: 0,
+            #[cfg(feature = "debug-headers")]
+            generation: 0,
+        };
+        self.note_alloc(ptr.cast(), HUGEPAGE_SIZE);
+        self.check_high_watermark();
+        Ok(ptr.cast())
+    }
+
+    /// Maps a header page followed by a 2 MiB aligned data region, and
+    /// returns the start of the header page.
+    unsafe fn map_hugepage_block(&mut self) -> Result<sys::AnyNonNull, Box<dyn Error>> {
+        // Reserve enough to find an aligned region, then trim the rest.
+        let allocated_size = HUGEPAGE_SIZE + self.pagesize;
+        let reserved_len = allocated_size + HUGEPAGE_SIZE;
+        #[cfg(feature = "profile")]
+        {
+            self.syscall_counts.reserve += 1;
+        }
+        let reserved = sys::reserve(reserved_len, std::ptr::null_mut()).map_err(AllocError::MapFailed)?;
+        let reserved_begin = reserved.as_ptr() as usize;
+        let data_begin = aligned_size(reserved_begin + self.pagesize, HUGEPAGE_SIZE);
+        let base_begin = data_begin - self.pagesize;
+        let trailing = reserved_begin + reserved_len - (base_begin + allocated_size);
+        if base_begin > reserved_begin {
+            sys::release(reserved, base_begin - reserved_begin)?;
+        }
+        if trailing > 0 {
+            sys::release(NonNull::new_unchecked((base_begin + allocated_size) as *mut libc::c_void), trailing)?;
+        }
+
+        let base = NonNull::new_unchecked(base_begin as *mut libc::c_void);
+        let data = NonNull::new_unchecked(data_begin as *mut libc::c_void);
+        #[cfg(feature = "profile")]
+        {
+            self.syscall_counts.commit += 2;
+        }
+        let mut fell_back = false;
+        let committed = sys::commit(base, self.pagesize, sys::CommitStrategy::Mprotect).and_then(|_| {
+            sys::map_hugepages(data, HUGEPAGE_SIZE).or_else(|_| {
+                fell_back = true;
+                sys::commit(data, HUGEPAGE_SIZE, sys::CommitStrategy::Mprotect).map(|_| ())
+            })
+        });
+        if fell_back && committed.is_ok() {
+            self.hugepage_fallbacks += 1;
+        }
+        if let Err(error) = committed {
+            let _ = sys::release(base, allocated_size);
+            return Err(AllocError::CommitFailed(error).into());
+        }
+        Ok(base)
+    }
+
+    /// Maps a downward-growing stack of at least `size` bytes with a guard
+    /// page below it. Unlike other allocations it has no header, and must
+    /// be released with `free_stack`.
+    #[allow(unused)]
+    pub unsafe fn alloc_stack(&mut self, size: usize) -> Result<StackAlloc, Box<dyn Error>> {
+        let len = size
+            .checked_add(self.pagesize - 1)
+            .map(|size| size & !(self.pagesize - 1))
+            .and_then(|size| size.checked_add(self.pagesize))
+            .ok_or(AllocError::TooLarge)?;
+        #[cfg(feature = "profile")]
+        {
+            self.syscall_counts.map += 1;
+        }
+        let base = sys::alloc_stack(len, self.pagesize)?;
+        Ok(StackAlloc { base, len, guard_len: self.pagesize })
+    }
+
+    #[allow(unused)]
+    pub unsafe fn free_stack(&mut self, stack: StackAlloc) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "profile")]
+        {
+            self.syscall_counts.release += 1;
+        }
+        sys::release(stack.base, stack.len)
+    }
+
+    /// Allocates `len` bytes and returns a handle to them instead of a pointer.
+    #[allow(unused)]
+    pub unsafe fn alloc_handle(&mut self, len: usize) -> Result<Handle, Box<dyn Error>> {
+        // Checked before allocating, so that running out leaks nothing.
+        let reused = self.free_handle_slots.last().copied();
+        let index = match reused {
+            Some(index) => index,
+            None => u32::try_from(self.handle_slots.len()).map_err(|_| "Every handle is in use.")?,
+        };
+        let ptr: NonNull<u8> = self.alloc_by_size(len)?;
+        if reused.is_some() {
+            self.free_handle_slots.pop();
+        } else {
+            self.handle_slots.push(HandleSlot { ptr: None, generation: 0 });
+        }
+        let slot = &mut self.handle_slots[index as usize];
+        slot.ptr = Some(ptr);
+        Ok(Handle::new(index, slot.generation))
+    }
+
+    /// Returns the allocation of `handle`, or `None` once it has been freed.
+    #[allow(unused)]
+    pub fn resolve(&self, handle: Handle) -> Option<NonNull<u8>> {
+        let slot = self.handle_slots.get(handle.index())?;
+        if slot.generation == handle.generation() {
+            slot.ptr
+        } else {
+            None
+        }
+    }
+
+    #[allow(unused)]
+    pub unsafe fn free_handle(&mut self, handle: Handle) -> Result<(), Box<dyn Error>> {
+        let ptr = match self.resolve(handle) {
+            None => return Err("Handle is stale or unknown.".into()),
+            Some(ptr) => ptr,
+        };
+
+        // A block that fails to free stays reachable through its handle.
+        self.free(ptr)?;
+        let slot = &mut self.handle_slots[handle.index()];
+        slot.ptr = None;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free_handle_slots.push(handle.index() as u32);
+        Ok(())
+    }
+
+    /// Maps `len` bytes of `fd` from `offset` as shared memory, so writes
+    /// go to the file. `offset` must be page aligned. The returned pointer
+    /// is page aligned and is released by `free` like an external allocation.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn alloc_file_backed(
+        &mut self,
+        fd: RawFd,
+        offset: i64,
+        len: usize,
+    ) -> Result<NonNull<u8>, Box<dyn Error>> {
+        // The leading page holds the header, so the file is not touched.
+        #[cfg(feature = "profile")]
+        {
+            self.syscall_counts.map += 1;
+        }
+        let ptr = self.alloc_on_external_page_aligned(len, |ptr, size| sys::map_file(ptr, size, fd, offset))?;
+        self.file_backed_externals.insert(self.external_base(header_of(ptr)).as_ptr() as usize);
+        Ok(ptr)
+    }
+
+    /// Allocates memory for `layout`. Alignments up to a header's are served
+    /// like `alloc_by_size`, and larger ones up to the page size get a page
+    /// aligned external mapping.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn alloc_by_layout(&mut self, layout: Layout) -> Result<NonNull<u8>, Box<dyn Error>> {
+        if layout.align() <= align_of::<Header>() {
+            return self.alloc_by_size(layout.size());
+        }
+        if layout.align() > self.pagesize {
+            return Err("Alignment exceeds the page size.".into());
+        }
+        self.alloc_on_external_page_aligned(layout.size(), |_, _| Ok(()))
+    }
+
+    /// Allocates `size` bytes aligned to `alignment`, as C's
+    /// `aligned_alloc` does, for code ported from C. `alignment` must be a
+    /// power of two up to the page size. Released with `free`.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn aligned_alloc(&mut self, alignment: usize, size: usize) -> Result<NonNull<u8>, Box<dyn Error>> {
+        self.alloc_by_layout(Layout::from_size_align(size, alignment)?)
+    }
+
+    /// Allocates like C's `posix_memalign`: stores the block in `memptr`
+    /// and returns 0, or returns `EINVAL` for an `alignment` that is not a
+    /// power of two multiple of the pointer size up to the page size, and
+    /// `ENOMEM` when allocating fails, leaving `memptr` alone.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn posix_memalign(&mut self, memptr: &mut *mut u8, alignment: usize, size: usize) -> libc::c_int {
+        if !alignment.is_power_of_two()
+            || !alignment.is_multiple_of(size_of::<*mut libc::c_void>())
+            || alignment > self.pagesize
+        {
+            return libc::EINVAL;
+        }
+        match self.aligned_alloc(alignment, size) {
+            Ok(ptr) => {
+                *memptr = ptr.as_ptr();
+                0
+            }
+            Err(_) => libc::ENOMEM,
+        }
+    }
+
+    /// Allocates `len` bytes aligned to `align`, a power of two up to the
+    /// page size, from the subheaps when `len` fits a class, so that small
+    /// page aligned buffers do not each take a mapping.
+    ///
+    /// A free block of the class is reused only if it happens to be
+    /// aligned, found by walking the list; otherwise a block is carved
+    /// after padding, which is lost until the heap is dropped. Expect up to
+    /// `align` bytes of waste per fresh block.
+    #[allow(unused)]
+    #[track_caller]
+    pub unsafe fn alloc_aligned<T>(&mut self, len: usize, align: usize) -> Result<NonNull<T>, Box<dyn Error>> {
+        let layout = Layout::from_size_align(len, align)?;
+        let Some(class_of_subheap) =
+            (0..self.subheap_count).find(|&class_of_subheap| len <= block_size_of_subheap(class_of_subheap))
+        else {
+            return Ok(self.alloc_by_layout(layout)?.cast());
+        };
+        if align <= align_of::<Header>() {
+            return self.alloc_by_size(len);
+        }
+        if align > self.pagesize {
+            return Err("Alignment exceeds the page size.".into());
+        }
+
+        let mut prev: Option<NonNull<FreeHeader>> = None;
+        let mut node = self.free_lists[class_of_subheap];
+        let mut allocated_ptr = None;
+        while let Some(free_ptr) = NonNull::new(node) {
+            node = self.next_of(free_ptr);
+            if (free_ptr.as_ptr() as usize + size_of::<Header>()).is_multiple_of(align) {
+                match prev {
+                    None => self.free_lists[class_of_subheap] = node,
+                    Some(prev) => self.set_next(prev, node),
+                }
+                allocated_ptr = Some(free_ptr.cast());
+                break;
+            }
+            prev = Some(free_ptr);
+        }
+        let allocated_ptr: NonNull<Header> = match allocated_ptr {
+            Some(allocated_ptr) => allocated_ptr,
+            None => self.extend_active_heap_end_aligned(class_of_subheap, align)?,
+        };
+        self.clear_free_link(allocated_ptr.cast());
+        self.live_subheap_bytes += carved_size_of_subheap(class_of_subheap);
+        let ptr = NonNull::new_unchecked((allocated_ptr.as_ptr() as *mut u8).add(size_of::<Header>()));
+        self.note_alloc(ptr, len);
+        self.check_high_watermark();
+        Ok(ptr.cast())
+    }
+
+    /// Maps `len` bytes after a leading anonymous page that holds the
+    /// header, so that the data is page aligned. `map_data` may then map
+    /// something else over the data pages.
+    #[track_caller]
+    unsafe fn alloc_on_external_page_aligned(
+        &mut self,
+        len: usize,
+        map_data: impl FnOnce(sys::AnyNonNull, usize) -> Result<(), Box<dyn Error>>,
+    ) -> Result<NonNull<u8>, Box<dyn Error>> {
+        // The header is on the leading page, so only `len` is rounded up.
+        let data_size = len
+            .max(1)
+            .checked_add(self.pagesize - 1)
+            .filter(|&size| size <= isize::MAX as usize)
+            .map(|size| size & !(self.pagesize - 1))
+            .ok_or(AllocError::TooLarge)?;
+        let allocated_size = data_size.checked_add(self.pagesize).ok_or(AllocError::TooLarge)?;
+        self.check_soft_limit(allocated_size)?;
+        #[cfg(feature = "profile")]
+        {
+            self.syscall_counts.map += 1;
+        }
+        let base = sys::alloc(allocated_size).map_err(AllocError::MapFailed)?;
+        let ptr = NonNull::new_unchecked(base.as_ptr().add(self.pagesize));
+        if let Err(error) = map_data(ptr, data_size) {
+            #[cfg(feature = "profile")]
+            {
+                self.syscall_counts.release += 1;
+            }
+            let _ = sys::release(base, allocated_size);
+            return Err(error);
+        }
+
+        self.external_bytes += allocated_size;
+        self.external_count += 1;
+        self.external_regions.insert(base.as_ptr() as usize, allocated_size);
+        *header_of(ptr).as_mut() = Header {
+            size_or_class_of_subheap: allocated_size,
+            #[cfg(feature = "debug-headers")]
+            tag: 0,
+            #[cfg(feature = "debug-headers")]
+            generation: 0,
+        };
+        self.note_alloc(ptr.cast(), len);
+        self.check_high_watermark();
+        Ok(ptr.cast())
+    }
+
+    /// Commits `len` bytes at `offset` from the start of the reservation,
+    /// for formats that need blocks at known offsets. `offset` must be page
+    /// aligned and past the pages carved so far, and the range must not
+    /// overlap an earlier placed block.
+    ///
+    /// The block bypasses the classes and has no header, so it must not be
+    /// passed to `free`; it stays until the allocator is dropped. The bump
+    /// pointer then stops below it, so placing a block low in the
+    /// reservation leaves little room for normal allocations.
+    #[allow(unused)]
+    pub unsafe fn allocate_at(&mut self, offset: usize, len: usize) -> Result<NonNull<u8>, Box<dyn Error>> {
+        if !offset.is_multiple_of(self.pagesize) {
+            return Err("Offset must be page aligned.".into());
+        }
+        if !self.class_regions.is_empty() {
+            return Err("Blocks cannot be placed in class regions.".into());
+        }
+        let len = aligned_size(len.max(1), self.pagesize);
+        if offset > self.capacity_total() || len > self.capacity_total() - offset {
+            return Err(AllocError::OutOfReservedSpace.into());
+        }
+        let begin = self.heap_begin.as_ptr() as usize + offset;
+        let end = begin + len;
+        if begin < aligned_size(self.active_heap_end.as_ptr() as usize, self.pagesize) {
+            return Err("Range overlaps carved blocks.".into());
+        }
+        let before = self.placed_ranges.range(..end).next_back();
+        if before.is_some_and(|(&placed, &placed_len)| placed + placed_len > begin) {
+            return Err("Range overlaps a placed block.".into());
+        }
+
+        self.check_soft_limit(len)?;
+        #[cfg(feature = "fault-injection")]
+        self.count_down_oom()?;
+
+        let ptr = NonNull::new_unchecked(begin as *mut libc::c_void);
+        // Buffers and lazily committed reservations are usable already.
+        if !self.lazy_commit && end > self.commited_heap_end.as_ptr() as usize {
+            #[cfg(feature = "fault-injection")]
+            if self.fail_next_commit {
+                self.fail_next_commit = false;
+                return Err(AllocError::CommitFailed("Injected commit failure.".into()).into());
+            }
+            #[cfg(feature = "profile")]
+            {
+                self.syscall_counts.commit += 1;
+            }
+            self.prefer_commit_strategy =
+                sys::commit(ptr, len, self.prefer_commit_strategy).map_err(AllocError::CommitFailed)?;
+        }
+        self.placed_ranges.insert(begin, len);
+        self.carvable_heap_end = self.carvable_heap_end.min(ptr);
+        // Pages committed from here on are no longer the bump pointer's to trim.
+        self.commited_heap_end = self.commited_heap_end.min(ptr);
+        Ok(ptr.cast())
+    }
+
+    /// Flushes `len` bytes from `ptr` of a file-backed allocation to its
+    /// file, waiting for the write unless `async_` is set.
+    /// Anonymous memory has nothing to flush, and this returns `Ok`.
+    #[allow(unused)]
+    pub unsafe fn sync<T>(&self, ptr: NonNull<T>, len: usize, async_: bool) -> Result<(), Box<dyn Error>> {
+        let allocated_ptr = header_of(ptr);
+        if allocated_ptr.as_ref().size_or_class_of_subheap <= MAX_BLOCK_SIZE {
+            return Ok(());
+        }
+
+        let begin = ptr.as_ptr() as usize & !(self.pagesize - 1);
+        let end = aligned_size(ptr.as_ptr() as usize + len, self.pagesize);
+        sys::sync(NonNull::new_unchecked(begin as *mut libc::c_void), end - begin, async_)
+    }
+
+    /// Frees a block returned by this allocator.
+    ///
+    /// The block is identified by its header alone, so `T` need not match
+    /// the type it was allocated as.
+    /// Pushing a subheap block onto a LIFO free list is inlined into the
+    /// caller; everything else goes through the out-of-line slow path.
+    /// Debug builds always take the slow path, for its header checks.
+    #[inline(always)]
+    pub unsafe fn free<T>(&mut self, ptr: NonNull<T>) -> Result<(), Box<dyn Error>> {
+        if !cfg!(debug_assertions) && self.fast_path_enabled() {
+            let allocated_ptr = header_of(ptr);
+            let class_of_subheap = allocated_ptr.as_ref().size_or_class_of_subheap;
+            if class_of_subheap < SUBHEAP_COUNT
+                && self.free_policies[class_of_subheap] == FreePolicy::Lifo
+                && !self.lazy_free
+                && self.may_be_carved(allocated_ptr.as_ptr() as usize, class_of_subheap)
+                && !self.looks_freed(allocated_ptr)
+            {
+                if self.zero_on_free {
+                    self.zero_freed_block(allocated_ptr);
+                }
+                self.push_free_list(allocated_ptr, class_of_subheap);
+                self.live_subheap_bytes -= carved_size_of_subheap(class_of_subheap);
+                return Ok(());
+            }
+        }
+        self.free_slow(ptr)
+    }
+
+    #[cold]
+    #[inline(never)]
+    unsafe fn free_slow<T>(&mut self, ptr: NonNull<T>) -> Result<(), Box<dyn Error>> {
+        let allocated_ptr = header_of(ptr);
+
+        let size_or_class_of_subheap = allocated_ptr.as_ref().size_or_class_of_subheap;
+        debug_assert!(
+            !(SUBHEAP_COUNT..=MAX_BLOCK_SIZE).contains(&size_or_class_of_subheap),
+            "freed pointer has a corrupted header: {}", size_or_class_of_subheap,
+        );
+        if size_or_class_of_subheap <= MAX_BLOCK_SIZE {
+            let class_of_subheap = size_or_class_of_subheap;
+            self.check_carved_class(allocated_ptr, class_of_subheap)?;
+            if self.looks_freed(allocated_ptr) {
+                return Err(AllocError::DoubleFree.into());
+            }
+            if self.zero_on_free {
+                self.zero_freed_block(allocated_ptr);
+            }
+            self.free_on_subheap(allocated_ptr, class_of_subheap);
+            self.live_subheap_bytes -= carved_size_of_subheap(class_of_subheap);
+            if self.lazy_free {
+                self.advise_free_block(allocated_ptr, class_of_subheap)?;
+            }
+        } else {
+            let size = size_or_class_of_subheap;
+            self.check_external(allocated_ptr)?;
+            if self.zero_on_free {
+                self.zero_freed_block(allocated_ptr);
+            }
+            self.free_on_external(allocated_ptr, size)?;
+        }
+        self.note_free(ptr.cast());
+        self.check_high_watermark();
+        Ok(())
+    }
+
+    /// Frees every pointer in `ptrs` at once.
+    /// Subheap blocks are spliced onto each free list in one step, and
+    /// adjacent external regions are released with a single munmap.
+    /// A pointer listed twice fails the whole batch with `DoubleFree`.
+    #[allow(unused)]
+    pub unsafe fn free_many<T>(&mut self, ptrs: &[NonNull<T>]) -> Result<(), Box<dyn Error>> {
+        let mut chains: [Option<(NonNull<FreeHeader>, NonNull<FreeHeader>)>; SUBHEAP_COUNT] = [None; SUBHEAP_COUNT];
+        let mut externals = Vec::new();
+
+        // Validate the whole batch first, so a bad pointer leaves nothing freed.
+        let mut seen = std::collections::BTreeSet::new();
+        for &ptr in ptrs {
+            if !seen.insert(ptr.as_ptr() as usize) {
+                return Err(AllocError::DoubleFree.into());
+            }
+            let allocated_ptr = header_of(ptr);
+            let size_or_class_of_subheap = allocated_ptr.as_ref().size_or_class_of_subheap;
+            if size_or_class_of_subheap <= MAX_BLOCK_SIZE {
+                self.check_carved_class(allocated_ptr, size_or_class_of_subheap)?;
+                if self.looks_freed(allocated_ptr) {
+                    return Err(AllocError::DoubleFree.into());
+                }
+            } else {
+                self.check_external(allocated_ptr)?;
+            }
+        }
+
+        for &ptr in ptrs {
+            let allocated_ptr = header_of(ptr);
+            if self.zero_on_free {
+                self.zero_freed_block(allocated_ptr);
+            }
+            let size_or_class_of_subheap = allocated_ptr.as_ref().size_or_class_of_subheap;
+            if size_or_class_of_subheap <= MAX_BLOCK_SIZE {
+                let class_of_subheap = size_or_class_of_subheap;
+                self.live_subheap_bytes -= carved_size_of_subheap(class_of_subheap);
+                if self.lazy_free {
+                    self.advise_free_block(allocated_ptr, class_of_subheap)?;
+                }
+                if self.free_policies[class_of_subheap] != FreePolicy::Lifo {
+                    self.free_on_subheap(allocated_ptr, class_of_subheap);
+                    continue;
+                }
+
+                #[cfg(feature = "debug-headers")]
+                self.mark_freed(allocated_ptr);
+                let free_ptr: NonNull<FreeHeader> = allocated_ptr.cast();
+                match chains[class_of_subheap] {
+                    None => {
+                        self.set_next(free_ptr, std::ptr::null_mut());
+                        chains[class_of_subheap] = Some((free_ptr, free_ptr));
+                    }
+                    Some((head, tail)) => {
+                        self.set_next(free_ptr, head.as_ptr());
+                        chains[class_of_subheap] = Some((free_ptr, tail));
+                    }
+                }
+            } else {
+                let size = size_or_class_of_subheap;
+                let base = self.external_base(allocated_ptr);
+                if self.hugepage_blocks.contains(&(base.as_ptr() as usize)) {
+                    // Kept for reuse rather than unmapped with its neighbours.
+                    self.free_on_external(allocated_ptr, size)?;
+                    continue;
+                }
+                externals.push((base.as_ptr() as usize, size));
+            }
+        }
+
+        for (class_of_subheap, chain) in chains.into_iter().enumerate() {
+            if let Some((head, tail)) = chain {
+                self.set_next(tail, self.free_lists[class_of_subheap]);
+                self.free_lists[class_of_subheap] = head.as_ptr();
+            }
+        }
+
+        externals.sort_unstable();
+        let mut pending: Option<(usize, usize)> = None;
+        for (addr, size) in externals {
+            pending = match pending {
+                Some((begin, len)) if begin + len == addr => Some((begin, len + size)),
+                Some((begin, len)) => {
+                    self.release_external_run(begin, len)?;
+                    Some((addr, size))
+                }
+                None => Some((addr, size)),
+            };
+        }
+        if let Some((begin, len)) = pending {
+            self.release_external_run(begin, len)?;
+        }
+        for &ptr in ptrs {
+            self.note_free(ptr.cast());
+        }
+        self.check_high_watermark();
+        Ok(())
+    }
+
+    /// Carves `count` blocks of `class_of_subheap` up front and puts them on
+    /// its free list, committing the whole range at once so the following
+    /// allocations are plain free list pops.
+    #[allow(unused)]
+    pub unsafe fn reserve_for(&mut self, class_of_subheap: usize, count: usize) -> Result<(), Box<dyn Error>> {
+        assert!(class_of_subheap < SUBHEAP_COUNT);
+
+        let allocated_size = carved_size_of_subheap(class_of_subheap);
+        let mut region = self.bump_region(class_of_subheap);
+        let remaining = region.end.as_ptr().offset_from(region.active.as_ptr()) as usize;
+        let reserved_size = match allocated_size.checked_mul(count) {
+            Some(reserved_size) if reserved_size <= remaining => reserved_size,
+            _ => return Err(AllocError::OutOfReservedSpace.into()),
+        };
+        let reserved_end = NonNull::new_unchecked(region.active.as_ptr().add(reserved_size));
+        self.commit_until(&mut region, reserved_end)?;
+        self.set_bump_region(class_of_subheap, region);
+
+        for _ in 0..count {
+            let allocated_ptr = self.extend_active_heap_end(class_of_subheap)?;
+            self.free_on_subheap(allocated_ptr, class_of_subheap);
+        }
+        Ok(())
+    }
+
+    /// Does `reserve_for` with `per_class` blocks for every class serving
+    /// requests, so that the first `per_class` allocations of any size in
+    /// the subheaps are plain free list pops. Returns the bytes committed.
+    #[allow(unused)]
+    pub unsafe fn prewarm_all_classes(&mut self, per_class: usize) -> Result<usize, Box<dyn Error>> {
+        let committed = self.committed_subheap_bytes();
+        for class_of_subheap in 0..self.subheap_count {
+            self.reserve_for(class_of_subheap, per_class)?;
+        }
+        Ok(self.committed_subheap_bytes() - committed)
+    }
+
+    /// Queues `ptr` to be freed by the next `flush_deferred`.
+    ///
+    /// The queue holds up to `DEFERRED_FREE_CAPACITY` pointers; queuing
+    /// into a full queue flushes it first, on the caller's path. Callers
+    /// that must keep frees off the hot path should flush before that.
+    #[allow(unused)]
+    pub unsafe fn defer_free<T>(&mut self, ptr: NonNull<T>) -> Result<(), Box<dyn Error>> {
+        if self.deferred_frees.len() >= DEFERRED_FREE_CAPACITY {
+            self.flush_deferred()?;
+        }
+        self.deferred_frees.push(ptr.cast());
+        Ok(())
+    }
+
+    /// Frees every pointer queued by `defer_free`.
+    #[allow(unused)]
+    pub unsafe fn flush_deferred(&mut self) -> Result<(), Box<dyn Error>> {
+        let deferred_frees = std::mem::take(&mut self.deferred_frees);
+        let result = self.free_many(&deferred_frees);
+
+        // Keep the queue's buffer for reuse.
+        self.deferred_frees = deferred_frees;
+        self.deferred_frees.clear();
+        result
+    }
+
+    /// Takes over what `other` can hand over.
+    ///
+    /// Live external allocations always move to `self`. Free subheap blocks
+    /// move only when both allocators manage the same reservation, since a
+    /// block cannot leave the reservation it was carved from; otherwise
+    /// they stay with `other`. Each carves from the start of a shared
+    /// reservation, so only one of them may have used it, or their blocks
+    /// would already overlap. `self` then advances past everything `other`
+    /// carved, so their future blocks cannot overlap either.
+    ///
+    /// Nothing is moved when merging fails, though `other`'s deferred frees
+    /// are flushed first.
+    #[allow(unused)]
+    pub unsafe fn merge_from(&mut self, other: &mut Allocator) -> Result<(), Box<dyn Error>> {
+        other.flush_deferred()?;
+
+        let shared = self.heap_begin == other.heap_begin && self.heap_end == other.heap_end;
+        if shared {
+            if !self.class_regions.is_empty() || !other.class_regions.is_empty() {
+                return Err("Free blocks of class regions cannot be merged.".into());
+            }
+            let used = |allocator: &Allocator| {
+                allocator.active_heap_end != allocator.heap_begin || !allocator.placed_ranges.is_empty()
+            };
+            if used(self) && used(other) {
+                return Err("Both allocators carved blocks from the same reservation.".into());
+            }
+        }
+
+        // Nothing below fails, so the merge happens entirely or not at all.
+        if shared {
+            for class_of_subheap in 0..SUBHEAP_COUNT {
+                let mut node = std::mem::replace(&mut other.free_lists[class_of_subheap], std::ptr::null_mut());
+                while let Some(free_ptr) = NonNull::new(node) {
+                    node = other.next_of(free_ptr);
+                    self.free_on_subheap(free_ptr.cast(), class_of_subheap);
+                }
+            }
+
+            self.detached_ranges.append(&mut other.detached_ranges);
+            self.decommitted_holes.append(&mut other.decommitted_holes);
+            self.shard_ranges.append(&mut other.shard_ranges);
+            self.placed_ranges.append(&mut other.placed_ranges);
+            self.live_subheap_bytes += std::mem::take(&mut other.live_subheap_bytes);
+            self.active_heap_end = self.active_heap_end.max(other.active_heap_end);
+            self.commited_heap_end = self.commited_heap_end.max(other.commited_heap_end);
+            self.carvable_heap_end = self.carvable_heap_end.min(other.carvable_heap_end);
+            if other.owns_reservation {
+                other.owns_reservation = false;
+                self.owns_reservation = true;
+            }
+            #[cfg(debug_assertions)]
+            self.carved_classes.append(&mut other.carved_classes);
+        }
+
+        self.external_regions.append(&mut other.external_regions);
+        self.hugepage_blocks.append(&mut other.hugepage_blocks);
+        self.protected_externals.append(&mut other.protected_externals);
+        self.file_backed_externals.append(&mut other.file_backed_externals);
+        self.external_bytes += std::mem::take(&mut other.external_bytes);
+        self.external_count += std::mem::take(&mut other.external_count);
+        Ok(())
+    }
+
+    /// Carves `bytes`, rounded up to whole pages, off the uncarved part of
+    /// the heap and returns an allocator managing just them, so that many
+    /// shards share one reservation. The shard starts with the default
+    /// configuration and, like a heap over a caller's mapping, never
+    /// releases or decommits its pages; this allocator must outlive it.
+    #[allow(unused)]
+    pub unsafe fn fork_shard(&mut self, bytes: usize) -> Result<Allocator, Box<dyn Error>> {
+        if !self.class_regions.is_empty() {
+            return Err("Shards cannot be carved from class regions.".into());
+        }
+        let len = aligned_size(bytes.max(1), self.pagesize);
+        let active = self.active_heap_end.as_ptr() as usize;
+        let begin = aligned_size(active, self.pagesize);
+        let heap_end = self.carvable_heap_end.as_ptr() as usize;
+        if begin > heap_end || len > heap_end - begin {
+            return Err(AllocError::OutOfReservedSpace.into());
+        }
+        let end = begin + len;
+
+        // The parent's committed end may fall anywhere around the shard.
+        let committed = (self.commited_heap_end.as_ptr() as usize).clamp(begin, end);
+        self.detached_ranges.insert(active, end - active);
+        self.shard_ranges.insert(active);
+        self.active_heap_end = NonNull::new_unchecked(end as *mut libc::c_void);
+        self.commited_heap_end = self.commited_heap_end.max(self.active_heap_end);
+        Ok(Self::with_heap(
+            self.pagesize,
+            NonNull::new_unchecked(begin as *mut libc::c_void),
+            NonNull::new_unchecked(end as *mut libc::c_void),
+            NonNull::new_unchecked(committed as *mut libc::c_void),
+        ))
+    }
+
+    /// Starts logging every successful allocation and every free, through
+    /// `free` or `free_many`, of the blocks allocated since, with ids
+    /// numbering the allocations in order. Any earlier log is dropped.
+    /// Replaying serves every allocation with `alloc_by_size`.
+    #[allow(unused)]
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Recording::default());
+    }
+
+    /// Stops logging and returns the trace, for `replay`.
+    #[allow(unused)]
+    pub fn stop_recording(&mut self) -> Vec<TraceOp> {
+        self.recording.take().map(|recording| recording.ops).unwrap_or_default()
+    }
+
+    /// Runs a trace from `stop_recording` on this allocator, typically a
+    /// fresh one configured as the one to evaluate. Blocks the trace never
+    /// frees stay allocated.
+    #[allow(unused)]
+    pub unsafe fn replay(&mut self, trace: &[TraceOp]) -> Result<(), Box<dyn Error>> {
+        let mut ptrs = std::collections::BTreeMap::new();
+        for &op in trace {
+            match op {
+                TraceOp::Alloc { id, len } => {
+                    let ptr: NonNull<u8> = self.alloc_by_size(len)?;
+                    ptrs.insert(id, ptr);
+                }
+                TraceOp::Free { id } => {
+                    let ptr = ptrs.remove(&id).ok_or("Trace frees an id it has not allocated.")?;
+                    self.free(ptr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Captures where blocks are carved, which are free and in what order,
+    /// and how far each region is carved and committed, for `restore`.
+    ///
+    /// Addresses are kept raw, so a snapshot only means something to an
+    /// allocator over the same reservation: this one, or one created with
+    /// `init_at` at the same base in a later run. Block contents and
+    /// external allocations are not captured.
+    #[allow(unused)]
+    pub fn snapshot(&self) -> AllocatorSnapshot {
+        let mut free_lists = vec![Vec::new(); SUBHEAP_COUNT];
+        self.for_each_free_block(|class_of_subheap, data| {
+            free_lists[class_of_subheap].push(data.as_ptr() as usize - size_of::<Header>());
+        });
+        AllocatorSnapshot {
+            heap_begin: self.heap_begin.as_ptr() as usize,
+            regions: self
+                .bump_regions()
+                .iter()
+                .map(|region| (region.active.as_ptr() as usize, region.committed.as_ptr() as usize))
+                .collect(),
+            blocks: unsafe { self.carved_blocks() }
+                .iter()
+                .map(|block| (block.as_ptr() as usize, unsafe { block.as_ref().size_or_class_of_subheap }))
+                .collect(),
+            free_lists,
+            detached_ranges: self.detached_ranges.iter().map(|(&addr, &size)| (addr, size)).collect(),
+            deferred_frees: self.deferred_frees.iter().map(|ptr| ptr.as_ptr() as usize).collect(),
+            live_subheap_bytes: self.live_subheap_bytes,
+        }
+    }
+
+    /// Puts the subheaps back in the state captured by `snapshot`: the
+    /// same blocks are carved, the free lists are relinked in the same
+    /// order, and anything carved since is dropped. The following
+    /// allocations then get the same addresses as after the capture.
+    ///
+    /// Pages decommitted since are committed again, zero-filled, and
+    /// blocks allocated since must not be used or freed any more.
+    #[allow(unused)]
+    pub unsafe fn restore(&mut self, snapshot: &AllocatorSnapshot) -> Result<(), Box<dyn Error>> {
+        let regions = self.bump_regions();
+        if snapshot.heap_begin != self.heap_begin.as_ptr() as usize || snapshot.regions.len() != regions.len() {
+            return Err("Snapshot was taken over another reservation.".into());
+        }
+        if self.shard_ranges.iter().any(|&addr| !snapshot.detached_ranges.iter().any(|&(detached, _)| detached == addr)) {
+            return Err("Snapshot predates a shard it would overwrite.".into());
+        }
+
+        // Pages within runs of detached ranges may have been given back by
+        // reclaiming.
+        if self.owns_reservation {
+            let mut runs: Vec<(usize, usize)> = Vec::new();
+            for (&addr, &size) in &self.detached_ranges {
+                if self.shard_ranges.contains(&addr) {
+                    continue;
+                }
+                match runs.last_mut() {
+                    Some((_, end)) if *end == addr => *end += size,
+                    _ => runs.push((addr, addr + size)),
+                }
+            }
+            for (begin, end) in runs {
+                let begin = aligned_size(begin, self.pagesize);
+                let end = end & !(self.pagesize - 1);
+                if begin < end {
+                    #[cfg(feature = "profile")]
+                    {
+                        self.syscall_counts.commit += 1;
+                    }
+                    let addr = NonNull::new_unchecked(begin as *mut libc::c_void);
+                    self.prefer_commit_strategy = sys::commit(addr, end - begin, self.prefer_commit_strategy)?;
+                }
+            }
+            self.decommitted_holes.clear();
+        }
+        for (class_of_subheap, (mut region, &(active, committed))) in regions.into_iter().zip(&snapshot.regions).enumerate() {
+            self.commit_until(&mut region, NonNull::new_unchecked(committed as *mut libc::c_void))?;
+            region.active = NonNull::new_unchecked(active as *mut libc::c_void);
+            self.set_bump_region(class_of_subheap, region);
+        }
+
+        #[cfg(debug_assertions)]
+        self.carved_classes.clear();
+        for &(addr, class_of_subheap) in &snapshot.blocks {
+            let mut block = NonNull::new_unchecked(addr as *mut Header);
+            block.as_mut().size_or_class_of_subheap = class_of_subheap;
+            #[cfg(debug_assertions)]
+            self.carved_classes.insert(addr, class_of_subheap);
+        }
+        for (class_of_subheap, blocks) in snapshot.free_lists.iter().enumerate() {
+            let mut next = std::ptr::null_mut();
+            for &addr in blocks.iter().rev() {
+                let free_ptr = NonNull::new_unchecked(addr as *mut FreeHeader);
+                #[cfg(feature = "debug-headers")]
+                {
+                    free_ptr.cast::<Header>().as_mut().tag = FREED_TAG;
+                }
+                self.set_next(free_ptr, next);
+                next = free_ptr.as_ptr();
+            }
+            self.free_lists[class_of_subheap] = next;
+        }
+        self.detached_ranges = snapshot.detached_ranges.iter().copied().collect();
+        self.deferred_frees = snapshot
+            .deferred_frees
+            .iter()
+            .map(|&addr| NonNull::new_unchecked(addr as *mut u8))
+            .collect();
+        self.live_subheap_bytes = snapshot.live_subheap_bytes;
+        Ok(())
+    }
+
+    /// Checks that no two of the heap reservation, the live external
+    /// mappings and the cached ones overlap.
+    #[allow(unused)]
+    pub fn validate_no_overlap(&self) -> Result<(), Box<dyn Error>> {
+        let heap_begin = self.heap_begin.as_ptr() as usize;
+        let heap_len = self.heap_end.as_ptr() as usize - heap_begin;
+        let mut ranges: Vec<(usize, usize)> = self.external_regions.iter().map(|(&base, &size)| (base, size)).collect();
+        ranges.extend_from_slice(&self.external_cache);
+        ranges.extend(self.hugepage_free_list.iter().map(|&base| (base, HUGEPAGE_SIZE + self.pagesize)));
+        ranges.push((heap_begin, heap_len));
+        ranges.sort_unstable();
+
+        for pair in ranges.windows(2) {
+            let (begin, len) = pair[0];
+            let (next_begin, next_len) = pair[1];
+            if begin + len > next_begin {
+                return Err(format!(
+                    "Range {:#x}+{:#x} overlaps range {:#x}+{:#x}.",
+                    begin, len, next_begin, next_len
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sums the blocks on the free lists, for reconciling against the
+    /// bytes carved from the heap that are not live.
+    ///
+    /// Each list walk stops after as many nodes as could fit in the carved
+    /// heap, and reports a cycle beyond that. With `debug-headers`, a free
+    /// block whose tag is not `FREED_TAG` is reported as corrupted.
+    #[allow(unused)]
+    pub fn validate_free_list_bytes(&self) -> Result<FreeListBytes, Box<dyn Error>> {
+        let carved_bytes = self.carved_bytes();
+
+        let mut free_bytes = 0;
+        for class_of_subheap in 0..SUBHEAP_COUNT {
+            let max_nodes = carved_bytes / carved_size_of_subheap(class_of_subheap);
+            let mut nodes = 0;
+            let mut node = self.free_lists[class_of_subheap];
+            while let Some(free_ptr) = NonNull::new(node) {
+                nodes += 1;
+                if nodes > max_nodes {
+                    return Err(format!("Free list of class {} has a cycle.", class_of_subheap).into());
+                }
+                #[cfg(feature = "debug-headers")]
+                {
+                    let tag = unsafe { free_ptr.cast::<Header>().as_ref().tag };
+                    if tag != FREED_TAG {
+                        return Err(format!(
+                            "Free block {:p} of class {} has a corrupted tag {:#x}.",
+                            free_ptr, class_of_subheap, tag
+                        )
+                        .into());
+                    }
+                }
+                node = unsafe { self.next_of(free_ptr) };
+            }
+            free_bytes += nodes * carved_size_of_subheap(class_of_subheap);
+        }
+
+        Ok(FreeListBytes {
+            free_bytes,
+            expected_free_bytes: carved_bytes - self.live_subheap_bytes - self.detached_ranges.values().sum::<usize>(),
+        })
+    }
+
+    /// Runs every invariant check: the tracked ranges must not overlap, and
+    /// the free lists must hold exactly the carved bytes that are not live.
+    #[allow(unused)]
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        self.validate_no_overlap()?;
+        let bytes = self.validate_free_list_bytes()?;
+        if !bytes.is_balanced() {
+            return Err(format!(
+                "Free lists hold {:#x} bytes but {:#x} carved bytes are not live.",
+                bytes.free_bytes, bytes.expected_free_bytes
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Decommits the pages of the carved heap that hold only free blocks,
+    /// and returns how many bytes were decommitted.
+    ///
+    /// Blocks overlapping such a page are taken off their free lists for
+    /// good, including any part that spills onto a neighbouring page.
+    /// Heaps over a caller's buffer or mapping are left alone.
+    #[allow(unused)]
+    pub unsafe fn reclaim_empty_subheap_pages(&mut self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.reclaim_empty_subheap_pages_until(None)?.0)
+    }
+
+    /// Does `reclaim_empty_subheap_pages`, but stops before the next run of
+    /// empty pages once `deadline` has passed, leaving the blocks of the
+    /// runs not reached on their free lists. Returns the bytes decommitted
+    /// and whether every run was reached.
+    unsafe fn reclaim_empty_subheap_pages_until(
+        &mut self,
+        deadline: Option<std::time::Instant>,
+    ) -> Result<(usize, bool), Box<dyn Error>> {
+        if !self.owns_reservation {
+            return Ok((0, true));
+        }
+
+        let mut blocks = Vec::new();
+        for class_of_subheap in 0..SUBHEAP_COUNT {
+            let mut node = self.free_lists[class_of_subheap];
+            while let Some(free_ptr) = NonNull::new(node) {
+                blocks.push((free_ptr.as_ptr() as usize, class_of_subheap));
+                node = self.next_of(free_ptr);
+            }
+        }
+        blocks.sort_unstable();
+
+        // Find runs of adjacent free blocks and the whole pages inside them.
+        let mut empty_runs = Vec::new();
+        let mut reclaimed = std::collections::BTreeSet::new();
+        let mut index = 0;
+        while index < blocks.len() {
+            let run_start = index;
+            let mut end = blocks[index].0;
+            while index < blocks.len() && blocks[index].0 == end {
+                end += carved_size_of_subheap(blocks[index].1);
+                index += 1;
+            }
+            let page_begin = aligned_size(blocks[run_start].0, self.pagesize);
+            let page_end = end & !(self.pagesize - 1);
+            if page_begin >= page_end {
+                continue;
+            }
+            let run_blocks: Vec<_> = blocks[run_start..index]
+                .iter()
+                .copied()
+                .filter(|&(addr, class_of_subheap)| {
+                    addr < page_end && page_begin < addr + carved_size_of_subheap(class_of_subheap)
+                })
+                .collect();
+            for &(addr, class_of_subheap) in &run_blocks {
+                reclaimed.insert(addr);
+                self.detached_ranges.insert(addr, carved_size_of_subheap(class_of_subheap));
+                #[cfg(debug_assertions)]
+                self.carved_classes.remove(&addr);
+            }
+            empty_runs.push((page_begin, page_end - page_begin, run_blocks));
+        }
+
+        // Unlink the blocks before their pages become inaccessible.
+        self.unlink_free_blocks(&reclaimed);
+
+        let mut decommitted_bytes = 0;
+        let mut runs = empty_runs.into_iter().peekable();
+        let in_time = |_: &_| deadline.is_none_or(|deadline| std::time::Instant::now() < deadline);
+        while let Some((addr, len, _)) = runs.next_if(in_time) {
+            self.decommit_range(NonNull::new_unchecked(addr as *mut libc::c_void), len)?;
+            // Discarded pages stay accessible.
+            if !self.lazy_commit {
+                self.decommitted_holes.insert(addr, len);
+            }
+            decommitted_bytes += len;
+        }
+
+        // Put back the blocks of the runs left for a later call.
+        let mut finished = true;
+        for (_, _, run_blocks) in runs {
+            finished = false;
+            for (addr, class_of_subheap) in run_blocks {
+                self.detached_ranges.remove(&addr);
+                #[cfg(debug_assertions)]
+                self.carved_classes.insert(addr, class_of_subheap);
+                self.free_on_subheap(NonNull::new_unchecked(addr as *mut Header), class_of_subheap);
+            }
+        }
+        Ok((decommitted_bytes, finished))
+    }
+
+    /// Takes the free blocks at the addresses in `removed` off their lists,
+    /// keeping the order of the rest.
+    unsafe fn unlink_free_blocks(&mut self, removed: &std::collections::BTreeSet<usize>) {
+        for class_of_subheap in 0..SUBHEAP_COUNT {
+            let mut nodes = Vec::new();
+            let mut node = self.free_lists[class_of_subheap];
+            while let Some(free_ptr) = NonNull::new(node) {
+                nodes.push(free_ptr);
+                node = self.next_of(free_ptr);
+            }
+
+            let mut next = std::ptr::null_mut();
+            for &free_ptr in nodes.iter().rev() {
+                if !removed.contains(&(free_ptr.as_ptr() as usize)) {
+                    self.set_next(free_ptr, next);
+                    next = free_ptr.as_ptr();
+                }
+            }
+            self.free_lists[class_of_subheap] = next;
+        }
+    }
+
+    /// A copy of the free list heads of every class, so tests can inspect
+    /// or rebuild free lists without going through real allocations.
+    #[cfg(feature = "testing")]
+    #[allow(unused)]
+    pub fn clone_free_list_state_for_testing(&self) -> [*mut FreeHeader; SUBHEAP_COUNT] {
+        self.free_lists
+    }
+
+    /// Replaces the free list heads of every class. Every node reachable
+    /// from them must be a carved block of its list's class, linked as
+    /// `set_next` links it; the byte accounting is left alone.
+    #[cfg(feature = "testing")]
+    #[allow(unused)]
+    pub unsafe fn set_free_list_state_for_testing(&mut self, free_lists: [*mut FreeHeader; SUBHEAP_COUNT]) {
+        self.free_lists = free_lists;
+    }
+
+    /// Calls `f` with the class and data pointer of every free block, the
+    /// pointer `alloc` would hand out for it, in free list order.
+    ///
+    /// Each list walk stops after as many nodes as could fit in the carved
+    /// heap, so a corrupted list with a cycle cannot loop forever.
+    #[allow(unused)]
+    pub fn for_each_free_block(&self, mut f: impl FnMut(usize, NonNull<u8>)) {
+        let carved_bytes = self.carved_bytes();
+        for class_of_subheap in 0..SUBHEAP_COUNT {
+            let max_nodes = carved_bytes / carved_size_of_subheap(class_of_subheap);
+            let mut node = self.free_lists[class_of_subheap];
+            for _ in 0..max_nodes {
+                let Some(free_ptr) = NonNull::new(node) else {
+                    break;
+                };
+                node = unsafe { self.next_of(free_ptr) };
+                let data = unsafe { (free_ptr.as_ptr() as *mut u8).add(size_of::<Header>()) };
+                f(class_of_subheap, unsafe { NonNull::new_unchecked(data) });
+            }
+        }
+    }
+
+    /// Rebuilds every free list in ascending address order, so that the
+    /// following allocations of a class are handed out from low addresses first.
+    #[allow(unused)]
+    pub fn compact_free_lists(&mut self) {
+        for class_of_subheap in 0..SUBHEAP_COUNT {
+            self.compact_free_list(class_of_subheap);
+        }
+    }
+
+    /// Hands idle memory back to the OS, as much as `level` asks for, and
+    /// returns how many bytes were released.
+    #[allow(unused)]
+    pub unsafe fn release_to_os(&mut self, level: ReleaseLevel) -> Result<usize, Box<dyn Error>> {
+        let mut released = self.trim_tail()?;
+        if level >= ReleaseLevel::Aggressive {
+            released += self.reclaim_empty_subheap_pages()?;
+            released += self.evict_external_cache()?;
+        }
+        Ok(released)
+    }
+
+    /// Unmaps the freed external mappings and huge-page blocks kept for reuse.
+    unsafe fn evict_external_cache(&mut self) -> Result<usize, Box<dyn Error>> {
+        let mut released = 0;
+        while let Some((base, size)) = self.external_cache.pop() {
+            #[cfg(feature = "profile")]
+            {
+                self.syscall_counts.release += 1;
+            }
+            sys::release(NonNull::new_unchecked(base as *mut libc::c_void), size)?;
+            released += size;
+        }
+        while let Some(base) = self.hugepage_free_list.pop() {
+            #[cfg(feature = "profile")]
+            {
+                self.syscall_counts.release += 1;
+            }
+            sys::release(NonNull::new_unchecked(base as *mut libc::c_void), HUGEPAGE_SIZE + self.pagesize)?;
+            released += HUGEPAGE_SIZE + self.pagesize;
+        }
+        Ok(released)
+    }
+
+    /// Gives the free blocks at the end of each carved region back to the
+    /// uncarved part, then decommits the committed pages past the new end.
+    /// Heaps over a caller's buffer or mapping are left alone.
+    unsafe fn trim_tail(&mut self) -> Result<usize, Box<dyn Error>> {
+        if !self.owns_reservation {
+            return Ok(0);
+        }
+
+        let mut free = std::collections::BTreeSet::new();
+        self.for_each_free_block(|_, data| {
+            free.insert(data.as_ptr() as usize - size_of::<Header>());
+        });
+        let mut uncarved = std::collections::BTreeSet::new();
+        for class_of_subheap in 0..self.bump_regions().len() {
+            let mut region = self.bump_region(class_of_subheap);
+            let mut blocks = self.carved_blocks_in(&region);
+            let mut end = region.active.as_ptr() as usize;
+            loop {
+                if let Some((&addr, &size)) = self.detached_ranges.range(..end).next_back() {
+                    if addr + size == end {
+                        if self.shard_ranges.contains(&addr) {
+                            break;
+                        }
+                        self.detached_ranges.remove(&addr);
+                        end = addr;
+                        continue;
+                    }
+                }
+                match blocks.last() {
+                    Some(&block) if free.contains(&(block.as_ptr() as usize)) => {
+                        blocks.pop();
+                        end = block.as_ptr() as usize;
+                        uncarved.insert(end);
+                        #[cfg(debug_assertions)]
+                        self.carved_classes.remove(&end);
+                    }
+                    _ => break,
+                }
+            }
+            region.active = NonNull::new_unchecked(end as *mut libc::c_void);
+            self.set_bump_region(class_of_subheap, region);
+        }
+        self.unlink_free_blocks(&uncarved);
+
+        let mut trimmed = 0;
+        for class_of_subheap in 0..self.bump_regions().len() {
+            let mut region = self.bump_region(class_of_subheap);
+            let carved_end = aligned_size(region.active.as_ptr() as usize, self.pagesize);
+            let len = (region.committed.as_ptr() as usize).saturating_sub(carved_end);
+            if len == 0 {
+                continue;
+            }
+            let holes: Vec<usize> = self.decommitted_holes.range(carved_end..carved_end + len).map(|(&hole, _)| hole).collect();
+            for hole in holes {
+                self.decommitted_holes.remove(&hole);
+            }
+            let carved_end = NonNull::new_unchecked(carved_end as *mut libc::c_void);
+            self.decommit_range(carved_end, len)?;
+            region.committed = carved_end;
+            self.set_bump_region(class_of_subheap, region);
+            trimmed += len;
+        }
+        Ok(trimmed)
+    }
+
+    /// Moves the live blocks of `class_of_subheap` into its free blocks at
+    /// lower addresses, then decommits the pages left empty, and returns
+    /// how many bytes were decommitted.
+    ///
+    /// `relocate(old, new)` is called for every moved block after its data
+    /// is copied. Handles from `alloc_handle` are updated here, but any
+    /// other reference to a block of the class dangles afterwards, so this
+    /// is only sound when all of them go through a layer `relocate` fixes up.
+    /// Page reclamation covers every class, as in `reclaim_empty_subheap_pages`.
+    #[allow(unused)]
+    pub unsafe fn defragment_class(
+        &mut self,
+        class_of_subheap: usize,
+        mut relocate: impl FnMut(NonNull<u8>, NonNull<u8>),
+    ) -> Result<usize, Box<dyn Error>> {
+        assert!(class_of_subheap < SUBHEAP_COUNT);
+        // Queued frees point at live blocks that might otherwise be moved.
+        self.flush_deferred()?;
+
+        let mut free_blocks = Vec::new();
+        let mut node = std::mem::replace(&mut self.free_lists[class_of_subheap], std::ptr::null_mut());
+        while let Some(free_ptr) = NonNull::new(node) {
+            node = self.next_of(free_ptr);
+            free_blocks.push(free_ptr.cast::<Header>());
+        }
+        free_blocks.sort_unstable();
+        let mut live_blocks: Vec<NonNull<Header>> = self
+            .carved_blocks()
+            .into_iter()
+            .filter(|block| block.as_ref().size_or_class_of_subheap == class_of_subheap)
+            .filter(|block| free_blocks.binary_search(block).is_err())
+            .collect();
+
+        // Move the highest live blocks into the lowest free ones.
+        let mut moves = std::collections::BTreeMap::new();
+        let mut lowest_free = 0;
+        while lowest_free < free_blocks.len() {
+            match live_blocks.last() {
+                Some(&live) if free_blocks[lowest_free] < live => {
+                    live_blocks.pop();
+                    let free = std::mem::replace(&mut free_blocks[lowest_free], live);
+                    std::ptr::copy_nonoverlapping(
+                        live.as_ptr() as *const u8,
+                        free.as_ptr() as *mut u8,
+                        carved_size_of_subheap(class_of_subheap),
+                    );
+                    let old_ptr = NonNull::new_unchecked((live.as_ptr() as *mut u8).add(size_of::<Header>()));
+                    let new_ptr = NonNull::new_unchecked((free.as_ptr() as *mut u8).add(size_of::<Header>()));
+                    relocate(old_ptr, new_ptr);
+                    moves.insert(old_ptr, new_ptr);
+                    lowest_free += 1;
+                }
+                _ => break,
+            }
+        }
+        for slot in &mut self.handle_slots {
+            if let Some(new_ptr) = slot.ptr.and_then(|ptr| moves.get(&ptr)) {
+                slot.ptr = Some(*new_ptr);
+            }
+        }
+
+        free_blocks.sort_unstable();
+        for &free in free_blocks.iter().rev() {
+            self.free_on_subheap(free, class_of_subheap);
+        }
+        self.reclaim_empty_subheap_pages()
+    }
+
+    /// Walks the carved heap block by block, skipping detached ranges.
+    unsafe fn carved_blocks(&self) -> Vec<NonNull<Header>> {
+        self.bump_regions().iter().flat_map(|region| self.carved_blocks_in(region)).collect()
+    }
+
+    unsafe fn carved_blocks_in(&self, region: &BumpRegion) -> Vec<NonNull<Header>> {
+        let mut blocks = Vec::new();
+        let mut addr = region.begin.as_ptr() as usize;
+        let end = region.active.as_ptr() as usize;
+        while addr < end {
+            if let Some(&size) = self.detached_ranges.get(&addr) {
+                addr += size;
+                continue;
+            }
+            let block = NonNull::new_unchecked(addr as *mut Header);
+            addr += carved_size_of_subheap(block.as_ref().size_or_class_of_subheap);
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    /// The regions blocks are carved from: the shared one, or one per class.
+    fn bump_regions(&self) -> Vec<BumpRegion> {
+        if self.class_regions.is_empty() {
+            vec![self.bump_region(0)]
+        } else {
+            self.class_regions.clone()
+        }
+    }
+
+    /// The region blocks of `class_of_subheap` are carved from.
+    fn bump_region(&self, class_of_subheap: usize) -> BumpRegion {
+        match self.class_regions.get(class_of_subheap) {
+            Some(&region) => region,
+            None => BumpRegion {
+                begin: self.heap_begin,
+                end: self.carvable_heap_end,
+                active: self.active_heap_end,
+                committed: self.commited_heap_end,
+                strided: false,
+            },
+        }
+    }
+
+    fn set_bump_region(&mut self, class_of_subheap: usize, region: BumpRegion) {
+        match self.class_regions.get_mut(class_of_subheap) {
+            Some(class_region) => *class_region = region,
+            None => {
+                self.active_heap_end = region.active;
+                self.commited_heap_end = region.committed;
+            }
+        }
+    }
+
+    /// Bytes committed in the reservation, carved or not.
+    fn committed_subheap_bytes(&self) -> usize {
+        self.bump_regions()
+            .iter()
+            .map(|region| region.committed.as_ptr() as usize - region.begin.as_ptr() as usize)
+            .sum()
+    }
+
+    /// Bytes carved from the reservation, free or live.
+    fn carved_bytes(&self) -> usize {
+        self.bump_regions()
+            .iter()
+            .map(|region| region.active.as_ptr() as usize - region.begin.as_ptr() as usize)
+            .sum()
+    }
+
+    /// Reclaims empty pages one run at a time, then compacts free lists one
+    /// class at a time, until `budget` runs out, for callers running
+    /// periodic maintenance instead of trimming on the alloc and free paths.
+    /// The report tells whether everything got done; the next call picks up
+    /// what was left.
+    ///
+    /// The budget is checked between steps, so a step that has started
+    /// runs to completion and may overrun it. Finding the empty pages is a
+    /// single step, with a walk of every free list.
+    #[allow(unused)]
+    pub unsafe fn maintenance(&mut self, budget: std::time::Duration) -> Result<MaintenanceReport, Box<dyn Error>> {
+        let deadline = std::time::Instant::now() + budget;
+        let (bytes_decommitted, reclaimed_all) = self.reclaim_empty_subheap_pages_until(Some(deadline))?;
+        let mut report = MaintenanceReport { bytes_decommitted, ..MaintenanceReport::default() };
+        for class_of_subheap in 0..SUBHEAP_COUNT {
+            if std::time::Instant::now() >= deadline {
+                break;
+            }
+            self.compact_free_list(class_of_subheap);
+            report.lists_compacted += 1;
+        }
+        report.finished = reclaimed_all && report.lists_compacted == SUBHEAP_COUNT;
+        Ok(report)
+    }
+
+    fn compact_free_list(&mut self, class_of_subheap: usize) {
+        let mut nodes = Vec::new();
+        let mut node = self.free_lists[class_of_subheap];
+        while let Some(free_ptr) = NonNull::new(node) {
+            nodes.push(free_ptr);
+            node = unsafe { self.next_of(free_ptr) };
+        }
+
+        nodes.sort_unstable();
+        let mut next = std::ptr::null_mut();
+        for &free_ptr in nodes.iter().rev() {
+            unsafe { self.set_next(free_ptr, next) };
+            next = free_ptr.as_ptr();
+        }
+        self.free_lists[class_of_subheap] = next;
+    }
+
+    unsafe fn alloc_on_subheap<T>(&mut self, class_of_subheap: usize) -> Result<NonNull<T>, Box<dyn Error>> {
+        if let Some(used_ptr) = self.pop_free_list(class_of_subheap) {
+            return Ok(used_ptr);
+        }
+
+        let allocated_ptr = match self.split_larger_block(class_of_subheap)? {
+            Some(allocated_ptr) => allocated_ptr,
+            None => {
+                if self.coalesce_commit(class_of_subheap)? || self.batch_extend(class_of_subheap)? {
+                    if let Some(used_ptr) = self.pop_free_list(class_of_subheap) {
+                        return Ok(used_ptr);
+                    }
+                }
+                self.extend_active_heap_end(class_of_subheap)?
+            }
+        };
+        self.clear_free_link(allocated_ptr.cast());
+        self.live_subheap_bytes += carved_size_of_subheap(class_of_subheap);
+        let allocated_ptr: NonNull<libc::c_void> = allocated_ptr.cast();
+        Ok(NonNull::new_unchecked(allocated_ptr.as_ptr().add(size_of::<Header>()) as *mut T))
+    }
+
+    /// Commits `COALESCED_COMMIT_PAGES` pages in one go when a class with
+    /// sub-page blocks is about to run past the committed end, and seeds
+    /// the blocks fitting in them onto its free list, so that the page by
+    /// page commits of a run of small allocations take a single syscall.
+    ///
+    /// Returns whether the free list was seeded.
+    unsafe fn coalesce_commit(&mut self, class_of_subheap: usize) -> Result<bool, Box<dyn Error>> {
+        let allocated_size = carved_size_of_subheap(class_of_subheap);
+        if allocated_size >= self.pagesize || self.cacheline_align {
+            return Ok(false);
+        }
+        let region = self.bump_region(class_of_subheap);
+        let active = region.active.as_ptr() as usize;
+        let committed = region.committed.as_ptr() as usize;
+        if active + allocated_size <= committed {
+            return Ok(false);
+        }
+
+        let remaining = region.end.as_ptr() as usize - active;
+        let count = (COALESCED_COMMIT_PAGES * self.pagesize).min(remaining) / allocated_size;
+        self.seed_free_list(class_of_subheap, count)
+    }
+
+    /// Carves `extension_batch` blocks at once when a class's free list
+    /// runs empty, and seeds them onto it, see `set_extension_batch`.
+    ///
+    /// Returns whether the free list was seeded.
+    unsafe fn batch_extend(&mut self, class_of_subheap: usize) -> Result<bool, Box<dyn Error>> {
+        if self.extension_batch < 2 || self.cacheline_align {
+            return Ok(false);
+        }
+        let region = self.bump_region(class_of_subheap);
+        let remaining = region.end.as_ptr() as usize - region.active.as_ptr() as usize;
+        let count = self.extension_batch.min(remaining / carved_size_of_subheap(class_of_subheap));
+        self.seed_free_list(class_of_subheap, count)
+    }
+
+    /// Carves `count` blocks of the class, which must fit in its region,
+    /// committing them in one go, and splices them onto its list in one
+    /// step so that they are handed out in address order. Does nothing for
+    /// fewer than two blocks, or when committing them would cross the soft
+    /// limit.
+    ///
+    /// Returns whether the free list was seeded.
+    unsafe fn seed_free_list(&mut self, class_of_subheap: usize, count: usize) -> Result<bool, Box<dyn Error>> {
+        let mut region = self.bump_region(class_of_subheap);
+        let reserved_end = region.active.as_ptr() as usize + count * carved_size_of_subheap(class_of_subheap);
+        let committed_size = aligned_size(
+            reserved_end.saturating_sub(region.committed.as_ptr() as usize),
+            self.commit_granularity,
+        );
+        if count < 2 || self.check_soft_limit(committed_size).is_err() {
+            return Ok(false);
+        }
+
+        let reserved_end = NonNull::new_unchecked(reserved_end as *mut libc::c_void);
+        self.commit_until(&mut region, reserved_end)?;
+        self.set_bump_region(class_of_subheap, region);
+        let mut blocks = Vec::with_capacity(count);
+        for _ in 0..count {
+            blocks.push(self.extend_active_heap_end(class_of_subheap)?);
+        }
+
+        // Chain the blocks in address order. They lie past every carved
+        // block, so they go at the head for `Lifo` and at the tail for the
+        // other policies, where they are the newest and highest blocks.
+        let blocks: Vec<NonNull<FreeHeader>> = blocks.into_iter().map(NonNull::cast).collect();
+        let (first, last) = (blocks[0], blocks[blocks.len() - 1]);
+        for pair in blocks.windows(2) {
+            self.set_next(pair[0], pair[1].as_ptr());
+        }
+        #[cfg(feature = "debug-headers")]
+        for &block in &blocks {
+            self.mark_freed(block.cast());
+        }
+        if self.free_policies[class_of_subheap] == FreePolicy::Lifo {
+            self.set_next(last, self.free_lists[class_of_subheap]);
+            self.free_lists[class_of_subheap] = first.as_ptr();
+            return Ok(true);
+        }
+        self.set_next(last, std::ptr::null_mut());
+        let mut tail = None;
+        let mut node = self.free_lists[class_of_subheap];
+        while let Some(free_ptr) = NonNull::new(node) {
+            tail = Some(free_ptr);
+            node = self.next_of(free_ptr);
+        }
+        match tail {
+            None => self.free_lists[class_of_subheap] = first.as_ptr(),
+            Some(tail) => self.set_next(tail, first.as_ptr()),
+        }
+        Ok(true)
+    }
+
+    #[inline(always)]
+    unsafe fn pop_free_list<T>(&mut self, class_of_subheap: usize) -> Option<NonNull<T>> {
+        let free_ptr = NonNull::new(self.free_lists[class_of_subheap])?;
+        let next = self.next_of(free_ptr);
+        #[cfg(feature = "harden")]
+        self.check_free_link(class_of_subheap, next);
+        // The next alloc of this class reads the new head's link.
+        if !next.is_null() {
+            prefetch_read(next);
+        }
+        self.free_lists[class_of_subheap] = next;
+        self.live_subheap_bytes += carved_size_of_subheap(class_of_subheap);
+        self.clear_free_link(free_ptr);
+        let used_ptr: NonNull<libc::c_void> = free_ptr.cast();
+        Some(NonNull::new_unchecked(used_ptr.as_ptr().add(size_of::<Header>()) as *mut T))
+    }
+
+    /// Whether the block still carries the canary of a free list link, as
+    /// it does when it is freed twice. Blocks lose the canary
+    /// when they are handed out, so only live data that happens to match a
+    /// secret-keyed link is mistaken for a freed block.
+    #[inline(always)]
+    unsafe fn looks_freed(&self, addr: NonNull<Header>) -> bool {
+        let node: NonNull<FreeHeader> = addr.cast();
+        node.as_ref().canary == node.as_ref().next as usize ^ self.free_list_secret
+    }
+
+    /// Drops the canary of a block leaving its free list, so `looks_freed`
+    /// does not take it for freed until it is freed again, and the
+    /// `FREED_TAG` it was marked with.
+    #[inline(always)]
+    unsafe fn clear_free_link(&self, mut node: NonNull<FreeHeader>) {
+        node.as_mut().canary = !(node.as_ref().next as usize ^ self.free_list_secret);
+        #[cfg(feature = "debug-headers")]
+        {
+            let mut header: NonNull<Header> = node.cast();
+            header.as_mut().tag = 0;
+        }
+    }
+
+    /// Whether allocs and frees can skip the bookkeeping of the slow path,
+    /// which only the watermark, recording, profiler hooks and fault
+    /// injection need.
+    #[inline(always)]
+    fn fast_path_enabled(&self) -> bool {
+        #[cfg(feature = "fault-injection")]
+        if self.oom_after.is_some() {
+            return false;
+        }
+        self.high_watermark.is_none() && self.recording.is_none() && self.on_alloc.is_none() && self.on_free.is_none()
+    }
+
+    /// Splits a free block of a larger class into blocks of `class_of_subheap`,
+    /// returning the first and freeing the rest onto its list.
+    ///
+    /// Block sizes double from class to class, but every block also carries
+    /// a header, so a block of the next class holds only one block of this
+    /// class. Splitting therefore starts two classes up, where at least two
+    /// fit. The tail that fits no whole block is lost until the heap is dropped.
+    unsafe fn split_larger_block(&mut self, class_of_subheap: usize) -> Result<Option<NonNull<Header>>, Box<dyn Error>> {
+        if self.cacheline_align || !self.class_regions.is_empty() {
+            return Ok(None);
+        }
+        let Some(larger_class) =
+            (class_of_subheap + 2..SUBHEAP_COUNT).find(|&larger_class| !self.free_lists[larger_class].is_null())
+        else {
+            return Ok(None);
+        };
+        let mut block: NonNull<Header> = NonNull::new_unchecked(self.free_lists[larger_class]).cast();
+        self.free_lists[larger_class] = self.next_of(block.cast());
+
+        *block.as_mut() = Header {
+            size_or_class_of_subheap: class_of_subheap,
+            #[cfg(feature = "debug-headers")]
+            tag: 0,
+            #[cfg(feature = "debug-headers")]
+            generation: self.next_generation(),
+        };
+        self.split_block_tail(block, larger_class, class_of_subheap)?;
+        Ok(Some(block))
+    }
+
+    /// Carves the bytes of a `larger_class` block past its first block of
+    /// `class_of_subheap` into more such blocks, freeing them onto its list.
+    /// The header of the first block is left to the caller.
+    unsafe fn split_block_tail(
+        &mut self,
+        block: NonNull<Header>,
+        larger_class: usize,
+        class_of_subheap: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let piece_size = carved_size_of_subheap(class_of_subheap);
+        let pieces = carved_size_of_subheap(larger_class) / piece_size;
+        let tail_size = carved_size_of_subheap(larger_class) - pieces * piece_size;
+
+        let block: NonNull<u8> = block.cast();
+        if tail_size > 0 {
+            self.detached_ranges.insert(block.as_ptr() as usize + pieces * piece_size, tail_size);
+        }
+        #[cfg(debug_assertions)]
+        self.carved_classes.insert(block.as_ptr() as usize, class_of_subheap);
+        for index in 1..pieces {
+            let mut piece: NonNull<Header> = NonNull::new_unchecked(block.as_ptr().add(index * piece_size)).cast();
+            *piece.as_mut() = Header {
+                size_or_class_of_subheap: class_of_subheap,
+                #[cfg(feature = "debug-headers")]
+                tag: 0,
+                #[cfg(feature = "debug-headers")]
+                generation: self.next_generation(),
+            };
+            #[cfg(debug_assertions)]
+            self.carved_classes.insert(piece.as_ptr() as usize, class_of_subheap);
+            self.free_on_subheap(piece, class_of_subheap);
+        }
+        Ok(())
+    }
+
+    /// Fails with `ForeignPointer` for blocks this allocator never carved,
+    /// such as those of another shard, and in debug builds with
+    /// `ClassMismatch` for a header not matching the carved class.
+    ///
+    /// Debug builds look the block up among the carved blocks, which are
+    /// tracked on the side so headers stay small. Release builds only
+    /// check that a block of the class may start there, as
+    /// `may_be_carved` does.
+    fn check_carved_class(&self, addr: NonNull<Header>, class_of_subheap: usize) -> Result<(), Box<dyn Error>> {
+        if class_of_subheap >= SUBHEAP_COUNT {
+            return Err(AllocError::ForeignPointer.into());
+        }
+        #[cfg(debug_assertions)]
+        match self.carved_classes.get(&(addr.as_ptr() as usize)) {
+            None => return Err(AllocError::ForeignPointer.into()),
+            Some(&carved_class) if carved_class != class_of_subheap => return Err(AllocError::ClassMismatch.into()),
+            Some(_) => {}
+        }
+        if !self.may_be_carved(addr.as_ptr() as usize, class_of_subheap) {
+            return Err(AllocError::ForeignPointer.into());
+        }
+        Ok(())
+    }
+
+    /// Fails with `ForeignPointer` for external allocations this allocator
+    /// has not mapped.
+    fn check_external(&self, addr: NonNull<Header>) -> Result<(), Box<dyn Error>> {
+        if !self.external_regions.contains_key(&(self.external_base(addr).as_ptr() as usize)) {
+            return Err(AllocError::ForeignPointer.into());
+        }
+        Ok(())
+    }
+
+    unsafe fn free_on_subheap(&mut self, addr: NonNull<Header>, class_of_subheap: usize) {
+        #[cfg(feature = "debug-headers")]
+        self.mark_freed(addr);
+        let addr: NonNull<FreeHeader> = addr.cast();
+
+        // Find the link to insert `addr` at, according to the class policy.
+        let mut prev: Option<NonNull<FreeHeader>> = None;
+        let mut next = self.free_lists[class_of_subheap];
+        match self.free_policies[class_of_subheap] {
+            FreePolicy::Lifo => {}
+            FreePolicy::Fifo => {
+                while let Some(node) = NonNull::new(next) {
+                    prev = Some(node);
+                    next = self.next_of(node);
+                }
+            }
+            FreePolicy::AddressOrdered => {
+                while let Some(node) = NonNull::new(next) {
+                    if addr < node {
+                        break;
+                    }
+                    prev = Some(node);
+                    next = self.next_of(node);
+                }
+            }
+        }
+
+        self.set_next(addr, next);
+        match prev {
+            None => self.free_lists[class_of_subheap] = addr.as_ptr(),
+            Some(prev) => self.set_next(prev, addr.as_ptr()),
+        }
+    }
+
+    /// Takes the next value of the generation clock. Every carve and free
+    /// takes one, so a block carved again where a freed one was, after a
+    /// trim or a split, cannot match the generation of a stale pointer.
+    #[cfg(feature = "debug-headers")]
+    #[inline(always)]
+    fn next_generation(&mut self) -> u32 {
+        self.generation_clock = self.generation_clock.wrapping_add(1);
+        self.generation_clock
+    }
+
+    /// Marks a block going onto a free list: it takes a new generation,
+    /// which stales its `TrackedPtr`s, and `FREED_TAG`.
+    #[cfg(feature = "debug-headers")]
+    #[inline(always)]
+    unsafe fn mark_freed(&mut self, mut addr: NonNull<Header>) {
+        addr.as_mut().generation = self.next_generation();
+        addr.as_mut().tag = FREED_TAG;
+    }
+
+    /// Frees onto the head of the list, as `free_on_subheap` does for `Lifo`.
+    #[inline(always)]
+    unsafe fn push_free_list(&mut self, addr: NonNull<Header>, class_of_subheap: usize) {
+        #[cfg(feature = "debug-headers")]
+        self.mark_freed(addr);
+        let addr: NonNull<FreeHeader> = addr.cast();
+        self.set_next(addr, self.free_lists[class_of_subheap]);
+        self.free_lists[class_of_subheap] = addr.as_ptr();
+    }
+
+    unsafe fn set_next(&self, mut node: NonNull<FreeHeader>, next: *mut FreeHeader) {
+        node.as_mut().next = next;
+        node.as_mut().canary = next as usize ^ self.free_list_secret;
+    }
+
+    unsafe fn next_of(&self, node: NonNull<FreeHeader>) -> *mut FreeHeader {
+        let next = node.as_ref().next;
+        #[cfg(feature = "harden")]
+        if node.as_ref().canary != next as usize ^ self.free_list_secret {
+            panic!("free list corruption detected.");
+        }
+        next
+    }
+
+    /// Panics unless `next` is null or may be a block carved for the class,
+    /// as `may_be_carved` tells, so that a link overwritten along with its
+    /// canary is not handed out as a block.
+    #[cfg(feature = "harden")]
+    fn check_free_link(&self, class_of_subheap: usize, next: *mut FreeHeader) {
+        if !next.is_null() && !self.may_be_carved(next as usize, class_of_subheap) {
+            panic!("free list of class {} links outside the carved heap: {:#x}.", class_of_subheap, next as usize);
+        }
+    }
+
+    /// Whether a block of the class can start at `addr`: header aligned,
+    /// inside the carved part of the class's region but not in a range
+    /// detached from it, and on the region's stride while it has one. In
+    /// debug builds `addr` must also be a
+    /// block carved for the class; otherwise an address inside a block of
+    /// the shared region passes.
+    fn may_be_carved(&self, addr: usize, class_of_subheap: usize) -> bool {
+        let region = self.bump_region(class_of_subheap);
+        let begin = region.begin.as_ptr() as usize;
+        let carved_size = carved_size_of_subheap(class_of_subheap);
+        if addr < begin
+            || addr > (region.active.as_ptr() as usize).saturating_sub(carved_size)
+            || !addr.is_multiple_of(align_of::<Header>())
+            || (region.strided && !(addr - begin).is_multiple_of(carved_size))
+        {
+            return false;
+        }
+        if self.detached_ranges.range(..=addr).next_back().is_some_and(|(&detached, &len)| addr < detached + len) {
+            return false;
+        }
+        #[cfg(debug_assertions)]
+        if self.carved_classes.get(&addr) != Some(&class_of_subheap) {
+            return false;
+        }
+        true
+    }
+
+    /// Returns the mapping size of an external allocation of `len` bytes,
+    /// or `None` if it overflows.
+    fn external_size(&self, len: usize) -> Option<usize> {
+        let allocated_size = len.checked_add(size_of::<Header>())?.checked_add(self.pagesize - 1)?;
+        if allocated_size > isize::MAX as usize {
+            return None;
+        }
+        Some(aligned_size(allocated_size - (self.pagesize - 1), self.pagesize))
+    }
+
+    unsafe fn alloc_on_external<T>(&mut self, len: usize) -> Result<NonNull<T>, Box<dyn Error>> {
+        let allocated_size = self.external_size(len).ok_or(AllocError::TooLarge)?;
+        let cached = self
+            .external_cache
+            .iter()
+            .position(|&(_, size)| self.external_reuse && size == allocated_size);
+        let mut allocated_ptr: NonNull<Header> = match cached {
+            Some(index) => {
+                let (base, _) = self.external_cache.swap_remove(index);
+                NonNull::new_unchecked(base as *mut Header)
+            }
+            None => {
+                self.check_soft_limit(allocated_size)?;
+                #[cfg(feature = "profile")]
+                {
+                    self.syscall_counts.map += 1;
+                }
+                sys::alloc(allocated_size).map_err(AllocError::MapFailed)?.cast()
+            }
+        };
+        self.external_bytes += allocated_size;
+        self.external_count += 1;
+        self.external_regions.insert(allocated_ptr.as_ptr() as usize, allocated_size);
+        *allocated_ptr.as_mut() = Header {
+            size_or_class_of_subheap: allocated_size,
+            #[cfg(feature = "debug-headers")]
+            tag: 0,
+            #[cfg(feature = "debug-headers")]
+            generation: 0,
+        };
+        let allocated_ptr: NonNull<libc::c_void> = allocated_ptr.cast();
+        Ok(NonNull::new_unchecked(allocated_ptr.as_ptr().add(size_of::<Header>()) as *mut T))
+    }
+
+    #[track_caller]
+    unsafe fn remap_external<T>(
+        &mut self,
+        header: NonNull<Header>,
+        size: usize,
+        new_len: usize,
+    ) -> Result<NonNull<T>, Box<dyn Error>> {
+        let new_size = self.external_size(new_len).ok_or(AllocError::TooLarge)?;
+        self.check_soft_limit(new_size - size)?;
+        #[cfg(feature = "profile")]
+        {
+            self.syscall_counts.map += 1;
+        }
+        let mut new_header: NonNull<Header> = sys::remap(header.cast(), size, new_size).map_err(AllocError::MapFailed)?.cast();
+        self.external_bytes += new_size - size;
+        self.external_regions.remove(&(header.as_ptr() as usize));
+        self.external_regions.insert(new_header.as_ptr() as usize, new_size);
+        new_header.as_mut().size_or_class_of_subheap = new_size;
+        let ptr = NonNull::new_unchecked((header.as_ptr() as *mut u8).add(size_of::<Header>()));
+        let new_ptr = NonNull::new_unchecked((new_header.as_ptr() as *mut u8).add(size_of::<Header>()));
+        self.note_free(ptr);
+        self.note_alloc(new_ptr, new_len);
+        self.check_high_watermark();
+        Ok(new_ptr.cast())
+    }
+
+    unsafe fn free_on_external(&mut self, addr: NonNull<Header>, size: usize) -> Result<(), Box<dyn Error>> {
+        let base = self.external_base(addr);
+        if self.hugepage_blocks.remove(&(base.as_ptr() as usize)) {
+            self.hugepage_free_list.push(base.as_ptr() as usize);
+            self.external_bytes -= size;
+            self.external_count -= 1;
+            self.external_regions.remove(&(base.as_ptr() as usize));
+            return Ok(());
+        }
+        // Only plain writable mappings, with the header at the base, are
+        // handed out again.
+        let protected = self.protected_externals.remove(&(base.as_ptr() as usize));
+        self.file_backed_externals.remove(&(base.as_ptr() as usize));
+        let reusable = self.external_reuse
+            && base == addr.cast()
+            && !protected
+            && size <= aligned_size(carved_size_of_subheap(SUBHEAP_COUNT - 1), self.pagesize)
+            && self.external_cache.len() < EXTERNAL_CACHE_CAPACITY;
+        if reusable {
+            self.external_cache.push((base.as_ptr() as usize, size));
+        } else {
+            #[cfg(feature = "profile")]
+            {
+                self.syscall_counts.release += 1;
+            }
+            sys::release(base, size).map_err(AllocError::ReleaseFailed)?;
+        }
+        self.external_bytes -= size;
+        self.external_count -= 1;
+        self.external_regions.remove(&(base.as_ptr() as usize));
+        Ok(())
+    }
+
+    /// Returns the start of the mapping of an external allocation.
+    ///
+    /// The header usually sits at the start, but file-backed mappings put
+    /// it at the end of a leading page so that their data is page aligned.
+    /// Either way the mapping starts at the page holding the header.
+    fn external_base(&self, header: NonNull<Header>) -> sys::AnyNonNull {
+        let offset = header.as_ptr() as usize & (self.pagesize - 1);
+        unsafe { NonNull::new_unchecked((header.as_ptr() as *mut u8).sub(offset) as *mut libc::c_void) }
+    }
+
+    unsafe fn usable_size_of(&self, header: NonNull<Header>) -> usize {
+        let size_or_class_of_subheap = header.as_ref().size_or_class_of_subheap;
+        if size_or_class_of_subheap <= MAX_BLOCK_SIZE {
+            block_size_of_subheap(size_or_class_of_subheap)
+        } else {
+            let size = size_or_class_of_subheap;
+            let front_size = header.as_ptr() as usize + size_of::<Header>() - self.external_base(header).as_ptr() as usize;
+            size - front_size
+        }
+    }
+
+    /// Releases the adjacent external allocations spanning `len` bytes from `begin`.
+    unsafe fn release_external_run(&mut self, begin: usize, len: usize) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "profile")]
+        {
+            self.syscall_counts.release += 1;
+        }
+        sys::release(NonNull::new_unchecked(begin as *mut libc::c_void), len).map_err(AllocError::ReleaseFailed)?;
+        let addrs: Vec<usize> = self.external_regions.range(begin..begin + len).map(|(&addr, _)| addr).collect();
+        for &addr in &addrs {
+            self.external_regions.remove(&addr);
+            self.protected_externals.remove(&addr);
+            self.file_backed_externals.remove(&addr);
+        }
+        self.external_bytes -= len;
+        self.external_count -= addrs.len();
+        Ok(())
+    }
+
+    unsafe fn extend_active_heap_end(&mut self, class_of_subheap: usize) -> Result<NonNull<Header>, Box<dyn Error>> {
+        let align = if self.cacheline_align { CACHELINE_SIZE } else { align_of::<Header>() };
+        self.extend_active_heap_end_aligned(class_of_subheap, align)
+    }
+
+    /// Carves a block whose data is aligned to `align`, recording the
+    /// padding needed before it as detached.
+    unsafe fn extend_active_heap_end_aligned(
+        &mut self,
+        class_of_subheap: usize,
+        align: usize,
+    ) -> Result<NonNull<Header>, Box<dyn Error>> {
+        let mut region = self.bump_region(class_of_subheap);
+        let data = region.active.as_ptr() as usize + size_of::<Header>();
+        let padding = aligned_size(data, align) - data;
+        let allocated_size = carved_size_of_subheap(class_of_subheap) + padding;
+        // Check on addresses first, as a pointer past the heap end is UB to form.
+        let remaining = region.end.as_ptr() as usize - region.active.as_ptr() as usize;
+        if allocated_size > remaining {
+            return Err(AllocError::OutOfReservedSpace.into());
+        }
+        let new_active_heap_end = NonNull::new_unchecked(region.active.as_ptr().add(allocated_size));
+
+        self.commit_until(&mut region, new_active_heap_end)?;
+
+        if padding > 0 {
+            self.detached_ranges.insert(region.active.as_ptr() as usize, padding);
+            region.strided = false;
+        }
+        let mut allocated_ptr: NonNull<Header> = NonNull::new_unchecked(region.active.as_ptr().add(padding)).cast();
+        region.active = new_active_heap_end;
+        self.set_bump_region(class_of_subheap, region);
+        #[cfg(debug_assertions)]
+        self.carved_classes.insert(allocated_ptr.as_ptr() as usize, class_of_subheap);
+
+        *allocated_ptr.as_mut() = Header {
+            size_or_class_of_subheap: class_of_subheap,
+            #[cfg(feature = "debug-headers")]
+            tag: 0,
+            #[cfg(feature = "debug-headers")]
+            generation: self.next_generation(),
+        };
+        Ok(allocated_ptr)
+    }
+
+    /// Zeroes the data of a block being freed, see `set_zero_on_free`.
+    /// The fence keeps the stores from being dropped as dead before the
+    /// block is linked or unmapped.
+    ///
+    /// Read-only and executable mappings would fault, and zeroing a
+    /// file-backed one would write the zeroes to the file, so those are
+    /// left as they are.
+    unsafe fn zero_freed_block(&self, addr: NonNull<Header>) {
+        if addr.as_ref().size_or_class_of_subheap > MAX_BLOCK_SIZE {
+            let base = self.external_base(addr).as_ptr() as usize;
+            if self.protected_externals.contains(&base) || self.file_backed_externals.contains(&base) {
+                return;
+            }
+        }
+        let data = (addr.as_ptr() as *mut u8).add(size_of::<Header>());
+        std::ptr::write_bytes(data, 0, self.usable_size_of(addr));
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Advises the pages of a free block past its link, see `set_lazy_free`.
+    unsafe fn advise_free_block(&self, addr: NonNull<Header>, class_of_subheap: usize) -> Result<(), Box<dyn Error>> {
+        if !self.owns_reservation {
+            return Ok(());
+        }
+        let begin = aligned_size(addr.as_ptr() as usize + size_of::<FreeHeader>(), self.pagesize);
+        let end = (addr.as_ptr() as usize + carved_size_of_subheap(class_of_subheap)) & !(self.pagesize - 1);
+        if begin < end {
+            sys::advise_free(NonNull::new_unchecked(begin as *mut libc::c_void), end - begin)?;
+        }
+        Ok(())
+    }
+
+    unsafe fn decommit_range(&mut self, addr: sys::AnyNonNull, len: usize) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "timing")]
+        let started = std::time::Instant::now();
+        #[cfg(feature = "profile")]
+        {
+            self.syscall_counts.decommit += 1;
+        }
+        if self.lazy_commit {
+            // The pages must stay writable, as commits no longer remap them.
+            let result = sys::discard(addr, len);
+            #[cfg(feature = "timing")]
+            self.commit_latency.record(started.elapsed());
+            return result;
+        }
+        let result = sys::decommit(addr, len, self.prefer_decommit_strategy);
+        #[cfg(feature = "timing")]
+        self.commit_latency.record(started.elapsed());
+        self.prefer_decommit_strategy = result?;
+        Ok(())
+    }
+
+    /// Reports an allocation that succeeded to the recording and to the
+    /// `on_alloc` hook, naming the first caller outside `#[track_caller]`
+    /// functions as its site.
+    #[track_caller]
+    fn note_alloc(&mut self, ptr: NonNull<u8>, len: usize) {
+        if let Some(recording) = &mut self.recording {
+            recording.record_alloc(ptr.as_ptr() as usize, len);
+        }
+        if let Some(on_alloc) = &mut self.on_alloc {
+            let site: *const std::panic::Location = std::panic::Location::caller();
+            on_alloc(ptr, len, site as usize as u64);
+        }
+    }
+
+    /// Reports a free that succeeded, as `note_alloc` does allocations.
+    fn note_free(&mut self, ptr: NonNull<u8>) {
+        if let Some(recording) = &mut self.recording {
+            recording.record_free(ptr.as_ptr() as usize);
+        }
+        if let Some(on_free) = &mut self.on_free {
+            on_free(ptr);
+        }
+    }
+
+    /// Counts an allocation against `set_oom_after`, failing the one past it.
+    #[cfg(feature = "fault-injection")]
+    fn count_down_oom(&mut self) -> Result<(), AllocError> {
+        match self.oom_after {
+            Some(0) => {
+                self.oom_after = None;
+                Err(AllocError::OutOfReservedSpace)
+            }
+            Some(n) => {
+                self.oom_after = Some(n - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check_high_watermark(&mut self) {
+        let used_bytes = self.used_bytes();
+        self.peak_used_bytes = self.peak_used_bytes.max(used_bytes);
+        if let Some(high_watermark) = &mut self.high_watermark {
+            if used_bytes < high_watermark.bytes {
+                high_watermark.crossed = false;
+            } else if !high_watermark.crossed {
+                high_watermark.crossed = true;
+                (high_watermark.callback)(used_bytes);
+            }
+        }
+    }
+
+    /// Fails if mapping `additional` more bytes would pass the soft limit.
+    fn check_soft_limit(&self, additional: usize) -> Result<(), AllocError> {
+        let Some(limit) = self.soft_limit else {
+            return Ok(());
+        };
+        let cached_bytes: usize = self.external_cache.iter().map(|&(_, size)| size).sum::<usize>()
+            + self.hugepage_free_list.len() * (HUGEPAGE_SIZE + self.pagesize);
+        let placed_bytes: usize = self.placed_ranges.values().sum();
+        match (self.committed_subheap_bytes() + self.external_bytes + cached_bytes + placed_bytes).checked_add(additional) {
+            Some(total) if total <= limit => Ok(()),
+            _ => Err(AllocError::OutOfReservedSpace),
+        }
+    }
+
+    unsafe fn commit_until(&mut self, region: &mut BumpRegion, new_active_heap_end: sys::AnyNonNull) -> Result<(), Box<dyn Error>> {
+        if region.committed < new_active_heap_end {
+            #[cfg(feature = "fault-injection")]
+            if self.fail_next_commit {
+                self.fail_next_commit = false;
+                return Err(AllocError::CommitFailed("Injected commit failure.".into()).into());
+            }
+
+            // A granularity over the page size may round past the region end.
+            let committed_size = aligned_size(
+                new_active_heap_end.as_ptr().offset_from(region.committed.as_ptr()) as usize,
+                self.commit_granularity,
+            )
+            .min(region.end.as_ptr().offset_from(region.committed.as_ptr()) as usize);
+            self.check_soft_limit(committed_size)?;
+            if !self.lazy_commit {
+                #[cfg(feature = "timing")]
+                let started = std::time::Instant::now();
+                #[cfg(feature = "profile")]
+                {
+                    self.syscall_counts.commit += 1;
+                }
+                let result = sys::commit(region.committed, committed_size, self.prefer_commit_strategy);
+                #[cfg(feature = "timing")]
+                self.commit_latency.record(started.elapsed());
+                self.prefer_commit_strategy = result.map_err(AllocError::CommitFailed)?;
+            }
+            if self.eager_commit {
+                let result = sys::populate(region.committed, committed_size, self.pagesize, self.prefer_populate_strategy);
+                match result {
+                    Ok(strategy) => self.prefer_populate_strategy = strategy,
+                    Err(error) => {
+                        // Leave the committed end where it was, with nothing past it
+                        // committed. The pages populated before the failure stay
+                        // charged through a decommit, so they are discarded first.
+                        let _ = sys::discard(region.committed, committed_size);
+                        let _ = self.decommit_range(region.committed, committed_size);
+                        return Err(AllocError::CommitFailed(error).into());
+                    }
+                }
+            }
+            region.committed = NonNull::new_unchecked(region.committed.as_ptr().add(committed_size));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Allocator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let offset_of = |ptr: sys::AnyNonNull| ptr.as_ptr() as usize - self.heap_begin.as_ptr() as usize;
+
+        // Stop each walk after as many nodes as fit in the carved heap, in
+        // case a list is corrupted into a cycle.
+        let carved_bytes = self.carved_bytes();
+        let free_list_lengths: Vec<usize> = (0..SUBHEAP_COUNT)
+            .map(|class_of_subheap| {
+                let max_nodes = carved_bytes / carved_size_of_subheap(class_of_subheap);
+                let mut nodes = 0;
+                let mut node = self.free_lists[class_of_subheap];
+                while let Some(free_ptr) = NonNull::new(node) {
+                    if nodes == max_nodes {
+                        break;
+                    }
+                    nodes += 1;
+                    node = unsafe { free_ptr.as_ref().next };
+                }
+                nodes
+            })
+            .collect();
+
+        f.debug_struct("Allocator")
+            .field("heap_begin", &self.heap_begin)
+            .field("heap_end", &self.heap_end)
+            .field("committed_offset", &offset_of(self.commited_heap_end))
+            .field("active_offset", &offset_of(self.active_heap_end))
+            .field("free_list_lengths", &free_list_lengths)
+            .field("commit_strategy", &self.prefer_commit_strategy)
+            .field("external_count", &self.external_count)
+            .finish()
+    }
+}
+
+// The raw pointers only refer to the allocator's own mappings, so it can
+// move to another thread along with them.
+unsafe impl Send for Allocator {}
+
+impl Drop for Allocator {
+    fn drop(&mut self) {
+        // Nothing can be reported from here, so failures are dropped.
+        unsafe {
+            for (&addr, &size) in &self.external_regions {
+                let _ = sys::release(NonNull::new_unchecked(addr as *mut libc::c_void), size);
+            }
+            for &(addr, size) in &self.external_cache {
+                let _ = sys::release(NonNull::new_unchecked(addr as *mut libc::c_void), size);
+            }
+            for &addr in &self.hugepage_free_list {
+                let _ = sys::release(NonNull::new_unchecked(addr as *mut libc::c_void), HUGEPAGE_SIZE + self.pagesize);
+            }
+            if self.owns_reservation {
+                let len = self.heap_end.as_ptr() as usize - self.heap_begin.as_ptr() as usize;
+                let _ = sys::release(self.heap_begin, len);
+            }
+        }
+    }
+}
+
+/// Memory syscalls made by an allocator, counted when they are attempted.
+#[cfg(feature = "profile")]
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct SyscallCounts {
+    /// Reservations of the heap.
+    pub reserve: u64,
+    /// Commits of heap pages.
+    pub commit: u64,
+    /// Decommits of heap pages.
+    pub decommit: u64,
+    /// Mappings and remappings of external allocations and stacks.
+    pub map: u64,
+    /// Unmappings of external allocations and stacks.
+    pub release: u64,
+}
+
+/// A histogram of durations in power-of-two nanosecond buckets:
+/// bucket `i` counts durations in `[2^i, 2^(i + 1))` ns, and bucket 0 also
+/// counts zero.
+#[cfg(feature = "timing")]
+pub struct LatencyHistogram {
+    buckets: [u64; 64],
+}
+
+#[cfg(feature = "timing")]
+#[allow(unused)]
+impl LatencyHistogram {
+    fn new() -> Self {
+        LatencyHistogram { buckets: [0; 64] }
+    }
+
+    fn record(&mut self, duration: std::time::Duration) {
+        let nanos = duration.as_nanos().min(u64::MAX as u128) as u64;
+        let bucket = nanos.checked_ilog2().unwrap_or(0) as usize;
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn buckets(&self) -> &[u64; 64] {
+        &self.buckets
+    }
+
+    /// The number of recorded samples.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+/// Free subheap bytes, headers included, counted in two independent ways.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct FreeListBytes {
+    /// The sum over the blocks found on the free lists.
+    pub free_bytes: usize,
+    /// The carved heap minus the live blocks.
+    pub expected_free_bytes: usize,
+}
+
+#[allow(unused)]
+impl FreeListBytes {
+    pub fn is_balanced(&self) -> bool {
+        self.free_bytes == self.expected_free_bytes
+    }
+}
+
+/// What `Allocator::inspect` found out about a block.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct BlockInfo {
+    /// The size class, or `None` for an external allocation.
+    pub class: Option<usize>,
+    /// The bytes usable through the data pointer.
+    pub block_size: usize,
+    pub is_external: bool,
+    /// Whether the block is currently waiting on its class's free list.
+    pub on_free_list: bool,
+}
+
+type ErrorHook = Box<dyn FnMut(&AllocError) -> ErrorAction + Send>;
+type AllocHook = Box<dyn FnMut(NonNull<u8>, usize, u64) + Send>;
+type FreeHook = Box<dyn FnMut(NonNull<u8>) + Send>;
+
+/// What the hook of `Allocator::set_error_hook` asks for on a failure.
+#[allow(unused)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ErrorAction {
+    /// Aborts the process.
+    Abort,
+    /// Runs `release_to_os(ReleaseLevel::Aggressive)` and tries once more.
+    RetryAfterTrim,
+    /// Returns the error to the caller.
+    Propagate,
+}
+
+/// How hard `Allocator::release_to_os` looks for memory to release.
+#[allow(unused)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum ReleaseLevel {
+    /// Only the free blocks at the end of the carved heap.
+    Gentle,
+    /// Also every page that holds only free blocks, and the freed external
+    /// mappings kept for reuse.
+    Aggressive,
+}
+
+/// What a call to `Allocator::maintenance` got done within its budget.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct MaintenanceReport {
+    pub bytes_decommitted: usize,
+    pub lists_compacted: usize,
+    /// Whether every empty page was reclaimed and every list compacted.
+    pub finished: bool,
+}
+
+/// An operation logged by `Allocator::start_recording`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TraceOp {
+    Alloc { id: usize, len: usize },
+    Free { id: usize },
+}
+
+#[derive(Default)]
+struct Recording {
+    ops: Vec<TraceOp>,
+    // Ids of the recorded blocks still live, by data address.
+    ids: std::collections::BTreeMap<usize, usize>,
+    next_id: usize,
+}
+
+impl Recording {
+    fn record_alloc(&mut self, addr: usize, len: usize) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(addr, id);
+        self.ops.push(TraceOp::Alloc { id, len });
+    }
+
+    /// Blocks allocated before recording started are not logged.
+    fn record_free(&mut self, addr: usize) {
+        if let Some(id) = self.ids.remove(&addr) {
+            self.ops.push(TraceOp::Free { id });
+        }
+    }
+}
+
+/// The subheap state captured by `Allocator::snapshot`, in raw addresses.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AllocatorSnapshot {
+    pub heap_begin: usize,
+    /// The carved and committed ends of each bump region.
+    pub regions: Vec<(usize, usize)>,
+    /// The header address and class of every carved block.
+    pub blocks: Vec<(usize, usize)>,
+    /// The header addresses on each class's free list, head first.
+    pub free_lists: Vec<Vec<usize>>,
+    pub detached_ranges: Vec<(usize, usize)>,
+    pub deferred_frees: Vec<usize>,
+    pub live_subheap_bytes: usize,
+}
+
+/// A pointer together with the generation of its block at allocation.
+#[cfg(feature = "debug-headers")]
+#[derive(PartialEq, Eq, Debug)]
+pub struct TrackedPtr<T> {
+    ptr: NonNull<T>,
+    generation: u32,
+}
+
+#[cfg(feature = "debug-headers")]
+impl<T> Clone for TrackedPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+#[cfg(feature = "debug-headers")]
+impl<T> Copy for TrackedPtr<T> {}
+
+pub struct StackAlloc {
+    base: sys::AnyNonNull,
+    len: usize,
+    guard_len: usize,
+}
+
+#[allow(unused)]
+impl StackAlloc {
+    /// The highest address of the stack, where pushes start from.
+    pub fn top(&self) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked((self.base.as_ptr() as *mut u8).add(self.len)) }
+    }
+
+    /// The lowest usable address, just above the guard page.
+    pub fn bottom(&self) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked((self.base.as_ptr() as *mut u8).add(self.guard_len)) }
+    }
+
+    /// The usable size, excluding the guard page.
+    pub fn size(&self) -> usize {
+        self.len - self.guard_len
+    }
+}
+
+pub struct ExecAlloc {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+#[allow(unused)]
+impl ExecAlloc {
+    /// The start of the code, page aligned.
+    pub fn ptr(&self) -> NonNull<u8> {
+        self.ptr
+    }
+
+    /// The usable size, a whole number of pages.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+pub struct AllocWriter {
+    ptr: NonNull<u8>,
+    len: usize,
+    position: usize,
+}
+
+#[allow(unused)]
+impl AllocWriter {
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl std::io::Write for AllocWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = buf.len().min(self.len - self.position);
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr.as_ptr().add(self.position), written);
+        }
+        self.position += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct AllocReader {
+    ptr: NonNull<u8>,
+    len: usize,
+    position: usize,
+}
+
+#[allow(unused)]
+impl AllocReader {
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl std::io::Read for AllocReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = buf.len().min(self.len - self.position);
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.ptr.as_ptr().add(self.position), buf.as_mut_ptr(), read);
+        }
+        self.position += read;
+        Ok(read)
+    }
+}
+
+pub struct ScopedAllocator<'a> {
+    allocator: &'a mut Allocator,
+    allocated: Vec<NonNull<u8>>,
+}
+
+#[allow(unused)]
+impl ScopedAllocator<'_> {
+    pub unsafe fn alloc<T: Sized>(&mut self) -> Result<NonNull<T>, Box<dyn Error>> {
+        self.alloc_by_size(size_of::<T>())
+    }
+
+    pub unsafe fn alloc_by_size<T>(&mut self, len: usize) -> Result<NonNull<T>, Box<dyn Error>> {
+        let ptr: NonNull<T> = self.allocator.alloc_by_size(len)?;
+        self.allocated.push(ptr.cast());
+        Ok(ptr)
+    }
+
+    pub unsafe fn free<T>(&mut self, ptr: NonNull<T>) -> Result<(), Box<dyn Error>> {
+        let ptr: NonNull<u8> = ptr.cast();
+        match self.allocated.iter().position(|allocated| *allocated == ptr) {
+            None => Err("Pointer was not allocated in this scope.".into()),
+            Some(index) => {
+                self.allocated.swap_remove(index);
+                self.allocator.free(ptr)
+            }
+        }
+    }
+}
+
+impl Drop for ScopedAllocator<'_> {
+    fn drop(&mut self) {
+        for ptr in self.allocated.drain(..) {
+            // Nothing can be reported from here, so failures are dropped.
+            let _ = unsafe { self.allocator.free(ptr) };
+        }
+    }
+}
+
+/// An `Allocator` shared between threads behind a lock.
+#[allow(unused)]
+pub struct SyncAllocator {
+    allocator: std::sync::Mutex<Allocator>,
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub enum TryAllocError {
+    /// Another thread holds the lock.
+    WouldBlock,
+    /// The allocator could not serve the request.
+    Oom(Box<dyn Error>),
+}
+
+impl std::fmt::Display for TryAllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TryAllocError::WouldBlock => write!(f, "Allocator is locked by another thread."),
+            TryAllocError::Oom(error) => write!(f, "Failed to allocate: {}", error),
+        }
+    }
+}
+
+impl Error for TryAllocError {}
+
+#[allow(unused)]
+impl SyncAllocator {
+    pub fn new(allocator: Allocator) -> Self {
+        Self { allocator: std::sync::Mutex::new(allocator) }
+    }
+
+    pub unsafe fn alloc<T: Sized>(&self) -> Result<NonNull<T>, Box<dyn Error>> {
+        self.lock().alloc()
+    }
+
+    /// Allocates without waiting for the lock, for threads that must not
+    /// block, such as real-time ones. They can fall back to a local cache
+    /// on `TryAllocError::WouldBlock`.
+    pub unsafe fn try_alloc<T: Sized>(&self) -> Result<NonNull<T>, TryAllocError> {
+        let mut allocator = match self.allocator.try_lock() {
+            Ok(allocator) => allocator,
+            Err(std::sync::TryLockError::WouldBlock) => return Err(TryAllocError::WouldBlock),
+            Err(std::sync::TryLockError::Poisoned(_)) => panic!("allocator lock poisoned."),
+        };
+        allocator.alloc().map_err(TryAllocError::Oom)
+    }
+
+    pub unsafe fn free<T>(&self, ptr: NonNull<T>) -> Result<(), Box<dyn Error>> {
+        self.lock().free(ptr)
+    }
+
+    /// Frees every pointer in `ptrs` under a single acquisition of the lock.
+    pub unsafe fn free_many<T>(&self, ptrs: &[NonNull<T>]) -> Result<(), Box<dyn Error>> {
+        self.lock().free_many(ptrs)
+    }
+
+    /// A panic inside the allocator means its lists can no longer be
+    /// trusted, so a poisoned lock is not recovered.
+    fn lock(&self) -> std::sync::MutexGuard<'_, Allocator> {
+        self.allocator.lock().expect("allocator lock poisoned.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_aligned_reuses_only_aligned_free_blocks() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u8> = allocator.alloc_aligned(16, 256).unwrap();
+            assert!((ptr.as_ptr() as usize).is_multiple_of(256));
+            allocator.free(ptr).unwrap();
+            assert_eq!(allocator.alloc_aligned::<u8>(16, 256).unwrap(), ptr);
+        }
+    }
+
+    #[cfg(feature = "harden")]
+    #[test]
+    fn alloc_aligned_blocks_can_be_freed_again_after_reuse() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u8> = allocator.alloc_aligned(16, 16).unwrap();
+            allocator.free(ptr).unwrap();
+            let reused: NonNull<u8> = allocator.alloc_aligned(16, 16).unwrap();
+            assert_eq!(reused, ptr);
+            allocator.free(reused).unwrap();
+        }
+    }
+
+    #[test]
+    fn free_many_rejects_a_pointer_listed_twice() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u64> = allocator.alloc().unwrap();
+            let error = allocator.free_many(&[ptr, ptr]).unwrap_err();
+            assert!(matches!(error.downcast_ref::<AllocError>(), Some(AllocError::DoubleFree)));
+            assert!(allocator.validate_free_list_bytes().unwrap().is_balanced());
+            allocator.free_many(&[ptr]).unwrap();
+            assert_eq!(allocator.used_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn blocks_freed_twice_fail_with_double_free() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            for len in [8, 500] {
+                let ptr: NonNull<u8> = allocator.alloc_by_size(len).unwrap();
+                allocator.free(ptr).unwrap();
+                let error = allocator.free(ptr).unwrap_err();
+                assert!(matches!(error.downcast_ref::<AllocError>(), Some(AllocError::DoubleFree)), "{}", error);
+                let error = allocator.free_many(&[ptr]).unwrap_err();
+                assert!(matches!(error.downcast_ref::<AllocError>(), Some(AllocError::DoubleFree)), "{}", error);
+
+                // The block went onto its list once, so it is handed out once.
+                let reused: NonNull<u8> = allocator.alloc_by_size(len).unwrap();
+                let other: NonNull<u8> = allocator.alloc_by_size(len).unwrap();
+                assert_eq!(reused, ptr);
+                assert_ne!(other, ptr);
+                allocator.free(reused).unwrap();
+                allocator.free(other).unwrap();
+            }
+            assert_eq!(allocator.used_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn frees_of_a_shard_block_into_its_parent_are_foreign() {
+        unsafe {
+            let mut parent = Allocator::init().unwrap();
+            let mut shard = parent.fork_shard(1 << 20).unwrap();
+            let ptr: NonNull<u64> = shard.alloc().unwrap();
+            let error = parent.free(ptr).unwrap_err();
+            assert!(matches!(error.downcast_ref::<AllocError>(), Some(AllocError::ForeignPointer)), "{}", error);
+            shard.free(ptr).unwrap();
+        }
+    }
+
+    #[test]
+    fn sealed_external_mappings_are_not_reused() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_external_reuse(true);
+            let ptr: NonNull<u8> = allocator.alloc_by_size(2000).unwrap();
+            allocator.seal(ptr).unwrap();
+            allocator.free(ptr).unwrap();
+            let ptr: NonNull<u8> = allocator.alloc_by_size(2000).unwrap();
+            ptr.as_ptr().write_bytes(1, 2000);
+            allocator.free(ptr).unwrap();
+        }
+    }
+
+    /// A scratch file of `len` bytes of `byte`, removed again on drop.
+    struct TempFile {
+        path: std::path::PathBuf,
+        file: std::fs::File,
+    }
+
+    impl TempFile {
+        fn new(name: &str, len: usize, byte: u8) -> Self {
+            let path = std::env::temp_dir().join(format!("sample-alloc-{}-{}", name, std::process::id()));
+            std::fs::write(&path, vec![byte; len]).unwrap();
+            let file = std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+            TempFile { path, file }
+        }
+
+        fn fd(&self) -> RawFd {
+            std::os::fd::AsRawFd::as_raw_fd(&self.file)
+        }
+
+        fn contents(&self) -> Vec<u8> {
+            std::fs::read(&self.path).unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn zero_on_free_leaves_files_and_sealed_mappings_alone() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_zero_on_free(true);
+            let pagesize = allocator.page_size();
+
+            let file = TempFile::new("zero-on-free", 2 * pagesize, 7);
+            let mapped = allocator.alloc_file_backed(file.fd(), 0, pagesize).unwrap();
+            allocator.free(mapped).unwrap();
+            assert!(file.contents().iter().all(|&byte| byte == 7));
+
+            let sealed: NonNull<u8> = allocator.alloc_by_size(2000).unwrap();
+            allocator.seal(sealed).unwrap();
+            allocator.free(sealed).unwrap();
+
+            let exec = allocator.alloc_exec(16).unwrap();
+            allocator.make_executable(&exec).unwrap();
+            allocator.free(exec.ptr()).unwrap();
+
+            let block: NonNull<u8> = allocator.alloc_by_size(64).unwrap();
+            block.as_ptr().write_bytes(9, 64);
+            allocator.free(block).unwrap();
+            // The free list link keeps the first bytes.
+            let link_size = size_of::<FreeHeader>() - size_of::<Header>();
+            let data = std::slice::from_raw_parts(block.as_ptr().add(link_size), 64 - link_size);
+            assert!(data.iter().all(|&byte| byte == 0));
+        }
+    }
+
+    #[test]
+    fn committed_ranges_skip_reclaimed_pages() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptrs: Vec<NonNull<u8>> = (0..2000).map(|_| allocator.alloc_by_size(16).unwrap()).collect();
+            for &ptr in &ptrs[..ptrs.len() - 1] {
+                allocator.free(ptr).unwrap();
+            }
+            let reclaimed = allocator.reclaim_empty_subheap_pages().unwrap();
+            assert!(reclaimed > 0);
+
+            let ranges: Vec<(NonNull<u8>, usize)> = allocator.committed_ranges().collect();
+            let committed: usize = ranges.iter().map(|&(_, len)| len).sum();
+            assert_eq!(committed, allocator.committed_subheap_bytes() - reclaimed);
+            for (addr, len) in ranges {
+                let bytes = std::slice::from_raw_parts(addr.as_ptr(), len);
+                std::hint::black_box(bytes.iter().map(|&byte| byte as usize).sum::<usize>());
+            }
+        }
+    }
+
+    #[test]
+    fn page_aligned_allocations_map_only_the_requested_pages() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+
+            let file = TempFile::new("page-aligned", pagesize, 0);
+            let mapped = allocator.alloc_file_backed(file.fd(), 0, pagesize).unwrap();
+            assert_eq!(allocator.usable_size(mapped), pagesize);
+            mapped.as_ptr().write_bytes(3, pagesize);
+            allocator.free(mapped).unwrap();
+            assert!(file.contents().iter().all(|&byte| byte == 3));
+
+            let layout = Layout::from_size_align(pagesize, pagesize).unwrap();
+            let ptr = allocator.alloc_by_layout(layout).unwrap();
+            assert_eq!(allocator.usable_size(ptr), pagesize);
+            allocator.free(ptr).unwrap();
+
+            assert_eq!(allocator.alloc_exec(pagesize).unwrap().len(), pagesize);
+        }
+    }
+
+    #[test]
+    fn hugepage_blocks_are_recycled_by_free_many_and_not_shrunk() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let block = allocator.alloc_hugepage_block().unwrap();
+            allocator.shrink(block, 16).unwrap();
+            assert_eq!(allocator.usable_size(block), HUGEPAGE_SIZE);
+
+            allocator.free_many(&[block]).unwrap();
+            assert_eq!(allocator.external_count(), 0);
+            assert_eq!(allocator.alloc_hugepage_block().unwrap(), block);
+        }
+    }
+
+    /// A `PROT_NONE` reservation of `len` bytes that the tests own.
+    unsafe fn reserve_mapping(len: usize) -> NonNull<libc::c_void> {
+        sys::reserve(len, std::ptr::null_mut()).unwrap()
+    }
+
+    /// Whether writing to `addr` faults, tried in a forked child.
+    unsafe fn write_faults(addr: *mut u8) -> bool {
+        let pid = libc::fork();
+        assert!(pid >= 0);
+        if pid == 0 {
+            addr.write_volatile(1);
+            libc::_exit(0);
+        }
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+    }
+
+    #[test]
+    fn merge_from_takes_free_blocks_of_a_shared_mapping() {
+        unsafe {
+            let len = 64 * page_size();
+            let base = reserve_mapping(len);
+            {
+                let mut survivor = Allocator::from_existing_mapping(base, len, page_size()).unwrap();
+                let mut other = Allocator::from_existing_mapping(base, len, page_size()).unwrap();
+                let ptr: NonNull<u64> = other.alloc().unwrap();
+                let kept: NonNull<u64> = other.alloc().unwrap();
+                let external: NonNull<u8> = other.alloc_by_size(8000).unwrap();
+                other.free(ptr).unwrap();
+
+                survivor.merge_from(&mut other).unwrap();
+                assert_eq!(survivor.external_count(), 1);
+                assert_eq!(other.external_count(), 0);
+                assert!(survivor.validate_free_list_bytes().unwrap().is_balanced());
+                assert!(survivor.inspect(ptr).unwrap().on_free_list);
+                survivor.free(kept).unwrap();
+                survivor.free(external).unwrap();
+            }
+            sys::release(base, len).unwrap();
+        }
+    }
+
+    #[test]
+    fn merge_from_rejects_two_users_of_one_mapping() {
+        unsafe {
+            let len = 64 * page_size();
+            let base = reserve_mapping(len);
+            {
+                let mut survivor = Allocator::from_existing_mapping(base, len, page_size()).unwrap();
+                let mut other = Allocator::from_existing_mapping(base, len, page_size()).unwrap();
+                let _: NonNull<u64> = survivor.alloc().unwrap();
+                let _: NonNull<u64> = other.alloc().unwrap();
+                let external: NonNull<u8> = other.alloc_by_size(8000).unwrap();
+
+                assert!(survivor.merge_from(&mut other).is_err());
+                assert_eq!(survivor.external_count(), 0);
+                assert_eq!(other.external_count(), 1);
+                other.free(external).unwrap();
+            }
+            sys::release(base, len).unwrap();
+        }
+    }
+
+    #[test]
+    fn from_existing_mapping_rejects_bad_geometry() {
+        unsafe {
+            let pagesize = page_size();
+            let len = 4 * pagesize;
+            let base = reserve_mapping(len);
+            let rejected = |len: usize, pagesize: usize| {
+                let error = Allocator::from_existing_mapping(base, len, pagesize).unwrap_err();
+                matches!(error.downcast_ref::<AllocError>(), Some(AllocError::InvalidMapping(_)))
+            };
+            assert!(rejected(0, pagesize));
+            assert!(rejected(len - 1, pagesize));
+            assert!(rejected(len, pagesize / 2));
+            assert!(rejected(len, pagesize + 1));
+            let misaligned = NonNull::new_unchecked(base.as_ptr().add(8));
+            assert!(Allocator::from_existing_mapping(misaligned, len, pagesize).is_err());
+
+            let mut allocator = Allocator::from_existing_mapping(base, len, pagesize).unwrap();
+            let ptr: NonNull<u64> = allocator.alloc().unwrap();
+            ptr.as_ptr().write(1);
+            drop(allocator);
+            sys::release(base, len).unwrap();
+        }
+    }
+
+    #[test]
+    fn allocate_at_respects_the_soft_limit() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            allocator.set_soft_limit(4 * pagesize);
+            let placed = allocator.allocate_at(16 * pagesize, 3 * pagesize).unwrap();
+            placed.as_ptr().write_bytes(1, 3 * pagesize);
+            assert!(allocator.allocate_at(32 * pagesize, 2 * pagesize).is_err());
+            assert!(allocator.allocate_at(32 * pagesize, pagesize).is_ok());
+        }
+    }
+
+    #[test]
+    fn allocate_at_uses_a_buffer_in_place() {
+        unsafe {
+            let pagesize = page_size();
+            let mut buf = vec![MaybeUninit::<u8>::uninit(); 8 * pagesize];
+            let mut allocator = Allocator::from_buffer(&mut buf).unwrap();
+            let placed = allocator.allocate_at(pagesize, pagesize).unwrap();
+            placed.as_ptr().write_bytes(1, pagesize);
+            assert_eq!(allocator.committed_ranges().count(), 1);
+        }
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn allocate_at_takes_injected_faults() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            allocator.set_fail_next_commit();
+            assert!(allocator.allocate_at(16 * pagesize, pagesize).is_err());
+            allocator.set_oom_after(0);
+            assert!(allocator.allocate_at(16 * pagesize, pagesize).is_err());
+            assert!(allocator.allocate_at(16 * pagesize, pagesize).is_ok());
+        }
+    }
+
+    #[test]
+    fn error_hook_sees_failed_mmaps() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let hook_calls = calls.clone();
+            allocator.set_error_hook(move |error| {
+                assert!(matches!(error, AllocError::MapFailed(_)));
+                hook_calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                ErrorAction::RetryAfterTrim
+            });
+            // Far more than any address space can map at once.
+            let error = allocator.alloc_by_size::<u8>(1 << 62).unwrap_err();
+            assert!(matches!(error.downcast_ref::<AllocError>(), Some(AllocError::MapFailed(_))));
+            assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    fn profiler_hooks_see_every_kind_of_allocation_once() {
+        unsafe {
+            type Events = std::sync::Arc<std::sync::Mutex<Vec<(bool, usize)>>>;
+            let events: Events = Default::default();
+            let mut allocator = Allocator::init().unwrap();
+            let (on_alloc, on_free) = (events.clone(), events.clone());
+            allocator.set_profiler_hooks(
+                move |ptr, _, _| on_alloc.lock().unwrap().push((true, ptr.as_ptr() as usize)),
+                move |ptr| on_free.lock().unwrap().push((false, ptr.as_ptr() as usize)),
+            );
+
+            let pagesize = allocator.page_size();
+            let mut ptrs: Vec<NonNull<u8>> = vec![
+                allocator.alloc_by_size(16).unwrap(),
+                allocator.alloc_aligned(16, 256).unwrap(),
+                allocator.alloc_by_layout(Layout::from_size_align(64, pagesize).unwrap()).unwrap(),
+                allocator.alloc_exec(16).unwrap().ptr(),
+                allocator.alloc_hugepage_block().unwrap(),
+            ];
+            let external: NonNull<u8> = allocator.alloc_by_size(8000).unwrap();
+            ptrs.push(allocator.realloc(external, 64000).unwrap());
+            assert_eq!(events.lock().unwrap().iter().filter(|&&(is_alloc, _)| is_alloc).count(), 7);
+
+            #[cfg(debug_assertions)]
+            {
+                let foreign = [0usize; 4];
+                let foreign = NonNull::new_unchecked(foreign.as_ptr().add(1) as *mut u8);
+                assert!(allocator.free(foreign).is_err());
+            }
+            for &ptr in &ptrs {
+                allocator.free(ptr).unwrap();
+            }
+
+            // Every live pointer is freed once, and nothing else is.
+            let mut live = std::collections::BTreeSet::new();
+            for &(is_alloc, addr) in events.lock().unwrap().iter() {
+                if is_alloc {
+                    assert!(live.insert(addr));
+                } else {
+                    assert!(live.remove(&addr));
+                }
+            }
+            assert!(live.is_empty());
+        }
+    }
+
+    #[test]
+    fn aligned_size_rounds_up_to_the_alignment() {
+        assert_eq!(aligned_size(0, 8), 0);
+        assert_eq!(aligned_size(1, 8), 8);
+        assert_eq!(aligned_size(8, 8), 8);
+        assert_eq!(aligned_size(9, 8), 16);
+        assert_eq!(aligned_size(4097, 4096), 8192);
+        assert_eq!(aligned_size(MAX_HEAP_SIZE, 4096), MAX_HEAP_SIZE);
+    }
+
+    #[test]
+    fn external_allocations_return_the_data_past_the_header() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u8> = allocator.alloc_by_size(8000).unwrap();
+            assert!(allocator.inspect(ptr).unwrap().is_external);
+            let base = allocator.external_base(header_of(ptr)).as_ptr() as usize;
+            assert_eq!(ptr.as_ptr() as usize, base + size_of::<Header>());
+            // Writing the whole usable size must leave the header intact.
+            ptr.as_ptr().write_bytes(0xff, allocator.usable_size(ptr));
+            assert_eq!(allocator.usable_size(ptr), aligned_size(8000 + size_of::<Header>(), allocator.page_size()) - size_of::<Header>());
+            allocator.free(ptr).unwrap();
+            assert_eq!(allocator.external_count(), 0);
+        }
+    }
+
+    #[test]
+    fn classes_for_sizes_covers_each_cluster_tightly() {
+        assert_eq!(classes_for_sizes(&[10, 12, 100, 110], 2).unwrap(), [16, 112]);
+        assert_eq!(classes_for_sizes(&[10, 12, 100, 110], 1).unwrap(), [112]);
+        assert_eq!(classes_for_sizes(&[], 0).unwrap(), Vec::<usize>::new());
+        assert!(classes_for_sizes(&[10], 0).is_err());
+        assert!(classes_for_sizes(&[usize::MAX], 1).is_err());
+        assert!(classes_for_sizes(&[usize::MAX / 2, usize::MAX / 2, 8], 1).is_err());
+    }
+
+    #[test]
+    fn aligned_alloc_and_posix_memalign_honor_the_alignment() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr = allocator.aligned_alloc(64, 100).unwrap();
+            assert!((ptr.as_ptr() as usize).is_multiple_of(64));
+            allocator.free(ptr).unwrap();
+            assert!(allocator.aligned_alloc(48, 100).is_err());
+
+            let mut memptr = std::ptr::null_mut();
+            assert_eq!(allocator.posix_memalign(&mut memptr, 64, 100), 0);
+            assert!((memptr as usize).is_multiple_of(64));
+            allocator.free(NonNull::new(memptr).unwrap()).unwrap();
+
+            let mut untouched = std::ptr::null_mut();
+            assert_eq!(allocator.posix_memalign(&mut untouched, 2, 100), libc::EINVAL);
+            assert_eq!(allocator.posix_memalign(&mut untouched, 96, 100), libc::EINVAL);
+            assert_eq!(allocator.posix_memalign(&mut untouched, 2 * allocator.page_size(), 100), libc::EINVAL);
+            assert_eq!(allocator.posix_memalign(&mut untouched, 64, usize::MAX), libc::ENOMEM);
+            assert!(untouched.is_null());
+        }
+    }
+
+    #[test]
+    fn sync_allocator_try_alloc_does_not_wait_for_the_lock() {
+        unsafe {
+            let sync = SyncAllocator::new(Allocator::init().unwrap());
+            let guard = sync.lock();
+            std::thread::scope(|scope| {
+                let would_block = scope
+                    .spawn(|| matches!(sync.try_alloc::<u64>(), Err(TryAllocError::WouldBlock)))
+                    .join()
+                    .unwrap();
+                assert!(would_block);
+            });
+            drop(guard);
+
+            let ptrs: Vec<NonNull<u64>> = (0..4).map(|_| sync.try_alloc().unwrap()).collect();
+            sync.free_many(&ptrs).unwrap();
+            assert_eq!(sync.lock().used_bytes(), 0);
+        }
+    }
+
+    #[cfg(feature = "debug-headers")]
+    #[test]
+    fn tags_round_trip_and_corrupted_free_tags_are_flagged() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u64> = allocator.alloc_tagged(7).unwrap();
+            assert_eq!(allocator.tag_of(ptr), 7);
+            allocator.free(ptr).unwrap();
+            allocator.validate_free_list_bytes().unwrap();
+
+            header_of(ptr).as_mut().tag = 7;
+            assert!(allocator.validate_free_list_bytes().is_err());
+            header_of(ptr).as_mut().tag = FREED_TAG;
+
+            let reused: NonNull<u64> = allocator.alloc().unwrap();
+            assert_eq!(reused, ptr);
+            assert_eq!(allocator.tag_of(reused), 0);
+        }
+    }
+
+    #[test]
+    fn seeded_blocks_are_handed_out_in_address_order_under_every_policy() {
+        unsafe {
+            for policy in [FreePolicy::Lifo, FreePolicy::Fifo, FreePolicy::AddressOrdered] {
+                let mut allocator = Allocator::init().unwrap();
+                allocator.set_free_policy(0, policy);
+                let ptrs: Vec<NonNull<u8>> = (0..1000).map(|_| allocator.alloc_by_size(16).unwrap()).collect();
+                assert!(ptrs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", policy);
+                assert!(allocator.validate_free_list_bytes().unwrap().is_balanced());
+            }
+        }
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn small_allocations_share_commits() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let count = 1000;
+            for _ in 0..count {
+                let _: NonNull<u8> = allocator.alloc_by_size(16).unwrap();
+            }
+            let pages = (count * carved_size_of_subheap(0)).div_ceil(allocator.page_size());
+            assert!(allocator.syscall_counts().commit as usize <= pages.div_ceil(COALESCED_COMMIT_PAGES));
+        }
+    }
+
+    #[cfg(feature = "debug-headers")]
+    #[test]
+    fn tracked_pointers_go_stale_across_reuse_and_trims() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let tracked = allocator.alloc_tracked::<[u8; 16]>().unwrap();
+            assert!(allocator.check(tracked).is_some());
+            allocator.free(tracked.ptr).unwrap();
+            assert!(allocator.check(tracked).is_none());
+            let reused = allocator.alloc_tracked::<[u8; 16]>().unwrap();
+            assert_eq!(reused.ptr, tracked.ptr);
+            assert!(allocator.check(tracked).is_none());
+            assert!(allocator.check(reused).is_some());
+
+            // Trim everything, so the block is carved anew at the same address.
+            allocator.free(reused.ptr).unwrap();
+            allocator.release_to_os(ReleaseLevel::Gentle).unwrap();
+            let recarved = allocator.alloc_tracked::<[u8; 16]>().unwrap();
+            assert_eq!(recarved.ptr, tracked.ptr);
+            assert!(allocator.check(tracked).is_none());
+            assert!(allocator.check(reused).is_none());
+        }
+    }
+
+    #[test]
+    fn commit_granularity_rounds_the_first_commit_up() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            assert!(matches!(allocator.set_commit_granularity(0), Err(AllocError::InvalidGranularity)));
+            assert!(matches!(allocator.set_commit_granularity(1000), Err(AllocError::InvalidGranularity)));
+            assert_eq!(allocator.commit_granularity, allocator.page_size());
+
+            allocator.set_commit_granularity(1 << 20).unwrap();
+            let _: NonNull<u8> = allocator.alloc_by_size(16).unwrap();
+            assert_eq!(allocator.committed_subheap_bytes(), 1 << 20);
+            assert_eq!(allocator.release_to_os(ReleaseLevel::Gentle).unwrap(), (1 << 20) - allocator.page_size());
+        }
+    }
+
+    #[test]
+    fn verify_catches_overlapping_adopted_regions() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let small: NonNull<u8> = allocator.alloc_by_size(16).unwrap();
+            let large: NonNull<u8> = allocator.alloc_by_size(1 << 16).unwrap();
+            allocator.free(small).unwrap();
+            allocator.verify().unwrap();
+
+            let base = *allocator.external_regions.keys().next().unwrap();
+            allocator.external_regions.insert(base + allocator.page_size(), allocator.page_size());
+            assert!(allocator.validate_no_overlap().is_err());
+            assert!(allocator.verify().is_err());
+
+            allocator.external_regions.remove(&(base + allocator.page_size()));
+            allocator.verify().unwrap();
+            allocator.free(large).unwrap();
+        }
+    }
+
+    #[test]
+    fn array_sizes_reject_overflow_for_every_element_size() {
+        unsafe fn check<T>(allocator: &mut Allocator) {
+            let size = size_of::<T>();
+            let largest = usize::MAX / size;
+            let cases = [(0, Some(0)), (3, Some(3 * size)), (largest, Some(largest * size))]
+                .into_iter()
+                .chain(largest.checked_add(1).map(|count| (count, None)));
+            for (count, bytes) in cases {
+                assert_eq!(array_bytes::<T>(count).ok(), bytes, "count {} of size {}", count, size);
+            }
+            let Some(overflowing) = largest.checked_add(1) else {
+                return;
+            };
+
+            let too_large = |error: Box<dyn Error>| matches!(error.downcast_ref(), Some(AllocError::TooLarge));
+            assert!(too_large(allocator.alloc_array::<T>(overflowing).unwrap_err()));
+            assert!(too_large(allocator.calloc::<T>(overflowing).unwrap_err()));
+            assert!(too_large(allocator.alloc_slice::<T>(overflowing).unwrap_err()));
+
+            let ptr = allocator.calloc::<T>(3).unwrap();
+            assert!(std::slice::from_raw_parts(ptr.as_ptr() as *const u8, 3 * size).iter().all(|&b| b == 0));
+            assert!(too_large(allocator.realloc_array(ptr, overflowing).unwrap_err()));
+            let ptr = allocator.realloc_array(ptr, 100).unwrap();
+            assert!(allocator.usable_size(ptr) >= 100 * size);
+            allocator.free(ptr).unwrap();
+
+            let slice = allocator.alloc_slice::<T>(0).unwrap();
+            assert_eq!(slice.len(), 0);
+            allocator.free(slice.cast::<MaybeUninit<T>>()).unwrap();
+        }
+
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            check::<u8>(&mut allocator);
+            check::<u16>(&mut allocator);
+            check::<u64>(&mut allocator);
+            check::<[u8; 24]>(&mut allocator);
+            check::<u128>(&mut allocator);
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn the_smallest_blocks_hold_their_free_link() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptrs: Vec<NonNull<u8>> = (0..4).map(|_| allocator.alloc_by_size(1).unwrap()).collect();
+            for &ptr in &ptrs {
+                assert_eq!(allocator.usable_size(ptr), block_size_of_subheap(0));
+                assert!(allocator.usable_size(ptr) >= size_of::<*mut FreeHeader>());
+                std::ptr::write_bytes(ptr.as_ptr(), 0xff, allocator.usable_size(ptr));
+            }
+            for &ptr in &ptrs {
+                allocator.free(ptr).unwrap();
+            }
+            allocator.verify().unwrap();
+
+            let mut reused: Vec<NonNull<u8>> = (0..4).map(|_| allocator.alloc_by_size(1).unwrap()).collect();
+            reused.sort();
+            let mut ptrs = ptrs;
+            ptrs.sort();
+            assert_eq!(reused, ptrs);
+        }
+    }
+
+    #[test]
+    fn alloc_filled_sets_every_usable_byte() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_external_reuse(true);
+            for len in [1, 100, 4000, 1 << 16] {
+                let ptr: NonNull<u8> = allocator.alloc_filled(len, 0xab).unwrap();
+                let usable_size = allocator.usable_size(ptr);
+                assert!(usable_size >= len);
+                assert!(std::slice::from_raw_parts(ptr.as_ptr(), usable_size).iter().all(|&b| b == 0xab));
+                allocator.free(ptr).unwrap();
+
+                // Reused blocks and cached mappings are filled again, with zeroes too.
+                let ptr: NonNull<u8> = allocator.alloc_filled(len, 0).unwrap();
+                assert!(std::slice::from_raw_parts(ptr.as_ptr(), allocator.usable_size(ptr)).iter().all(|&b| b == 0));
+                allocator.free(ptr).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn from_buffer_serves_a_stack_buffer_without_syscalls() {
+        unsafe {
+            let mut buf = [MaybeUninit::<u8>::uninit(); 64 * 1024];
+            let buf_range = buf.as_ptr_range();
+            let mut allocator = Allocator::from_buffer(&mut buf).unwrap();
+            assert_eq!(allocator.page_size(), BUFFER_PAGE_SIZE);
+
+            // Larger sizes would be mapped, unless the classes cover them.
+            allocator.set_external_threshold(8 * 1024);
+            let mut ptrs = Vec::new();
+            for len in [8, 16, 100, 500, 2000, 8 * 1024] {
+                let ptr: NonNull<u8> = allocator.alloc_by_size(len).unwrap();
+                let ptr_range = ptr.as_ptr() as *const MaybeUninit<u8>..ptr.as_ptr().add(len) as *const MaybeUninit<u8>;
+                assert!(buf_range.start <= ptr_range.start && ptr_range.end <= buf_range.end);
+                std::ptr::write_bytes(ptr.as_ptr(), 0x5a, len);
+                ptrs.push(ptr);
+            }
+            for ptr in ptrs {
+                allocator.free(ptr).unwrap();
+            }
+            let ptr: NonNull<u8> = allocator.alloc_by_size(100).unwrap();
+            allocator.free(ptr).unwrap();
+            allocator.verify().unwrap();
+
+            #[cfg(feature = "profile")]
+            assert_eq!(allocator.syscall_counts(), SyscallCounts::default());
+        }
+    }
+
+    #[test]
+    fn external_allocations_grow_into_their_page_slack() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let len = allocator.max_block_size() + 100;
+            let ptr: NonNull<u8> = allocator.alloc_by_size(len).unwrap();
+            let usable_size = allocator.usable_size(ptr);
+            assert_eq!(usable_size, allocator.page_size() - size_of::<Header>());
+            ptr.as_ptr().write_bytes(0x3c, len);
+
+            let grown = allocator.realloc(ptr, usable_size).unwrap();
+            assert_eq!(grown, ptr);
+            assert_eq!(allocator.external_count(), 1);
+            assert!(std::slice::from_raw_parts(grown.as_ptr(), len).iter().all(|&b| b == 0x3c));
+            allocator.free(grown).unwrap();
+        }
+    }
+
+    #[test]
+    fn scoped_allocations_are_freed_when_the_scope_panics() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let kept: NonNull<u64> = allocator.alloc().unwrap();
+            let used_bytes = allocator.used_bytes();
+
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                allocator.scoped(|scope| {
+                    let freed: NonNull<u8> = scope.alloc_by_size(100).unwrap();
+                    let _: NonNull<u8> = scope.alloc_by_size(40).unwrap();
+                    let _: NonNull<u8> = scope.alloc_by_size(8000).unwrap();
+                    scope.free(freed).unwrap();
+                    assert!(scope.free(freed).is_err());
+                    panic!("unwinding out of the scope");
+                })
+            }));
+            assert!(result.is_err());
+            assert_eq!(allocator.used_bytes(), used_bytes);
+            assert_eq!(allocator.external_count(), 0);
+
+            let value = allocator.scoped(|scope| scope.alloc::<u32>().map(|_| 7).unwrap());
+            assert_eq!(value, 7);
+            assert_eq!(allocator.used_bytes(), used_bytes);
+            allocator.free(kept).unwrap();
+        }
+    }
+
+    #[test]
+    fn eagerly_committed_blocks_are_writable_throughout() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_eager_commit(true);
+            allocator.set_commit_granularity(64 * allocator.page_size()).unwrap();
+            let first: NonNull<u8> = allocator.alloc_by_size(16).unwrap();
+
+            // The pages are backed before anything is written to them.
+            let (begin, len) = allocator.committed_ranges().next().unwrap();
+            let mut resident = vec![0u8; len / allocator.page_size()];
+            assert_eq!(libc::mincore(begin.as_ptr() as *mut libc::c_void, len, resident.as_mut_ptr()), 0);
+            assert!(resident.len() > 1 && resident.iter().all(|&page| page & 1 == 1));
+
+            let mut ptrs = vec![first];
+            for index in 0..2000 {
+                let len = 16 << (index % 7);
+                let ptr: NonNull<u8> = allocator.alloc_by_size(len).unwrap();
+                ptr.as_ptr().write_bytes(0xe7, allocator.usable_size(ptr));
+                ptrs.push(ptr);
+            }
+            assert!(allocator.committed_subheap_bytes() >= 2000 * 16);
+            for ptr in ptrs {
+                allocator.free(ptr).unwrap();
+            }
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn class_for_size_maps_sizes_to_the_doubling_classes() {
+        let expected = [(0, Some(0)), (1, Some(0)), (16, Some(0)), (17, Some(1)), (32, Some(1)), (33, Some(2)),
+            (100, Some(3)), (128, Some(3)), (129, Some(4)), (512, Some(5)), (1024, Some(6)), (1025, None), (usize::MAX, None)];
+        for (len, class) in expected {
+            assert_eq!(class_for_size(len), class, "size {}", len);
+            if let Some(class) = class {
+                assert!(len <= block_size_for_class(class));
+                assert!(class == 0 || len > block_size_for_class(class - 1));
+            }
+        }
+        assert_eq!(block_size_for_class(0), 16);
+        assert_eq!(block_size_for_class(SUBHEAP_COUNT - 1), 64 * 1024);
+        assert_eq!(block_size_for_class(DEFAULT_SUBHEAP_COUNT - 1), MAX_BLOCK_SIZE);
+
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            for len in 1..=MAX_BLOCK_SIZE {
+                let ptr: NonNull<u8> = allocator.alloc_by_size(len).unwrap();
+                assert_eq!(allocator.inspect(ptr).unwrap().class, class_for_size(len));
+                allocator.free(ptr).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn free_many_returns_every_block_to_its_free_list() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let used_bytes = allocator.used_bytes();
+            let ptrs: Vec<NonNull<u8>> = (0..1000)
+                .map(|index| allocator.alloc_by_size(if index % 100 == 0 { 5000 } else { 8 << (index % 7) }).unwrap())
+                .collect();
+            allocator.free_many(&ptrs).unwrap();
+
+            assert_eq!(allocator.used_bytes(), used_bytes);
+            assert_eq!(allocator.external_count(), 0);
+            for &ptr in ptrs.iter().filter(|&&ptr| allocator.inspect(ptr).is_some_and(|info| info.class.is_some())) {
+                assert!(allocator.inspect(ptr).unwrap().on_free_list);
+            }
+            assert!(allocator.validate_free_list_bytes().unwrap().is_balanced());
+        }
+    }
+
+    #[cfg(feature = "harden")]
+    #[test]
+    #[should_panic(expected = "free list corruption detected.")]
+    fn overwritten_free_links_are_detected_on_pop() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let first: NonNull<usize> = allocator.alloc().unwrap();
+            let second: NonNull<usize> = allocator.alloc().unwrap();
+            allocator.free(first).unwrap();
+            allocator.free(second).unwrap();
+
+            // The link is the first word of the freed data.
+            second.as_ptr().write(first.as_ptr() as usize + 64);
+            let _: NonNull<usize> = allocator.alloc().unwrap();
+        }
+    }
+
+    #[test]
+    fn writer_and_reader_stream_through_a_block() {
+        use std::io::{Read, Write};
+
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u8> = allocator.alloc_by_size(100).unwrap();
+            let stream: Vec<u8> = (0..200).map(|b| b as u8).collect();
+
+            let mut writer = allocator.writer(ptr, 1000);
+            writer.write_all(&stream[..50]).unwrap();
+            assert_eq!(writer.write(&stream[50..]).unwrap(), 128 - 50);
+            assert_eq!(writer.position(), 128);
+            assert!(writer.write_all(&stream[..1]).is_err());
+
+            let mut reader = allocator.reader(ptr, 1000);
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).unwrap();
+            assert_eq!(read, &stream[..128]);
+            assert_eq!(reader.position(), 128);
+            allocator.free(ptr).unwrap();
+        }
+    }
+
+    #[test]
+    fn base_hints_are_honored_or_fall_back() {
+        unsafe {
+            let allocator = Allocator::init().unwrap();
+            assert!(!allocator.base_hint_honored());
+            let (taken, _) = allocator.reserved_range();
+
+            // The range is in use, so the kernel has to place the heap elsewhere.
+            let mut elsewhere = Allocator::with_base_hint(taken.as_ptr()).unwrap();
+            assert!(!elsewhere.base_hint_honored());
+            assert_ne!(elsewhere.reserved_range().0, taken);
+            let ptr: NonNull<u64> = elsewhere.alloc().unwrap();
+            elsewhere.free(ptr).unwrap();
+            drop(elsewhere);
+            drop(allocator);
+
+            // Now freed, though another test may have mapped it since.
+            let mut hinted = Allocator::with_base_hint(taken.as_ptr()).unwrap();
+            assert_eq!(hinted.base_hint_honored(), hinted.reserved_range().0 == taken);
+            let ptr: NonNull<u64> = hinted.alloc().unwrap();
+            hinted.free(ptr).unwrap();
+        }
+    }
+
+    #[test]
+    fn compacted_free_lists_hand_out_ascending_addresses() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptrs: Vec<NonNull<u8>> = (0..64).map(|_| allocator.alloc_by_size(48).unwrap()).collect();
+            for index in (0..64).map(|index| index * 37 % 64) {
+                allocator.free(ptrs[index]).unwrap();
+            }
+            allocator.compact_free_lists();
+            allocator.verify().unwrap();
+
+            let reused: Vec<NonNull<u8>> = (0..64).map(|_| allocator.alloc_by_size(48).unwrap()).collect();
+            assert!(reused.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(reused[0], *ptrs.iter().min().unwrap());
+        }
+    }
+
+    #[test]
+    fn free_ignores_the_pointee_type() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let bytes: NonNull<[u8; 100]> = allocator.alloc().unwrap();
+            allocator.free(bytes.cast::<u32>()).unwrap();
+            assert!(allocator.inspect(bytes).unwrap().on_free_list);
+
+            let words: NonNull<[u32; 25]> = allocator.alloc().unwrap();
+            assert_eq!(words.cast::<u8>(), bytes.cast::<u8>());
+            allocator.free(words.cast::<u8>()).unwrap();
+            assert_eq!(allocator.used_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn reserved_blocks_are_allocated_without_commits() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.reserve_for(4, 500).unwrap();
+            let committed = allocator.committed_subheap_bytes();
+            assert!(committed >= 500 * carved_size_of_subheap(4));
+            #[cfg(feature = "profile")]
+            let commits = allocator.syscall_counts().commit;
+
+            let ptrs: Vec<NonNull<u8>> = (0..500).map(|_| allocator.alloc_by_size(block_size_of_subheap(4)).unwrap()).collect();
+            assert_eq!(allocator.committed_subheap_bytes(), committed);
+            #[cfg(feature = "profile")]
+            assert_eq!(allocator.syscall_counts().commit, commits);
+            allocator.free_many(&ptrs).unwrap();
+
+            assert!(allocator.reserve_for(4, usize::MAX).is_err());
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn shrinking_an_external_allocation_unmaps_its_tail() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            let ptr: NonNull<u8> = allocator.alloc_by_size(10 * pagesize - size_of::<Header>()).unwrap();
+            let base = allocator.external_base(header_of(ptr)).as_ptr();
+            assert_eq!(allocator.external_bytes(), 10 * pagesize);
+
+            allocator.shrink(ptr, 3 * pagesize - 100).unwrap();
+            // mincore fails with ENOMEM over pages that are not mapped.
+            let mut resident = [0u8; 10];
+            assert_eq!(libc::mincore(base, 3 * pagesize, resident.as_mut_ptr()), 0);
+            for page in 3..10 {
+                assert_eq!(libc::mincore(base.add(page * pagesize), pagesize, resident.as_mut_ptr()), -1);
+                assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(libc::ENOMEM));
+            }
+            assert_eq!(allocator.external_bytes(), 3 * pagesize);
+            assert_eq!(allocator.usable_size(ptr), 3 * pagesize - size_of::<Header>());
+            ptr.as_ptr().write_bytes(0x11, allocator.usable_size(ptr));
+
+            // Shrinking within the last page keeps it.
+            allocator.shrink(ptr, 3 * pagesize - 200).unwrap();
+            assert_eq!(allocator.external_bytes(), 3 * pagesize);
+            allocator.free(ptr).unwrap();
+        }
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn injected_failures_leave_the_allocator_consistent() {
+        let kind = |error: Box<dyn Error>| match error.downcast_ref() {
+            Some(AllocError::CommitFailed(_)) => "commit",
+            Some(AllocError::OutOfReservedSpace) => "oom",
+            _ => "other",
+        };
+
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_fail_next_commit();
+            assert_eq!(kind(allocator.alloc_by_size::<u8>(64).unwrap_err()), "commit");
+            assert_eq!(allocator.used_bytes(), 0);
+            assert_eq!(allocator.committed_subheap_bytes(), 0);
+            allocator.verify().unwrap();
+
+            // A commit failing once the heap is in use keeps the live blocks.
+            let mut ptrs: Vec<NonNull<u8>> = vec![allocator.alloc_by_size(64).unwrap()];
+            allocator.set_fail_next_commit();
+            let error = loop {
+                let used_bytes = allocator.used_bytes();
+                match allocator.alloc_by_size(64) {
+                    Ok(ptr) => ptrs.push(ptr),
+                    Err(error) => {
+                        assert_eq!(allocator.used_bytes(), used_bytes);
+                        break error;
+                    }
+                }
+            };
+            assert_eq!(kind(error), "commit");
+            allocator.verify().unwrap();
+            ptrs.push(allocator.alloc_by_size(64).unwrap());
+
+            allocator.set_oom_after(3);
+            for _ in 0..3 {
+                ptrs.push(allocator.alloc_by_size(64).unwrap());
+            }
+            let used_bytes = allocator.used_bytes();
+            assert_eq!(kind(allocator.alloc_by_size::<u8>(64).unwrap_err()), "oom");
+            assert_eq!(allocator.used_bytes(), used_bytes);
+            allocator.verify().unwrap();
+            ptrs.push(allocator.alloc_by_size(64).unwrap());
+            allocator.free_many(&ptrs).unwrap();
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn clone_config_copies_settings_onto_a_new_reservation() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_external_threshold(4096);
+            allocator.set_commit_granularity(4 * allocator.page_size()).unwrap();
+            allocator.set_free_policy(2, FreePolicy::AddressOrdered);
+            allocator.set_zero_on_free(true);
+            allocator.set_external_reuse(true);
+            allocator.set_soft_limit(1 << 30);
+            let ptr: NonNull<u8> = allocator.alloc_by_size(24).unwrap();
+
+            let clone = allocator.clone_config().unwrap();
+            assert_eq!(clone.subheap_count(), allocator.subheap_count());
+            assert_eq!(clone.commit_granularity, allocator.commit_granularity);
+            assert_eq!(clone.free_policies, allocator.free_policies);
+            assert_eq!(clone.zero_on_free, allocator.zero_on_free);
+            assert_eq!(clone.external_reuse, allocator.external_reuse);
+            assert_eq!(clone.soft_limit, allocator.soft_limit);
+            assert_ne!(clone.reserved_range().0, allocator.reserved_range().0);
+            assert_eq!(clone.used_bytes(), 0);
+            assert_eq!(clone.committed_subheap_bytes(), 0);
+            allocator.free(ptr).unwrap();
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn free_detects_a_corrupted_header_class() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u8> = allocator.alloc_by_size(100).unwrap();
+            let mut header = header_of(ptr);
+            assert_eq!(header.as_ref().size_or_class_of_subheap, 3);
+
+            header.as_mut().size_or_class_of_subheap = 2;
+            let error = allocator.free(ptr).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(AllocError::ClassMismatch)));
+            let error = allocator.free_many(&[ptr]).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(AllocError::ClassMismatch)));
+
+            header.as_mut().size_or_class_of_subheap = 3;
+            allocator.free(ptr).unwrap();
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn stacks_fault_in_their_guard_page() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let stack = allocator.alloc_stack(6 * allocator.page_size() + 1).unwrap();
+            assert!(stack.size() > 6 * allocator.page_size());
+            assert_eq!(stack.top().as_ptr() as usize - stack.bottom().as_ptr() as usize, stack.size());
+            assert_eq!(stack.top().as_ptr().align_offset(16), 0);
+
+            stack.top().as_ptr().sub(1).write(0x42);
+            stack.bottom().as_ptr().write(0x42);
+            assert!(!write_faults(stack.top().as_ptr().sub(8)));
+            assert!(!write_faults(stack.bottom().as_ptr()));
+            assert!(write_faults(stack.bottom().as_ptr().sub(1)));
+            allocator.free_stack(stack).unwrap();
+
+            let Err(error) = allocator.alloc_stack(usize::MAX - allocator.page_size()) else {
+                panic!("a stack past the address space was mapped");
+            };
+            assert!(matches!(error.downcast_ref(), Some(AllocError::TooLarge)));
+        }
+    }
+
+    #[test]
+    fn handles_go_stale_once_freed() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let handle = allocator.alloc_handle(100).unwrap();
+            let ptr = allocator.resolve(handle).unwrap();
+            assert_eq!(allocator.usable_size(ptr), 128);
+
+            allocator.free_handle(handle).unwrap();
+            assert_eq!(allocator.resolve(handle), None);
+            assert!(allocator.free_handle(handle).is_err());
+
+            let reused = allocator.alloc_handle(100).unwrap();
+            assert_eq!(reused.index(), handle.index());
+            assert_eq!(reused.generation(), handle.generation() + 1);
+            assert_ne!(reused, handle);
+            assert_eq!(allocator.resolve(handle), None);
+            assert_eq!(allocator.resolve(reused), Some(ptr));
+            assert_eq!(allocator.resolve(Handle(u64::MAX)), None);
+            allocator.free_handle(reused).unwrap();
+            assert_eq!(allocator.used_bytes(), 0);
+
+            // A failed free leaves the handle live.
+            let external = allocator.alloc_handle(5000).unwrap();
+            let ptr = allocator.resolve(external).unwrap();
+            let size = header_of(ptr).as_ref().size_or_class_of_subheap;
+            header_of(ptr).as_mut().size_or_class_of_subheap = usize::MAX & !(allocator.page_size() - 1);
+            assert!(allocator.free_handle(external).is_err());
+            assert_eq!(allocator.resolve(external), Some(ptr));
+            header_of(ptr).as_mut().size_or_class_of_subheap = size;
+            allocator.free_handle(external).unwrap();
+            assert_eq!(allocator.resolve(external), None);
+        }
+    }
+
+    #[test]
+    fn external_totals_follow_allocations_and_frees() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            let small: NonNull<u8> = allocator.alloc_by_size(100).unwrap();
+            let lens = [2000, pagesize, 5 * pagesize, 100 * pagesize + 1];
+            let ptrs: Vec<NonNull<u8>> = lens.iter().map(|&len| allocator.alloc_by_size(len).unwrap()).collect();
+            let expected: usize = lens.iter().map(|&len| aligned_size(len + size_of::<Header>(), pagesize)).sum();
+            assert_eq!(allocator.external_count(), lens.len());
+            assert_eq!(allocator.external_bytes(), expected);
+
+            allocator.free(ptrs[0]).unwrap();
+            assert_eq!(allocator.external_count(), lens.len() - 1);
+            assert_eq!(allocator.external_bytes(), expected - pagesize);
+            for &ptr in &ptrs[1..] {
+                allocator.free(ptr).unwrap();
+            }
+            assert_eq!(allocator.external_count(), 0);
+            assert_eq!(allocator.external_bytes(), 0);
+            allocator.free(small).unwrap();
+        }
+    }
+
+    #[test]
+    fn sizes_near_the_overflow_boundary_are_too_large() {
+        let too_large = |result: Result<NonNull<u8>, Box<dyn Error>>| {
+            matches!(result.unwrap_err().downcast_ref(), Some(AllocError::TooLarge))
+        };
+
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            // The largest length whose mapping size still fits an `isize`.
+            let largest = isize::MAX as usize - size_of::<Header>() - (pagesize - 1);
+            for len in [usize::MAX, usize::MAX - size_of::<Header>(), usize::MAX - pagesize, isize::MAX as usize, largest + 1] {
+                assert!(too_large(allocator.alloc_by_size(len)), "len {:#x}", len);
+                assert!(too_large(allocator.alloc_filled(len, 0)), "len {:#x}", len);
+            }
+            // Still a valid size, which the kernel then cannot map.
+            assert!(!too_large(allocator.alloc_by_size(largest)));
+
+            let ptr: NonNull<u8> = allocator.alloc_by_size(10).unwrap();
+            assert!(too_large(allocator.realloc(ptr, usize::MAX)));
+            allocator.free(ptr).unwrap();
+            assert_eq!(allocator.external_count(), 0);
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn committed_ranges_yield_the_heap_then_each_external_region() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            assert_eq!(allocator.committed_ranges().count(), 0);
+
+            let small: Vec<NonNull<u8>> = (0..100).map(|_| allocator.alloc_by_size(200).unwrap()).collect();
+            let large: Vec<NonNull<u8>> = [3000, 20000].iter().map(|&len| allocator.alloc_by_size(len).unwrap()).collect();
+            let ranges: Vec<(NonNull<u8>, usize)> = allocator.committed_ranges().collect();
+            assert_eq!(ranges.len(), 3);
+
+            let (heap_begin, _) = allocator.reserved_range();
+            assert_eq!(ranges[0], (heap_begin.cast(), allocator.committed_subheap_bytes()));
+            for &ptr in &small {
+                let addr = ptr.as_ptr() as usize;
+                assert!(addr >= heap_begin.as_ptr() as usize && addr + 200 <= heap_begin.as_ptr() as usize + ranges[0].1);
+            }
+            let mut externals: Vec<(NonNull<u8>, usize)> = large
+                .iter()
+                .map(|&ptr| (allocator.external_base(header_of(ptr)).cast(), aligned_size(allocator.usable_size(ptr) + size_of::<Header>(), allocator.page_size())))
+                .collect();
+            externals.sort();
+            let mut found = ranges[1..].to_vec();
+            found.sort();
+            assert_eq!(found, externals);
+
+            allocator.free_many(&large).unwrap();
+            assert_eq!(allocator.committed_ranges().count(), 1);
+            allocator.free_many(&small).unwrap();
+        }
+    }
+
+    #[test]
+    fn deferred_frees_wait_for_the_flush() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptrs: Vec<NonNull<u8>> = (0..DEFERRED_FREE_CAPACITY + 11).map(|_| allocator.alloc_by_size(32).unwrap()).collect();
+            let on_free_list = |allocator: &Allocator, ptrs: &[NonNull<u8>]| {
+                ptrs.iter().map(|&ptr| allocator.inspect(ptr).unwrap().on_free_list).collect::<Vec<_>>()
+            };
+
+            for &ptr in &ptrs[..10] {
+                allocator.defer_free(ptr).unwrap();
+            }
+            assert!(on_free_list(&allocator, &ptrs[..10]).iter().all(|&on| !on));
+            allocator.flush_deferred().unwrap();
+            assert!(on_free_list(&allocator, &ptrs[..10]).iter().all(|&on| on));
+
+            // Queuing into a full queue flushes it first.
+            let (queued, last) = ptrs[10..].split_at(DEFERRED_FREE_CAPACITY);
+            for &ptr in queued {
+                allocator.defer_free(ptr).unwrap();
+            }
+            assert!(on_free_list(&allocator, queued).iter().all(|&on| !on));
+            allocator.defer_free(last[0]).unwrap();
+            assert!(on_free_list(&allocator, queued).iter().all(|&on| on));
+            assert!(!allocator.inspect(last[0]).unwrap().on_free_list);
+
+            allocator.flush_deferred().unwrap();
+            assert!(allocator.inspect(last[0]).unwrap().on_free_list);
+            assert_eq!(allocator.used_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn sealed_allocations_fault_on_write_until_unsealed() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let len = 3 * allocator.page_size();
+            let ptr: NonNull<u8> = allocator.alloc_filled(len, 0x77).unwrap();
+            allocator.seal(ptr).unwrap();
+            assert_eq!(ptr.as_ptr().add(len - 1).read_volatile(), 0x77);
+            assert!(write_faults(ptr.as_ptr()));
+            assert!(write_faults(ptr.as_ptr().add(len - 1)));
+
+            allocator.unseal(ptr).unwrap();
+            assert!(!write_faults(ptr.as_ptr()));
+            ptr.as_ptr().write(0x78);
+            allocator.free(ptr).unwrap();
+
+            let small: NonNull<u8> = allocator.alloc_by_size(100).unwrap();
+            assert!(allocator.seal(small).is_err());
+            assert!(!write_faults(small.as_ptr()));
+            allocator.free(small).unwrap();
+        }
+    }
+
+    #[test]
+    fn adopted_mappings_serve_allocations_and_outlive_the_allocator() {
+        unsafe {
+            let pagesize = page_size();
+            let len = 64 * pagesize;
+            let base = reserve_mapping(len);
+            let mut allocator = Allocator::from_existing_mapping(base, len, pagesize).unwrap();
+            assert_eq!(allocator.reserved_range(), (base, len));
+
+            let ptrs: Vec<NonNull<u8>> = (0..200).map(|index| allocator.alloc_by_size(16 << (index % 7)).unwrap()).collect();
+            for &ptr in &ptrs {
+                let addr = ptr.as_ptr() as usize;
+                assert!(addr > base.as_ptr() as usize && addr < base.as_ptr() as usize + len);
+                ptr.as_ptr().write_bytes(0x99, allocator.usable_size(ptr));
+            }
+            allocator.free_many(&ptrs).unwrap();
+            allocator.verify().unwrap();
+            drop(allocator);
+
+            // Still mapped for the caller to release.
+            let mut resident = vec![0u8; len / pagesize];
+            assert_eq!(libc::mincore(base.as_ptr(), len, resident.as_mut_ptr()), 0);
+            sys::release(base, len).unwrap();
+        }
+    }
+
+    #[test]
+    fn realloc_zeroed_clears_the_grown_tail() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u8> = allocator.calloc(40).unwrap();
+            ptr.as_ptr().write_bytes(0x01, 40);
+            let dirty: NonNull<u8> = allocator.alloc_filled(200, 0xff).unwrap();
+            allocator.free(dirty).unwrap();
+
+            let grown = allocator.realloc_zeroed(ptr, 40, 200).unwrap();
+            assert_eq!(grown, dirty);
+            let bytes = std::slice::from_raw_parts(grown.as_ptr(), 200);
+            assert!(bytes[..40].iter().all(|&b| b == 0x01));
+            assert!(bytes[40..].iter().all(|&b| b == 0));
+
+            // Growing in place zeroes the tail too.
+            grown.as_ptr().add(200).write_bytes(0xff, 56);
+            let same = allocator.realloc_zeroed(grown, 200, 256).unwrap();
+            assert_eq!(same, grown);
+            assert!(std::slice::from_raw_parts(same.as_ptr().add(200), 56).iter().all(|&b| b == 0));
+            allocator.free(same).unwrap();
+        }
+    }
+
+    #[test]
+    fn free_policies_pick_the_block_to_reuse() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            for (policy, class_of_subheap) in [(FreePolicy::Lifo, 1), (FreePolicy::Fifo, 2), (FreePolicy::AddressOrdered, 3)] {
+                allocator.set_free_policy(class_of_subheap, policy);
+                let len = block_size_of_subheap(class_of_subheap);
+                let ptrs: Vec<NonNull<u8>> = (0..4).map(|_| allocator.alloc_by_size(len).unwrap()).collect();
+                // Drain the seeded blocks, so only freed ones are left.
+                let mut drained = Vec::new();
+                while !allocator.free_lists[class_of_subheap].is_null() {
+                    drained.push(allocator.alloc_by_size::<u8>(len).unwrap());
+                }
+                for &index in &[2, 0, 3, 1] {
+                    allocator.free(ptrs[index]).unwrap();
+                }
+                let reused: NonNull<u8> = allocator.alloc_by_size(len).unwrap();
+                let expected = match policy {
+                    FreePolicy::Lifo => ptrs[1],
+                    FreePolicy::Fifo => ptrs[2],
+                    FreePolicy::AddressOrdered => ptrs[0],
+                };
+                assert_eq!(reused, expected, "{:?}", policy);
+                allocator.free(reused).unwrap();
+                allocator.free_many(&drained).unwrap();
+            }
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn merge_from_moves_only_externals_between_reservations() {
+        unsafe {
+            let mut survivor = Allocator::init().unwrap();
+            let mut other = Allocator::init().unwrap();
+            let freed: NonNull<u64> = other.alloc().unwrap();
+            let kept: NonNull<u64> = other.alloc().unwrap();
+            let external: NonNull<u8> = other.alloc_by_size(8000).unwrap();
+            other.free(freed).unwrap();
+
+            survivor.merge_from(&mut other).unwrap();
+            assert_eq!(survivor.external_count(), 1);
+            assert_eq!(other.external_count(), 0);
+            assert!(survivor.inspect(freed).is_none());
+            assert!(other.inspect(freed).unwrap().on_free_list);
+            survivor.verify().unwrap();
+            other.verify().unwrap();
+
+            survivor.free(external).unwrap();
+            other.free(kept).unwrap();
+        }
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn commit_latencies_are_recorded_as_the_heap_grows() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            assert_eq!(allocator.commit_latency_histogram().count(), 0);
+            let ptrs: Vec<NonNull<u8>> = (0..64).map(|_| allocator.alloc_by_size(1024).unwrap()).collect();
+            let commits = allocator.commit_latency_histogram().count();
+            assert!(commits > 1);
+            assert_eq!(allocator.commit_latency_histogram().buckets().iter().sum::<u64>(), commits);
+
+            allocator.free_many(&ptrs).unwrap();
+            allocator.release_to_os(ReleaseLevel::Aggressive).unwrap();
+            assert!(allocator.commit_latency_histogram().count() > commits);
+        }
+    }
+
+    #[test]
+    fn prefetching_a_link_never_faults() {
+        unsafe {
+            // An inaccessible address is only a prefetch hint, not a read.
+            let len = 4 * page_size();
+            let base = reserve_mapping(len);
+            prefetch_read(base.as_ptr());
+            prefetch_read(base.as_ptr().add(len - 1));
+            sys::release(base, len).unwrap();
+
+            // Draining a list pops a last block with no next head to prefetch.
+            let mut allocator = Allocator::init().unwrap();
+            let mut ptrs: Vec<NonNull<u8>> = vec![allocator.alloc_by_size(16).unwrap()];
+            while !allocator.free_lists[0].is_null() {
+                ptrs.push(allocator.alloc_by_size(16).unwrap());
+            }
+            ptrs.push(allocator.alloc_by_size(16).unwrap());
+            allocator.free_many(&ptrs).unwrap();
+            allocator.verify().unwrap();
+        }
+    }
+
+    /// Times allocating from a shuffled free list that is out of cache,
+    /// where each pop prefetches the next head, against chasing the same
+    /// links without prefetching. Run with `--release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn bench_alloc_loop_from_a_cold_free_list() {
+        const BLOCKS: usize = 1 << 16;
+
+        unsafe fn shuffled_free_list(allocator: &mut Allocator, ptrs: &[NonNull<u8>], seed: usize) {
+            let mut order: Vec<usize> = (0..ptrs.len()).collect();
+            let mut state = seed;
+            for index in (1..order.len()).rev() {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                order.swap(index, (state >> 33) % (index + 1));
+            }
+            for index in order {
+                allocator.free(ptrs[index]).unwrap();
+            }
+            // Evict the links from the caches.
+            let mut evict = vec![0u8; 64 << 20];
+            evict.iter_mut().for_each(|b| *b = b.wrapping_add(1));
+            std::hint::black_box(&evict);
+        }
+
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let len = block_size_of_subheap(6);
+            let ptrs: Vec<NonNull<u8>> = (0..BLOCKS).map(|_| allocator.alloc_by_size(len).unwrap()).collect();
+
+            shuffled_free_list(&mut allocator, &ptrs, 1);
+            let start = std::time::Instant::now();
+            let mut node = allocator.free_lists[6];
+            let mut chased = 0;
+            while let Some(free_ptr) = NonNull::new(node) {
+                node = std::hint::black_box(allocator.next_of(free_ptr));
+                chased += 1;
+            }
+            let chase = start.elapsed();
+            assert_eq!(chased, BLOCKS);
+            let drained: Vec<NonNull<u8>> = (0..BLOCKS).map(|_| allocator.alloc_by_size(len).unwrap()).collect();
+
+            shuffled_free_list(&mut allocator, &drained, 2);
+            let start = std::time::Instant::now();
+            for _ in 0..BLOCKS {
+                std::hint::black_box(allocator.alloc_by_size::<u8>(len).unwrap());
+            }
+            let pops = start.elapsed();
+
+            eprintln!(
+                "chasing links: {:.1} ns/block, allocating with prefetch: {:.1} ns/block",
+                chase.as_nanos() as f64 / BLOCKS as f64,
+                pops.as_nanos() as f64 / BLOCKS as f64,
+            );
+        }
+    }
+
+    #[test]
+    fn free_list_bytes_balance_and_cycles_are_reported() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let mut live: Vec<NonNull<u8>> = Vec::new();
+            for step in 0..3000usize {
+                if step % 3 == 2 {
+                    let ptr = live.swap_remove(step * 7 % live.len());
+                    allocator.free(ptr).unwrap();
+                } else {
+                    live.push(allocator.alloc_by_size(1 + step * 13 % 1024).unwrap());
+                }
+                if step % 500 == 0 {
+                    let bytes = allocator.validate_free_list_bytes().unwrap();
+                    assert!(bytes.is_balanced(), "{:?} at step {}", bytes, step);
+                }
+            }
+            allocator.free_many(&live).unwrap();
+            let bytes = allocator.validate_free_list_bytes().unwrap();
+            assert!(bytes.is_balanced());
+            assert_eq!(allocator.used_bytes(), 0);
+
+            // Link the head of a list back to itself.
+            let head = NonNull::new(allocator.free_lists[0]).unwrap();
+            allocator.set_next(head, head.as_ptr());
+            assert!(allocator.validate_free_list_bytes().is_err());
+            allocator.set_next(head, std::ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn file_backed_allocations_write_through_to_the_file() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            let file = TempFile::new("file-backed", 3 * pagesize, 0);
+            let mapped = allocator.alloc_file_backed(file.fd(), pagesize as i64, 2 * pagesize).unwrap();
+            assert_eq!(mapped.as_ptr().align_offset(pagesize), 0);
+            assert_eq!(allocator.external_count(), 1);
+
+            mapped.as_ptr().write_bytes(0x5e, 2 * pagesize);
+            allocator.sync(mapped, 2 * pagesize, false).unwrap();
+            let contents = file.contents();
+            assert!(contents[..pagesize].iter().all(|&byte| byte == 0));
+            assert!(contents[pagesize..].iter().all(|&byte| byte == 0x5e));
+
+            allocator.free(mapped).unwrap();
+            assert_eq!(allocator.external_count(), 0);
+            assert!(allocator.alloc_file_backed(file.fd(), 1, pagesize).is_err());
+        }
+    }
+
+    #[test]
+    fn sync_flushes_file_backed_writes_and_ignores_anonymous_memory() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            let file = TempFile::new("sync", 2 * pagesize, 1);
+            let mapped = allocator.alloc_file_backed(file.fd(), 0, 2 * pagesize).unwrap();
+
+            // A range starting mid-page syncs the whole pages under it.
+            let middle = NonNull::new_unchecked(mapped.as_ptr().add(pagesize + 100));
+            middle.as_ptr().write_bytes(2, 10);
+            allocator.sync(middle, 10, false).unwrap();
+            mapped.as_ptr().write(3);
+            allocator.sync(mapped, 1, true).unwrap();
+            let contents = file.contents();
+            assert_eq!(contents[0], 3);
+            assert!(contents[pagesize + 100..pagesize + 110].iter().all(|&byte| byte == 2));
+            assert_eq!(contents[pagesize + 110], 1);
+            allocator.free(mapped).unwrap();
+
+            let small: NonNull<u8> = allocator.alloc_by_size(100).unwrap();
+            allocator.sync(small, 100, false).unwrap();
+            let large: NonNull<u8> = allocator.alloc_by_size(3 * pagesize).unwrap();
+            allocator.sync(large, 3 * pagesize, false).unwrap();
+            allocator.free(small).unwrap();
+            allocator.free(large).unwrap();
+        }
+    }
+
+    #[test]
+    fn geometry_accessors_match_the_system_and_the_classes() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+            assert_eq!(allocator.page_size(), pagesize);
+            assert_eq!(page_size(), pagesize);
+            assert_eq!(allocator.subheap_count(), DEFAULT_SUBHEAP_COUNT);
+            assert_eq!(allocator.max_block_size(), block_size_for_class(allocator.subheap_count() - 1));
+
+            let largest: NonNull<u8> = allocator.alloc_by_size(allocator.max_block_size()).unwrap();
+            let external: NonNull<u8> = allocator.alloc_by_size(allocator.max_block_size() + 1).unwrap();
+            assert!(!allocator.inspect(largest).unwrap().is_external);
+            assert!(allocator.inspect(external).unwrap().is_external);
+            allocator.free(largest).unwrap();
+            allocator.free(external).unwrap();
+
+            allocator.set_external_threshold(8192);
+            assert_eq!(allocator.max_block_size(), 8192);
+            assert_eq!(allocator.subheap_count(), DEFAULT_SUBHEAP_COUNT + 3);
+        }
+    }
+
+    #[test]
+    fn soft_limit_fails_allocations_until_memory_is_freed() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_soft_limit(1 << 20);
+            let len = 100 * 1024;
+            let mut ptrs: Vec<NonNull<u8>> = Vec::new();
+            let error = loop {
+                match allocator.alloc_by_size(len) {
+                    Ok(ptr) => ptrs.push(ptr),
+                    Err(error) => break error,
+                }
+                assert!(ptrs.len() <= 10);
+            };
+            assert!(matches!(error.downcast_ref(), Some(AllocError::OutOfReservedSpace)));
+            assert_eq!(ptrs.len(), (1 << 20) / aligned_size(len + size_of::<Header>(), allocator.page_size()));
+            assert!(allocator.external_bytes() <= 1 << 20);
+
+            allocator.free(ptrs.pop().unwrap()).unwrap();
+            ptrs.push(allocator.alloc_by_size(len).unwrap());
+            let small: NonNull<u8> = allocator.alloc_by_size(16).unwrap();
+            allocator.free(small).unwrap();
+            allocator.free_many(&ptrs).unwrap();
+            assert_eq!(allocator.external_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn small_allocations_split_a_larger_free_block() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let large: NonNull<u8> = allocator.alloc_by_size(512).unwrap();
+            let mut small: Vec<NonNull<u8>> = vec![allocator.alloc_by_size(16).unwrap()];
+            while !allocator.free_lists[0].is_null() {
+                small.push(allocator.alloc_by_size(16).unwrap());
+            }
+            allocator.free(large).unwrap();
+            let active = allocator.bump_region(0).active;
+
+            let split: NonNull<u8> = allocator.alloc_by_size(16).unwrap();
+            assert_eq!(split, large);
+            assert_eq!(allocator.bump_region(0).active, active);
+            assert_eq!(allocator.inspect(split).unwrap().class, Some(0));
+            assert!(!allocator.free_lists[0].is_null());
+
+            // The rest of the block is handed out as 16 byte blocks.
+            let large_end = large.as_ptr() as usize + 512;
+            let next: NonNull<u8> = allocator.alloc_by_size(16).unwrap();
+            assert!(next.as_ptr() as usize > large.as_ptr() as usize && (next.as_ptr() as usize) < large_end);
+            allocator.free(next).unwrap();
+            allocator.free(split).unwrap();
+            allocator.free_many(&small).unwrap();
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn forked_children_do_not_touch_the_parents_free_lists() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let freed: NonNull<u8> = allocator.alloc_by_size(64).unwrap();
+            let live: NonNull<u8> = allocator.alloc_filled(64, 0x11).unwrap();
+            allocator.free(freed).unwrap();
+            let free_lists = allocator.free_lists;
+
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                allocator.on_fork_child();
+                let reused: NonNull<u8> = allocator.alloc_filled(64, 0xcc).unwrap();
+                live.as_ptr().write_bytes(0xcc, 64);
+                let more: Vec<NonNull<u8>> = (0..100).map(|_| allocator.alloc_filled(64, 0xcc).unwrap()).collect();
+                let ok = reused == freed && allocator.free_many(&more).is_ok() && allocator.free(reused).is_ok();
+                libc::_exit(if ok { 0 } else { 1 });
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+            assert_eq!(allocator.free_lists, free_lists);
+            assert!(std::slice::from_raw_parts(live.as_ptr(), 64).iter().all(|&b| b == 0x11));
+            allocator.verify().unwrap();
+            assert_eq!(allocator.alloc_by_size::<u8>(64).unwrap(), freed);
+        }
+    }
+
+    #[test]
+    fn reclaim_decommits_only_the_fully_free_page() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            let mut ptrs: Vec<NonNull<u8>> = Vec::new();
+            while allocator.committed_subheap_bytes() < 4 * pagesize || ptrs.len() < 4 * pagesize / 24 {
+                ptrs.push(allocator.alloc_by_size(16).unwrap());
+            }
+            let mut seeded = Vec::new();
+            while !allocator.free_lists[0].is_null() {
+                seeded.push(allocator.alloc_by_size::<u8>(16).unwrap());
+            }
+
+            let (heap_begin, _) = allocator.reserved_range();
+            let page = heap_begin.as_ptr() as usize + 2 * pagesize;
+            let carved = carved_size_of_subheap(0);
+            let on_page = |ptr: &NonNull<u8>| {
+                let block = ptr.as_ptr() as usize - size_of::<Header>();
+                block < page + pagesize && page < block + carved
+            };
+            for ptr in ptrs.iter().chain(&seeded).filter(|ptr| on_page(ptr)) {
+                allocator.free(*ptr).unwrap();
+            }
+            assert_eq!(allocator.reclaim_empty_subheap_pages().unwrap(), pagesize);
+            assert_eq!(allocator.reclaim_empty_subheap_pages().unwrap(), 0);
+
+            let ranges: Vec<(usize, usize)> =
+                allocator.committed_ranges().map(|(addr, len)| (addr.as_ptr() as usize, len)).collect();
+            assert_eq!(ranges[0], (heap_begin.as_ptr() as usize, 2 * pagesize));
+            assert_eq!(ranges[1].0, page + pagesize);
+            assert!(write_faults(page as *mut u8));
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn init_adapts_the_heap_to_a_low_address_space_limit() {
+        unsafe {
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                // Only this thread lives on in the child, so the address
+                // space in use stays put while the limits are tried.
+                let pagesize = page_size();
+                let statm = std::fs::read_to_string("/proc/self/statm").unwrap();
+                let in_use = statm.split_whitespace().next().unwrap().parse::<usize>().unwrap() * pagesize;
+                let limit = 2 * (in_use + (1 << 30));
+                let mut rlimit = std::mem::zeroed::<libc::rlimit>();
+                assert_eq!(libc::getrlimit(libc::RLIMIT_AS, &mut rlimit), 0);
+                rlimit.rlim_cur = limit as libc::rlim_t;
+                let adapts = libc::setrlimit(libc::RLIMIT_AS, &rlimit) == 0
+                    && Allocator::init().is_ok_and(|mut allocator| {
+                        allocator.capacity_total() == (limit / 2) & !(pagesize - 1)
+                            && allocator.alloc_by_size::<u8>(1000).is_ok()
+                    });
+
+                rlimit.rlim_cur = pagesize as libc::rlim_t;
+                let errors = libc::setrlimit(libc::RLIMIT_AS, &rlimit) == 0
+                    && Allocator::init().is_err_and(|error| error.to_string().contains("RLIMIT_AS"));
+                libc::_exit(if !adapts { 1 } else if !errors { 2 } else { 0 });
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFEXITED(status));
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+        }
+    }
+
+    #[test]
+    fn used_bytes_sums_the_outstanding_blocks() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            let held = |allocator: &Allocator, ptr: NonNull<u8>| match allocator.inspect(ptr).unwrap().class {
+                Some(class_of_subheap) => carved_size_of_subheap(class_of_subheap),
+                None => aligned_size(allocator.usable_size(ptr) + size_of::<Header>(), pagesize),
+            };
+
+            let mut live: Vec<NonNull<u8>> = Vec::new();
+            for step in 0..400usize {
+                if step % 4 == 3 {
+                    allocator.free(live.swap_remove(step % live.len())).unwrap();
+                } else {
+                    live.push(allocator.alloc_by_size(1 + step * 37 % 3000).unwrap());
+                }
+                let expected: usize = live.iter().map(|&ptr| held(&allocator, ptr)).sum();
+                assert_eq!(allocator.used_bytes(), expected, "step {}", step);
+            }
+            assert!(allocator.peak_used_bytes() >= allocator.used_bytes());
+            allocator.free_many(&live).unwrap();
+            assert_eq!(allocator.used_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn raised_external_thresholds_keep_medium_allocations_on_the_subheaps() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let medium: NonNull<u8> = allocator.alloc_by_size(5000).unwrap();
+            assert!(allocator.inspect(medium).unwrap().is_external);
+            allocator.free(medium).unwrap();
+
+            allocator.set_external_threshold(16 * 1024);
+            let medium: NonNull<u8> = allocator.alloc_by_size(5000).unwrap();
+            let info = allocator.inspect(medium).unwrap();
+            assert!(!info.is_external);
+            assert_eq!(info.class, Some(9));
+            assert_eq!(allocator.external_count(), 0);
+            let large: NonNull<u8> = allocator.alloc_by_size(16 * 1024 + 1).unwrap();
+            assert!(allocator.inspect(large).unwrap().is_external);
+
+            // Lowering the threshold again keeps the block valid.
+            allocator.set_external_threshold(0);
+            assert_eq!(allocator.max_block_size(), MAX_BLOCK_SIZE);
+            allocator.free(medium).unwrap();
+            allocator.free(large).unwrap();
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn debug_output_reports_the_heap_and_survives_cycles() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptrs: Vec<NonNull<u8>> = (0..3).map(|_| allocator.alloc_by_size(100).unwrap()).collect();
+            let external: NonNull<u8> = allocator.alloc_by_size(8000).unwrap();
+            allocator.free(ptrs[1]).unwrap();
+
+            let debug = format!("{:?}", allocator);
+            for field in ["heap_begin", "heap_end", "committed_offset", "active_offset", "free_list_lengths", "commit_strategy"] {
+                assert!(debug.contains(field), "{} missing from {}", field, debug);
+            }
+            assert!(debug.contains("external_count: 1"));
+            assert!(debug.starts_with("Allocator {"));
+
+            // A head linked to itself is followed only as far as the heap allows.
+            let head = NonNull::new(allocator.free_lists[3]).unwrap();
+            let next = allocator.next_of(head);
+            allocator.set_next(head, head.as_ptr());
+            assert!(format!("{:?}", allocator).contains("free_list_lengths"));
+            allocator.set_next(head, next);
+            allocator.free(ptrs[0]).unwrap();
+            allocator.free(ptrs[2]).unwrap();
+            allocator.free(external).unwrap();
+        }
+    }
+
+    #[test]
+    fn alloc_uninit_is_initialized_through_maybe_uninit() {
+        #[derive(Debug, PartialEq)]
+        struct Point {
+            x: u64,
+            y: u64,
+        }
+
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let mut uninit = allocator.alloc_uninit::<Point>().unwrap();
+            let point = uninit.as_mut().write(Point { x: 3, y: 4 });
+            point.y += 1;
+            assert_eq!(uninit.as_ref().assume_init_ref(), &Point { x: 3, y: 5 });
+
+            let array = allocator.alloc_uninit::<[u32; 200]>().unwrap();
+            assert_eq!(allocator.usable_size(array), 1024);
+            allocator.free(uninit).unwrap();
+            allocator.free(array).unwrap();
+            assert_eq!(allocator.used_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn maintenance_reclaims_the_free_pages_within_its_budget() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            let mut ptrs: Vec<NonNull<u8>> = Vec::new();
+            while allocator.committed_subheap_bytes() < 5 * pagesize {
+                ptrs.push(allocator.alloc_by_size(64).unwrap());
+            }
+            while !allocator.free_lists[2].is_null() {
+                ptrs.push(allocator.alloc_by_size(64).unwrap());
+            }
+
+            // Free whatever touches pages 1 and 2, for two reclaimable pages.
+            let (heap_begin, _) = allocator.reserved_range();
+            let (begin, end) = (heap_begin.as_ptr() as usize + pagesize, heap_begin.as_ptr() as usize + 3 * pagesize);
+            for &ptr in &ptrs {
+                let block = ptr.as_ptr() as usize - size_of::<Header>();
+                if block < end && begin < block + carved_size_of_subheap(2) {
+                    allocator.free(ptr).unwrap();
+                }
+            }
+
+            // Out of budget, the pages are left for the next call.
+            let free_blocks = |allocator: &Allocator| {
+                let mut count = 0;
+                allocator.for_each_free_block(|_, _| count += 1);
+                count
+            };
+            let before = free_blocks(&allocator);
+            let report = allocator.maintenance(std::time::Duration::ZERO).unwrap();
+            assert_eq!(report, MaintenanceReport { bytes_decommitted: 0, lists_compacted: 0, finished: false });
+            assert_eq!(free_blocks(&allocator), before);
+            allocator.verify().unwrap();
+
+            let report = allocator.maintenance(std::time::Duration::from_secs(60)).unwrap();
+            assert_eq!(report.bytes_decommitted, 2 * pagesize);
+            assert_eq!(report.lists_compacted, SUBHEAP_COUNT);
+            assert!(report.finished);
+            let report = allocator.maintenance(std::time::Duration::ZERO).unwrap();
+            assert_eq!(report.bytes_decommitted, 0);
+            assert_eq!(report.lists_compacted, 0);
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn large_reallocs_remap_instead_of_copying() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let len = 16 << 20;
+            let ptr: NonNull<u8> = allocator.alloc_filled(len, 0x6d).unwrap();
+            #[cfg(feature = "profile")]
+            let maps = allocator.syscall_counts().map;
+
+            let grown = allocator.realloc(ptr, 2 * len).unwrap();
+            #[cfg(feature = "profile")]
+            assert_eq!(allocator.syscall_counts().map, maps + 1);
+            assert_eq!(allocator.external_count(), 1);
+            assert_eq!(allocator.external_bytes(), aligned_size(2 * len + size_of::<Header>(), allocator.page_size()));
+            assert!(allocator.usable_size(grown) >= 2 * len);
+            assert!(std::slice::from_raw_parts(grown.as_ptr(), len).iter().all(|&b| b == 0x6d));
+            grown.as_ptr().add(2 * len - 1).write(1);
+            allocator.free(grown).unwrap();
+            assert_eq!(allocator.external_bytes(), 0);
+        }
+    }
+
+    /// Times growing a 16 MiB buffer by `realloc`, which remaps its pages,
+    /// against allocating, copying and freeing. Run with `--release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn bench_realloc_by_remap_against_copy() {
+        const ROUNDS: u32 = 20;
+
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let len = 16 << 20;
+
+            let mut copy = std::time::Duration::ZERO;
+            let mut remap = std::time::Duration::ZERO;
+            for _ in 0..ROUNDS {
+                let ptr: NonNull<u8> = allocator.alloc_filled(len, 1).unwrap();
+                let start = std::time::Instant::now();
+                let new_ptr: NonNull<u8> = allocator.alloc_by_size(2 * len).unwrap();
+                std::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr(), len);
+                allocator.free(ptr).unwrap();
+                copy += start.elapsed();
+                allocator.free(new_ptr).unwrap();
+
+                let ptr: NonNull<u8> = allocator.alloc_filled(len, 1).unwrap();
+                let start = std::time::Instant::now();
+                let new_ptr = allocator.realloc(ptr, 2 * len).unwrap();
+                remap += start.elapsed();
+                allocator.free(new_ptr).unwrap();
+            }
+            eprintln!("copying: {:?}/realloc, remapping: {:?}/realloc", copy / ROUNDS, remap / ROUNDS);
+        }
+    }
+
+    #[test]
+    fn high_watermark_fires_once_per_crossing() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let seen = calls.clone();
+            let block = carved_size_of_subheap(2);
+            allocator.set_high_watermark(10 * block, move |used_bytes| seen.lock().unwrap().push(used_bytes));
+
+            let mut ptrs: Vec<NonNull<u8>> = (0..9).map(|_| allocator.alloc_by_size(64).unwrap()).collect();
+            assert!(calls.lock().unwrap().is_empty());
+            for _ in 0..5 {
+                ptrs.push(allocator.alloc_by_size(64).unwrap());
+            }
+            assert_eq!(*calls.lock().unwrap(), [10 * block]);
+
+            // Dropping below re-arms it for the next crossing.
+            for ptr in ptrs.drain(5..) {
+                allocator.free(ptr).unwrap();
+            }
+            ptrs.push(allocator.alloc_by_size(64).unwrap());
+            assert_eq!(calls.lock().unwrap().len(), 1);
+            while ptrs.len() < 12 {
+                ptrs.push(allocator.alloc_by_size(64).unwrap());
+            }
+            assert_eq!(*calls.lock().unwrap(), [10 * block, 10 * block]);
+            allocator.free_many(&ptrs).unwrap();
+        }
+    }
+
+    #[test]
+    fn defragment_class_moves_blocks_down_and_reclaims_the_tail() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            let count = 8 * pagesize / carved_size_of_subheap(4);
+            let mut table: Vec<Option<NonNull<u64>>> = (0..count).map(|_| Some(allocator.alloc_by_size(256).unwrap())).collect();
+            // Keep every eighth block, spread over the whole range.
+            for (index, entry) in table.iter_mut().enumerate() {
+                if index % 8 != 7 {
+                    allocator.free(entry.take().unwrap()).unwrap();
+                } else {
+                    entry.unwrap().as_ptr().write(index as u64);
+                }
+            }
+            let handle = allocator.alloc_handle(256).unwrap();
+            allocator.resolve(handle).unwrap().cast::<u64>().as_ptr().write(u64::MAX);
+            let highest = table.iter().flatten().max().copied().unwrap();
+
+            let mut moved = 0;
+            let reclaimed = allocator
+                .defragment_class(4, |old, new| {
+                    let entry = table.iter_mut().flatten().find(|ptr| ptr.cast::<u8>() == old);
+                    if let Some(ptr) = entry {
+                        *ptr = new.cast();
+                        moved += 1;
+                    }
+                })
+                .unwrap();
+            assert!(moved > 0);
+            assert!(reclaimed >= pagesize);
+            assert!(table.iter().flatten().all(|&ptr| ptr < highest));
+            for (index, ptr) in table.iter().enumerate() {
+                if let Some(ptr) = ptr {
+                    assert_eq!(ptr.as_ptr().read(), index as u64);
+                }
+            }
+            assert_eq!(allocator.resolve(handle).unwrap().cast::<u64>().as_ptr().read(), u64::MAX);
+            allocator.verify().unwrap();
+
+            for ptr in table.into_iter().flatten() {
+                allocator.free(ptr).unwrap();
+            }
+            allocator.free_handle(handle).unwrap();
+        }
+    }
+
+    #[test]
+    fn malloc_usable_size_reports_the_block_capacity() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u8> = allocator.alloc_by_size(10).unwrap();
+            assert_eq!(allocator.malloc_usable_size(ptr), block_size_of_subheap(0));
+            let mid: NonNull<u8> = allocator.alloc_by_size(300).unwrap();
+            assert_eq!(allocator.malloc_usable_size(mid), 512);
+            let external: NonNull<u8> = allocator.alloc_by_size(5000).unwrap();
+            let size = header_of(external).as_ref().size_or_class_of_subheap;
+            assert_eq!(allocator.malloc_usable_size(external), size - size_of::<Header>());
+            allocator.free(ptr).unwrap();
+            allocator.free(mid).unwrap();
+            allocator.free(external).unwrap();
+        }
+    }
+
+    /// Stays clear of mmap, so that `cargo miri test fills_a_buffer_heap`
+    /// checks the boundary arithmetic as well.
+    #[test]
+    fn fills_a_buffer_heap_to_its_end() {
+        unsafe {
+            let mut buf = [MaybeUninit::<u8>::uninit(); 4096];
+            let buf_end = buf.as_ptr_range().end as usize;
+            let mut allocator = Allocator::from_buffer(&mut buf).unwrap();
+
+            let mut ptrs: Vec<NonNull<u8>> = Vec::new();
+            let error = loop {
+                match allocator.alloc_by_size(100) {
+                    Ok(ptr) => ptrs.push(ptr),
+                    Err(error) => break error,
+                }
+            };
+            assert!(matches!(error.downcast_ref(), Some(AllocError::OutOfReservedSpace)));
+            let carved = carved_size_of_subheap(3);
+            assert_eq!(ptrs.len(), allocator.reserved_range().1 / carved);
+            let last_end = ptrs.last().unwrap().as_ptr() as usize + block_size_of_subheap(3);
+            assert!(last_end <= buf_end && last_end + carved > buf_end);
+
+            // Smaller blocks can still use the tail.
+            while let Ok(ptr) = allocator.alloc_by_size::<u8>(16) {
+                assert!(ptr.as_ptr() as usize + 16 <= buf_end);
+                ptrs.push(ptr);
+            }
+            allocator.free_many(&ptrs).unwrap();
+            allocator.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn cacheline_aligned_blocks_start_on_a_cache_line() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_cacheline_align(true);
+            let mut ptrs: Vec<NonNull<u8>> = Vec::new();
+            for index in 0..500 {
+                let ptr: NonNull<u8> = allocator.alloc_by_size(1 + index * 7 % 1024).unwrap();
+                assert_eq!(ptr.as_ptr().align_offset(64), 0, "allocation {}", index);
+                ptr.as_ptr().write_bytes(0x64, allocator.usable_size(ptr));
+                ptrs.push(ptr);
+            }
+            for &ptr in ptrs.iter().step_by(2) {
+                allocator.free(ptr).unwrap();
+            }
+            ptrs = ptrs.into_iter().skip(1).step_by(2).collect();
+            for _ in 0..250 {
+                let ptr: NonNull<u8> = allocator.alloc_by_size(40).unwrap();
+                assert_eq!(ptr.as_ptr().align_offset(64), 0);
+                ptrs.push(ptr);
+            }
+            allocator.free_many(&ptrs).unwrap();
+        }
+    }
+
+    #[test]
+    fn for_each_free_block_visits_exactly_the_free_blocks() {
+        let free_blocks = |allocator: &Allocator| {
+            let mut found = std::collections::BTreeSet::new();
+            allocator.for_each_free_block(|class_of_subheap, ptr| {
+                assert!(found.insert((class_of_subheap, ptr)));
+            });
+            found
         };
-        Ok(allocated_ptr.cast())
+
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let live: Vec<NonNull<u8>> = (0..300).map(|index| allocator.alloc_by_size(16 << (index % 5)).unwrap()).collect();
+            let mut expected = free_blocks(&allocator);
+            for (index, &ptr) in live.iter().enumerate().filter(|(index, _)| index % 3 == 0) {
+                allocator.free(ptr).unwrap();
+                expected.insert((index % 5, ptr));
+            }
+            assert_eq!(free_blocks(&allocator), expected);
+        }
     }
 
-    unsafe fn free_on_external(&mut self, addr: NonNull<Header>, size: usize) -> Result<(), Box<dyn Error>> {
-        sys::release(addr.cast(), size)
+    #[test]
+    fn fast_and_slow_paths_hand_out_the_same_blocks() {
+        unsafe fn churn(allocator: &mut Allocator) -> Vec<NonNull<u8>> {
+            let mut handed_out = Vec::new();
+            let mut live: Vec<NonNull<u8>> = Vec::new();
+            for step in 0..2000usize {
+                if step % 3 == 2 {
+                    allocator.free(live.swap_remove(step % live.len())).unwrap();
+                } else {
+                    let ptr = allocator.alloc_by_size(1 + step * 29 % 1024).unwrap();
+                    handed_out.push(ptr);
+                    live.push(ptr);
+                }
+            }
+            allocator.free_many(&live).unwrap();
+            handed_out
+        }
+
+        unsafe {
+            let mut fast = Allocator::init().unwrap();
+            assert!(fast.fast_path_enabled());
+            let mut slow = Allocator::init().unwrap();
+            slow.set_high_watermark(usize::MAX, |_| {});
+            assert!(!slow.fast_path_enabled());
+
+            let offsets = |allocator: &Allocator, ptrs: Vec<NonNull<u8>>| {
+                let base = allocator.reserved_range().0.as_ptr() as usize;
+                ptrs.into_iter().map(|ptr| ptr.as_ptr() as usize - base).collect::<Vec<_>>()
+            };
+            let fast_ptrs = churn(&mut fast);
+            let slow_ptrs = churn(&mut slow);
+            let fast_offsets = offsets(&fast, fast_ptrs);
+            let slow_offsets = offsets(&slow, slow_ptrs);
+            assert_eq!(fast_offsets, slow_offsets);
+            assert_eq!(fast.used_bytes(), 0);
+            assert_eq!(slow.used_bytes(), 0);
+            fast.verify().unwrap();
+            slow.verify().unwrap();
+        }
     }
 
-    unsafe fn extend_active_heap_end(&mut self, class_of_subheap: usize) -> Result<NonNull<Header>, Box<dyn Error>> {
-        let allocated_size = size_of::<Header>() + block_size_of_subheap(class_of_subheap);
-        let new_active_heap_end = NonNull::new_unchecked(self.active_heap_end.as_ptr().add(allocated_size));
-        if self.heap_end < new_active_heap_end {
-            return Err(format!("Failed to extend heap size.").into());
+    /// Times a tight alloc and free loop on the inlined fast paths, against
+    /// the same loop forced onto the out-of-line slow paths by a watermark
+    /// that never fires. Run with `--release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn bench_fast_path_against_slow_path() {
+        const ROUNDS: usize = 10_000_000;
+
+        unsafe fn time_loop(allocator: &mut Allocator) -> std::time::Duration {
+            let ptr: NonNull<u64> = allocator.alloc().unwrap();
+            allocator.free(ptr).unwrap();
+            let start = std::time::Instant::now();
+            for _ in 0..ROUNDS {
+                let ptr: NonNull<u64> = allocator.alloc().unwrap();
+                allocator.free(std::hint::black_box(ptr)).unwrap();
+            }
+            start.elapsed()
         }
 
-        if self.commited_heap_end < new_active_heap_end {
-            let committed_size = aligned_size(
-                new_active_heap_end.as_ptr().offset_from(self.active_heap_end.as_ptr()) as usize,
-                self.pagesize,
+        unsafe {
+            let mut fast = Allocator::init().unwrap();
+            let mut slow = Allocator::init().unwrap();
+            slow.set_high_watermark(usize::MAX, |_| {});
+            let fast_time = time_loop(&mut fast);
+            let slow_time = time_loop(&mut slow);
+            eprintln!(
+                "fast path: {:.2} ns/op, slow path: {:.2} ns/op",
+                fast_time.as_nanos() as f64 / ROUNDS as f64,
+                slow_time.as_nanos() as f64 / ROUNDS as f64,
             );
-            self.prefer_commit_strategy = sys::commit(self.commited_heap_end, committed_size, self.prefer_commit_strategy)?;
-            self.commited_heap_end = NonNull::new_unchecked(self.commited_heap_end.as_ptr().add(committed_size));
         }
+    }
 
-        let mut allocated_ptr: NonNull<Header> = self.active_heap_end.cast();
-        self.active_heap_end = new_active_heap_end;
+    #[test]
+    fn class_regions_keep_classes_in_disjoint_ranges() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_class_regions().unwrap();
+            assert!(allocator.set_class_regions().is_err());
 
-        *allocated_ptr.as_mut() = Header {
-            size_or_class_of_subheap: class_of_subheap,
-        };
-        Ok(allocated_ptr)
+            let mut ranges = Vec::new();
+            for class_of_subheap in 0..DEFAULT_SUBHEAP_COUNT {
+                let ptrs: Vec<usize> = (0..50)
+                    .map(|_| allocator.alloc_by_size::<u8>(block_size_of_subheap(class_of_subheap)).unwrap().as_ptr() as usize)
+                    .collect();
+                let region = allocator.bump_region(class_of_subheap);
+                let (begin, end) = (region.begin.as_ptr() as usize, region.end.as_ptr() as usize);
+                assert!(ptrs.iter().all(|&addr| begin < addr && addr < end));
+                ranges.push((*ptrs.iter().min().unwrap(), *ptrs.iter().max().unwrap()));
+            }
+            ranges.sort();
+            assert!(ranges.windows(2).all(|pair| pair[0].1 < pair[1].0));
+
+            let mut shared = Allocator::init().unwrap();
+            let carved: NonNull<u8> = shared.alloc_by_size(16).unwrap();
+            assert!(shared.set_class_regions().is_err());
+            shared.free(carved).unwrap();
+        }
+    }
+
+    #[test]
+    fn release_levels_free_the_expected_bytes() {
+        // Blocks over about eight pages, with live ones in the middle of
+        // pages 1 and 4 only, and a freed external mapping in the cache.
+        unsafe fn fragmented() -> (Allocator, usize, usize) {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_external_reuse(true);
+            let pagesize = allocator.page_size();
+            let base = allocator.reserved_range().0.as_ptr() as usize;
+            let mut ptrs: Vec<NonNull<u8>> = Vec::new();
+            while allocator.committed_subheap_bytes() < 8 * pagesize || !allocator.free_lists[2].is_null() {
+                ptrs.push(allocator.alloc_by_size(64).unwrap());
+            }
+            let carved = carved_size_of_subheap(2);
+            let holds = |ptr: NonNull<u8>, addr: usize| {
+                let block = ptr.as_ptr() as usize - size_of::<Header>();
+                block <= addr && addr < block + carved
+            };
+            let live_pages = [1, 4];
+            for &ptr in &ptrs {
+                if !live_pages.iter().any(|&page| holds(ptr, base + page * pagesize + pagesize / 2)) {
+                    allocator.free(ptr).unwrap();
+                }
+            }
+            let external: NonNull<u8> = allocator.alloc_by_size(2 * pagesize).unwrap();
+            allocator.free(external).unwrap();
+            (allocator, 5 * pagesize, 3 * pagesize)
+        }
+
+        unsafe {
+            let (mut gentle, kept, _) = fragmented();
+            let committed = gentle.committed_subheap_bytes();
+            assert_eq!(gentle.release_to_os(ReleaseLevel::Gentle).unwrap(), committed - kept);
+            assert_eq!(gentle.committed_subheap_bytes(), kept);
+            assert_eq!(gentle.release_to_os(ReleaseLevel::Gentle).unwrap(), 0);
+            gentle.verify().unwrap();
+
+            // Pages 0, 2 and 3 hold only free blocks.
+            let (mut aggressive, kept, external_size) = fragmented();
+            let pagesize = aggressive.page_size();
+            let committed = aggressive.committed_subheap_bytes();
+            assert_eq!(
+                aggressive.release_to_os(ReleaseLevel::Aggressive).unwrap(),
+                committed - kept + 3 * pagesize + external_size,
+            );
+            assert_eq!(aggressive.release_to_os(ReleaseLevel::Aggressive).unwrap(), 0);
+            aggressive.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn fixed_base_heaps_pin_the_block_layout() {
+        unsafe {
+            let len = 1 << 30;
+            // Find a free range, then reserve exactly there.
+            let probe = reserve_mapping(len);
+            sys::release(probe, len).unwrap();
+            let base = probe.as_ptr() as usize;
+
+            let mut allocator = Allocator::init_at(probe.as_ptr(), len).unwrap();
+            assert_eq!(allocator.reserved_range(), (probe, len));
+            assert!(Allocator::init_at(probe.as_ptr(), len).is_err());
+
+            let first: NonNull<u8> = allocator.alloc_by_size(16).unwrap();
+            let second: NonNull<u8> = allocator.alloc_by_size(16).unwrap();
+            assert_eq!(first.as_ptr() as usize, base + size_of::<Header>());
+            assert_eq!(second.as_ptr() as usize, base + 2 * size_of::<Header>() + block_size_of_subheap(0));
+            let larger: NonNull<u8> = allocator.alloc_by_size(32).unwrap();
+            assert!(larger.as_ptr() as usize > second.as_ptr() as usize);
+        }
+    }
+
+    /// Enough of a JSON parser for `stats_json`, which has no escapes.
+    #[derive(PartialEq, Debug)]
+    enum Json {
+        Object(Vec<(String, Json)>),
+        Array(Vec<Json>),
+        String(String),
+        Number(f64),
+    }
+
+    fn parse_json(text: &str) -> Option<Json> {
+        fn value(bytes: &[u8], at: &mut usize) -> Option<Json> {
+            match *bytes.get(*at)? {
+                b'{' => {
+                    *at += 1;
+                    let mut fields = Vec::new();
+                    while bytes.get(*at) != Some(&b'}') {
+                        if !fields.is_empty() {
+                            (bytes.get(*at) == Some(&b',')).then(|| *at += 1)?;
+                        }
+                        let Json::String(key) = value(bytes, at)? else {
+                            return None;
+                        };
+                        (bytes.get(*at) == Some(&b':')).then(|| *at += 1)?;
+                        fields.push((key, value(bytes, at)?));
+                    }
+                    *at += 1;
+                    Some(Json::Object(fields))
+                }
+                b'[' => {
+                    *at += 1;
+                    let mut items = Vec::new();
+                    while bytes.get(*at) != Some(&b']') {
+                        if !items.is_empty() {
+                            (bytes.get(*at) == Some(&b',')).then(|| *at += 1)?;
+                        }
+                        items.push(value(bytes, at)?);
+                    }
+                    *at += 1;
+                    Some(Json::Array(items))
+                }
+                b'"' => {
+                    let end = *at + 1 + bytes[*at + 1..].iter().position(|&b| b == b'"')?;
+                    let string = std::str::from_utf8(&bytes[*at + 1..end]).ok()?.to_string();
+                    *at = end + 1;
+                    Some(Json::String(string))
+                }
+                _ => {
+                    let len = bytes[*at..].iter().take_while(|b| b.is_ascii_digit() || b"-+.eE".contains(b)).count();
+                    let number = std::str::from_utf8(&bytes[*at..*at + len]).ok()?.parse().ok()?;
+                    *at += len;
+                    Some(Json::Number(number))
+                }
+            }
+        }
+
+        let mut at = 0;
+        let json = value(text.as_bytes(), &mut at)?;
+        (at == text.len()).then_some(json)
+    }
+
+    #[test]
+    fn stats_json_parses_with_the_expected_keys() {
+        assert!(parse_json("{\"a\":[1,2],\"b\":\"x\"}").is_some());
+        assert!(parse_json("{\"a\":1,}").is_none());
+
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let small: NonNull<u8> = allocator.alloc_by_size(100).unwrap();
+            let external: NonNull<u8> = allocator.alloc_by_size(5000).unwrap();
+            let Some(Json::Object(fields)) = parse_json(&allocator.stats_json()) else {
+                panic!("stats are not a JSON object: {}", allocator.stats_json());
+            };
+            let keys: Vec<&str> = fields.iter().map(|(key, _)| key.as_str()).collect();
+            assert_eq!(
+                keys,
+                ["committed_bytes", "active_bytes", "used_bytes", "peak_used_bytes", "external_count", "external_bytes", "commit_strategy", "classes"],
+            );
+            let field = |name: &str| &fields.iter().find(|(key, _)| key == name).unwrap().1;
+            assert_eq!(field("external_count"), &Json::Number(1.0));
+            assert_eq!(field("used_bytes"), &Json::Number(allocator.used_bytes() as f64));
+            let Json::Array(classes) = field("classes") else {
+                panic!("classes are not an array");
+            };
+            assert_eq!(classes.len(), allocator.subheap_count());
+            allocator.free(small).unwrap();
+            allocator.free(external).unwrap();
+        }
+    }
+
+    #[test]
+    fn inspect_sees_a_freed_block_on_its_free_list() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u64> = allocator.alloc().unwrap();
+            let live = allocator.inspect(ptr).unwrap();
+            assert_eq!(live, BlockInfo { class: Some(0), block_size: block_size_of_subheap(0), is_external: false, on_free_list: false });
+            allocator.free(ptr).unwrap();
+            assert_eq!(allocator.inspect(ptr).unwrap(), BlockInfo { on_free_list: true, ..live });
+            assert!(allocator.inspect(NonNull::new_unchecked(ptr.as_ptr().byte_add(1))).is_none());
+        }
+    }
+
+    #[test]
+    fn page_size_is_cached_after_the_first_call() {
+        use std::sync::atomic::Ordering;
+        let pagesize = page_size();
+        // Tests race for the first call, so only growth after it is checked.
+        let calls = sys::SYSCONF_CALLS.load(Ordering::Relaxed);
+        assert!(calls >= 1);
+        for _ in 0..100 {
+            assert_eq!(page_size(), pagesize);
+        }
+        let allocator = unsafe { Allocator::init() }.unwrap();
+        assert_eq!(allocator.page_size(), pagesize);
+        assert_eq!(sys::SYSCONF_CALLS.load(Ordering::Relaxed), calls);
+    }
+
+    #[test]
+    fn external_reuse_hands_back_the_freed_mapping() {
+        unsafe {
+            for enabled in [false, true] {
+                let mut allocator = Allocator::init().unwrap();
+                allocator.set_external_reuse(enabled);
+                let first: NonNull<u8> = allocator.alloc_by_size(3000).unwrap();
+                allocator.free(first).unwrap();
+                let second: NonNull<u8> = allocator.alloc_by_size(3000).unwrap();
+                if enabled {
+                    assert_eq!(second, first);
+                    #[cfg(feature = "profile")]
+                    assert_eq!(allocator.syscall_counts().map, 1);
+                }
+                second.as_ptr().write_bytes(0, 3000);
+                allocator.free(second).unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "harden")]
+    #[test]
+    #[should_panic(expected = "links outside the carved heap")]
+    fn out_of_range_free_links_panic_on_pop() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let first: NonNull<usize> = allocator.alloc().unwrap();
+            let second: NonNull<usize> = allocator.alloc().unwrap();
+            allocator.free(first).unwrap();
+            allocator.free(second).unwrap();
+
+            // Forge the canary too, so only the range check stands in the way.
+            let wild = 0x1000;
+            second.as_ptr().write(wild);
+            second.as_ptr().add(1).write(wild ^ allocator.free_list_secret);
+            let _: NonNull<usize> = allocator.alloc().unwrap();
+        }
+    }
+
+    #[cfg(feature = "harden")]
+    #[test]
+    #[should_panic(expected = "links outside the carved heap")]
+    fn mis_strided_free_links_panic_on_pop() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_class_regions().unwrap();
+            let first: NonNull<[usize; 8]> = allocator.alloc().unwrap();
+            let second: NonNull<[usize; 8]> = allocator.alloc().unwrap();
+            let _third: NonNull<[usize; 8]> = allocator.alloc().unwrap();
+            allocator.free(first).unwrap();
+            allocator.free(second).unwrap();
+
+            // Inside the carved blocks and header aligned, but not on a block.
+            let skewed = header_of(first).as_ptr() as usize + align_of::<Header>();
+            let link = second.as_ptr() as *mut usize;
+            link.write(skewed);
+            link.add(1).write(skewed ^ allocator.free_list_secret);
+            let _: NonNull<[usize; 8]> = allocator.alloc().unwrap();
+        }
+    }
+
+    #[test]
+    fn reserved_range_covers_every_subheap_block() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let (base, len) = allocator.reserved_range();
+            assert_eq!(base, allocator.heap_begin);
+            assert_eq!(base.as_ptr() as usize + len, allocator.heap_end.as_ptr() as usize);
+            let range = base.as_ptr() as usize..base.as_ptr() as usize + len;
+            let mut ptrs: Vec<NonNull<u8>> = Vec::new();
+            for len in [1, 16, 100, 500, 1024, 5000] {
+                for _ in 0..50 {
+                    let ptr = allocator.alloc_by_size(len).unwrap();
+                    assert_eq!(range.contains(&(ptr.as_ptr() as usize)), len <= MAX_BLOCK_SIZE);
+                    ptrs.push(ptr);
+                }
+            }
+            allocator.free_many(&ptrs).unwrap();
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn lazily_freed_pages_are_reclaimable_but_reusable() {
+        // Without swap, reclaim can only drop pages advised with MADV_FREE.
+        let no_swap = std::fs::read_to_string("/proc/swaps").is_ok_and(|swaps| swaps.lines().count() <= 1);
+        unsafe {
+            for lazy in [false, true] {
+                let mut allocator = Allocator::init().unwrap();
+                allocator.set_external_threshold(64 * 1024);
+                allocator.set_lazy_free(lazy);
+                let pagesize = allocator.page_size();
+                let ptr: NonNull<u8> = allocator.alloc_by_size(64 * 1024).unwrap();
+                ptr.as_ptr().write_bytes(0xab, 64 * 1024);
+                allocator.free(ptr).unwrap();
+
+                // What `advise_free_block` advises: the whole pages past the link.
+                let begin = aligned_size(ptr.as_ptr() as usize - size_of::<Header>() + size_of::<FreeHeader>(), pagesize);
+                let end = (ptr.as_ptr() as usize + 64 * 1024) & !(pagesize - 1);
+                let pages = (end - begin) / pagesize;
+                assert!(pages > 0);
+                // Stand in for memory pressure by asking the kernel to reclaim now.
+                assert_eq!(libc::madvise(begin as *mut libc::c_void, end - begin, libc::MADV_PAGEOUT), 0);
+                let mut resident = vec![0u8; pages];
+                assert_eq!(libc::mincore(begin as *mut libc::c_void, end - begin, resident.as_mut_ptr()), 0);
+                let resident = resident.iter().filter(|&&page| page & 1 != 0).count();
+                if lazy {
+                    assert_eq!(resident, 0);
+                } else if no_swap {
+                    assert_eq!(resident, pages);
+                }
+
+                let reused: NonNull<u8> = allocator.alloc_by_size(64 * 1024).unwrap();
+                assert_eq!(reused, ptr);
+                let data = std::slice::from_raw_parts_mut(reused.as_ptr(), 64 * 1024);
+                let expected = if lazy { 0 } else { 0xab };
+                assert!(data[begin - reused.as_ptr() as usize..end - reused.as_ptr() as usize].iter().all(|&b| b == expected));
+                data.fill(0xcd);
+                assert!(data.iter().all(|&b| b == 0xcd));
+                allocator.free(reused).unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn syscall_counts_follow_a_known_sequence() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            assert_eq!(allocator.syscall_counts(), SyscallCounts { reserve: 1, ..SyscallCounts::default() });
+
+            // One commit covers the first page of blocks and those seeded after it.
+            let small: Vec<NonNull<u64>> = (0..10).map(|_| allocator.alloc().unwrap()).collect();
+            assert_eq!(allocator.syscall_counts().commit, 1);
+
+            let external: NonNull<u8> = allocator.alloc_by_size(5000).unwrap();
+            allocator.free(external).unwrap();
+            allocator.free_many(&small).unwrap();
+            assert_eq!(
+                allocator.syscall_counts(),
+                SyscallCounts { reserve: 1, commit: 1, decommit: 0, map: 1, release: 1 },
+            );
+        }
+    }
+
+    #[test]
+    fn shrinking_a_block_splits_its_tail_onto_a_smaller_list() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let free_count = |allocator: &Allocator, class: usize| {
+                let mut count = 0;
+                allocator.for_each_free_block(|class_of_subheap, _| count += (class_of_subheap == class) as usize);
+                count
+            };
+            let ptr: NonNull<u8> = allocator.alloc_by_size(MAX_BLOCK_SIZE).unwrap();
+            ptr.as_ptr().write_bytes(0x5a, block_size_of_subheap(0));
+            let before = free_count(&allocator, 0);
+
+            allocator.shrink(ptr, block_size_of_subheap(0)).unwrap();
+            assert_eq!(allocator.inspect(ptr).unwrap().class, Some(0));
+            assert!(std::slice::from_raw_parts(ptr.as_ptr(), block_size_of_subheap(0)).iter().all(|&b| b == 0x5a));
+            let top = DEFAULT_SUBHEAP_COUNT - 1;
+            assert_eq!(
+                free_count(&allocator, 0) - before,
+                (carved_size_of_subheap(top) - carved_size_of_subheap(0)) / carved_size_of_subheap(0),
+            );
+            assert_eq!(allocator.used_bytes(), carved_size_of_subheap(0));
+            allocator.free(ptr).unwrap();
+        }
+    }
+
+    #[test]
+    fn page_aligned_small_buffers_stay_in_the_reservation() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            let (base, len) = allocator.reserved_range();
+            let range = base.as_ptr() as usize..base.as_ptr() as usize + len;
+            let ptrs: Vec<NonNull<u8>> = (0..8).map(|_| allocator.alloc_aligned(256, pagesize).unwrap()).collect();
+            for &ptr in &ptrs {
+                assert!((ptr.as_ptr() as usize).is_multiple_of(pagesize));
+                assert!(range.contains(&(ptr.as_ptr() as usize)));
+                ptr.as_ptr().write_bytes(0xee, 256);
+            }
+            assert_eq!(allocator.external_count(), 0);
+            #[cfg(feature = "profile")]
+            assert_eq!(allocator.syscall_counts().map, 0);
+            allocator.free_many(&ptrs).unwrap();
+        }
+    }
+
+    #[test]
+    fn restored_snapshots_replay_the_same_allocations() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let sizes = [16, 100, 16, 700, 40, 16];
+            let kept: Vec<NonNull<u8>> = sizes.iter().map(|&len| allocator.alloc_by_size(len).unwrap()).collect();
+            allocator.free(kept[1]).unwrap();
+            allocator.free(kept[4]).unwrap();
+            let snapshot = allocator.snapshot();
+
+            let replay = |allocator: &mut Allocator| -> Vec<NonNull<u8>> {
+                sizes.iter().chain(&sizes).map(|&len| allocator.alloc_by_size(len).unwrap()).collect()
+            };
+            let expected = replay(&mut allocator);
+
+            // Mutate the free lists and carve further before going back.
+            allocator.free_many(&expected[..6]).unwrap();
+            allocator.free(kept[0]).unwrap();
+            for _ in 0..500 {
+                let _: NonNull<u8> = allocator.alloc_by_size(200).unwrap();
+            }
+            allocator.release_to_os(ReleaseLevel::Aggressive).unwrap();
+            allocator.restore(&snapshot).unwrap();
+            assert_eq!(replay(&mut allocator), expected);
+
+            allocator.restore(&snapshot).unwrap();
+            assert_eq!(replay(&mut allocator), expected);
+            assert!(allocator.verify().is_ok());
+        }
+    }
+
+    #[test]
+    fn init_adapts_to_large_pages_and_rejects_odd_ones() {
+        struct Override;
+        impl Drop for Override {
+            fn drop(&mut self) {
+                sys::PAGESIZE_OVERRIDE.set(None);
+            }
+        }
+        let _restore = Override;
+
+        // 64 KiB pages are a multiple of the real ones, so they can be mapped here.
+        sys::PAGESIZE_OVERRIDE.set(Some(65536));
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            assert_eq!(allocator.page_size(), 65536);
+            let (base, len) = allocator.reserved_range();
+            assert!((base.as_ptr() as usize).is_multiple_of(page_size()));
+            assert!(len.is_multiple_of(65536));
+            assert!(len <= aligned_size(MAX_HEAP_SIZE, 65536));
+            let ptr: NonNull<u8> = allocator.alloc_by_size(100).unwrap();
+            ptr.as_ptr().write_bytes(1, 100);
+            let (_, len) = allocator.committed_ranges().next().unwrap();
+            assert_eq!(len % 65536, 0);
+            allocator.free(ptr).unwrap();
+        }
+
+        sys::PAGESIZE_OVERRIDE.set(Some(3 * 4096));
+        let error = unsafe { Allocator::init() }.err().unwrap();
+        assert_eq!(error.to_string(), "Page size 12288 is not a power of two.");
+    }
+
+    #[test]
+    fn shards_of_one_reservation_serve_disjoint_ranges() {
+        unsafe {
+            let mut parent = Allocator::init().unwrap();
+            let (base, len) = parent.reserved_range();
+            let reservation = base.as_ptr() as usize..base.as_ptr() as usize + len;
+            let parent_ptr: NonNull<u8> = parent.alloc_by_size(100).unwrap();
+            let mut shards = [parent.fork_shard(1 << 20).unwrap(), parent.fork_shard(1 << 20).unwrap()];
+            let after: NonNull<u8> = parent.alloc_by_size(100).unwrap();
+
+            let mut ranges = vec![];
+            for shard in &mut shards {
+                let (base, len) = shard.reserved_range();
+                let range = base.as_ptr() as usize..base.as_ptr() as usize + len;
+                assert!(reservation.start <= range.start && range.end <= reservation.end);
+                let ptrs: Vec<NonNull<u8>> = (0..100).map(|_| shard.alloc_by_size(200).unwrap()).collect();
+                for &ptr in &ptrs {
+                    assert!(range.contains(&(ptr.as_ptr() as usize)));
+                    ptr.as_ptr().write_bytes(0x11, 200);
+                }
+                shard.free_many(&ptrs).unwrap();
+                ranges.push(range);
+            }
+            assert!(ranges[0].end <= ranges[1].start);
+            for ptr in [parent_ptr, after] {
+                assert!(ranges.iter().all(|range| !range.contains(&(ptr.as_ptr() as usize))));
+            }
+            drop(shards);
+            parent.free(parent_ptr).unwrap();
+            parent.free(after).unwrap();
+        }
+    }
+
+    /// Runs `child` in a forked child inside a fresh memory cgroup limited
+    /// to `limit` bytes, and returns its wait status, or `None` where
+    /// memory cgroups cannot be created.
+    ///
+    /// The cgroup's OOM killer is disabled, so that charges the kernel
+    /// makes on the child's behalf fail instead. A fault past the limit
+    /// would wait for memory forever, so the child is given an alarm.
+    unsafe fn wait_in_memory_cgroup(limit: usize, child: impl FnOnce() -> i32) -> Option<libc::c_int> {
+        let dir = std::path::Path::new("/sys/fs/cgroup/memory").join(format!("sample-alloc-{}", std::process::id()));
+        std::fs::create_dir(&dir).ok()?;
+        let limited = std::fs::write(dir.join("memory.limit_in_bytes"), limit.to_string()).is_ok()
+            && std::fs::write(dir.join("memory.oom_control"), "1").is_ok();
+        let status = limited.then(|| {
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                libc::alarm(30);
+                let joined = std::fs::write(dir.join("cgroup.procs"), libc::getpid().to_string()).is_ok();
+                libc::_exit(if joined { child() } else { 100 });
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            status
+        });
+        std::fs::remove_dir(&dir).unwrap();
+        status
+    }
+
+    #[test]
+    fn eager_commits_turn_a_memory_limit_into_an_error() {
+        unsafe {
+            let status = wait_in_memory_cgroup(64 << 20, || {
+                let mut allocator = Allocator::init().unwrap();
+                allocator.set_eager_commit(true);
+                // A single commit past the limit, so that nothing else
+                // faults there first.
+                allocator.set_commit_granularity(128 << 20).unwrap();
+                let Err(error) = allocator.alloc_by_size::<u8>(100) else {
+                    return 1;
+                };
+                if !error.to_string().starts_with("Failed to commit heap") {
+                    return 2;
+                }
+                // The pages populated before the failure were given back.
+                allocator.set_commit_granularity(page_size()).unwrap();
+                match allocator.alloc_by_size::<u8>(100) {
+                    Ok(ptr) => {
+                        ptr.as_ptr().write_bytes(1, 100);
+                        0
+                    }
+                    Err(_) => 3,
+                }
+            });
+            let Some(status) = status else {
+                eprintln!("skipped: cannot create a memory cgroup");
+                return;
+            };
+            assert!(libc::WIFEXITED(status), "killed by signal {}", libc::WTERMSIG(status));
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+        }
+    }
+
+    #[cfg(feature = "track-live")]
+    #[test]
+    fn iter_live_yields_the_objects_left_in_a_pool() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let small: Vec<NonNull<[u64; 3]>> = (0..5).map(|_| allocator.alloc().unwrap()).collect();
+            allocator.free(small[2]).unwrap();
+            allocator.defer_free(small[4]).unwrap();
+            assert_eq!(allocator.iter_live::<[u64; 3]>().collect::<Vec<_>>(), [small[0], small[1], small[3]]);
+
+            let mut large: Vec<NonNull<[u8; 3000]>> = (0..3).map(|_| allocator.alloc().unwrap()).collect();
+            allocator.free(large.remove(1)).unwrap();
+            let mut live: Vec<_> = allocator.iter_live::<[u8; 3000]>().collect();
+            live.sort();
+            large.sort();
+            assert_eq!(live, large);
+        }
+    }
+
+    #[test]
+    fn replayed_traces_end_in_the_same_stats() {
+        unsafe {
+            let mut recorded = Allocator::init().unwrap();
+            recorded.start_recording();
+            let mut live: Vec<NonNull<u8>> = Vec::new();
+            for step in 0..200usize {
+                if step % 3 == 2 {
+                    recorded.free(live.swap_remove(step % live.len())).unwrap();
+                } else {
+                    live.push(recorded.alloc_by_size(step * 37 % 3000 + 1).unwrap());
+                }
+            }
+            recorded.free_many(&live[..10]).unwrap();
+            let trace = recorded.stop_recording();
+            assert_eq!(trace.iter().filter(|op| matches!(op, TraceOp::Alloc { .. })).count(), 134);
+            assert_eq!(trace.iter().filter(|op| matches!(op, TraceOp::Free { .. })).count(), 66 + 10);
+
+            let mut replayed = Allocator::init().unwrap();
+            replayed.replay(&trace).unwrap();
+            assert_eq!(replayed.stats_json(), recorded.stats_json());
+            assert!(replayed.replay(&[TraceOp::Free { id: 0 }]).is_err());
+        }
+    }
+
+    #[test]
+    fn frees_into_another_allocator_are_foreign() {
+        unsafe {
+            let mut a = Allocator::init().unwrap();
+            let mut b = Allocator::init().unwrap();
+            let _: NonNull<u64> = b.alloc().unwrap();
+            for len in [16, 500, 5000] {
+                let ptr: NonNull<u8> = a.alloc_by_size(len).unwrap();
+                let error = b.free(ptr).unwrap_err();
+                assert!(matches!(error.downcast_ref::<AllocError>(), Some(AllocError::ForeignPointer)), "{}", error);
+                assert!(b.free_many(&[ptr]).is_err());
+                a.free(ptr).unwrap();
+            }
+            assert!(b.verify().is_ok());
+        }
+    }
+
+    #[test]
+    fn prewarmed_classes_allocate_without_commits() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let per_class = 20;
+            let committed = allocator.prewarm_all_classes(per_class).unwrap();
+            let carved: usize = (0..allocator.subheap_count()).map(|class| per_class * carved_size_of_subheap(class)).sum();
+            assert!(committed >= carved);
+            assert!(committed < carved + allocator.subheap_count() * allocator.page_size());
+
+            #[cfg(feature = "profile")]
+            let counts = allocator.syscall_counts();
+            for class in 0..allocator.subheap_count() {
+                for _ in 0..per_class {
+                    let ptr: NonNull<u8> = allocator.alloc_by_size(block_size_of_subheap(class)).unwrap();
+                    assert_eq!(allocator.inspect(ptr).unwrap().class, Some(class));
+                }
+                assert!(allocator.free_lists[class].is_null());
+            }
+            #[cfg(feature = "profile")]
+            assert_eq!(allocator.syscall_counts(), counts);
+        }
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn batched_extension_cuts_commits_of_a_same_class_run() {
+        unsafe {
+            let commits = |batch: usize| {
+                let mut allocator = Allocator::init().unwrap();
+                // Blocks over a page, so that commits are not coalesced anyway.
+                allocator.set_external_threshold(8 << 10);
+                allocator.set_extension_batch(batch);
+                for _ in 0..64 {
+                    let ptr: NonNull<u8> = allocator.alloc_by_size(8 << 10).unwrap();
+                    ptr.as_ptr().write_bytes(7, 8 << 10);
+                }
+                allocator.syscall_counts().commit
+            };
+            assert_eq!(commits(1), 64);
+            assert_eq!(commits(8), 8);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn executable_allocations_run_the_code_written_to_them() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let exec = allocator.alloc_exec(16).unwrap();
+            assert!((exec.ptr().as_ptr() as usize).is_multiple_of(allocator.page_size()));
+            // mov eax, 42; ret
+            let code = [0xb8, 0x2a, 0x00, 0x00, 0x00, 0xc3];
+            std::ptr::copy_nonoverlapping(code.as_ptr(), exec.ptr().as_ptr(), code.len());
+            allocator.make_executable(&exec).unwrap();
+
+            let function: extern "C" fn() -> i32 = std::mem::transmute(exec.ptr().as_ptr());
+            assert_eq!(function(), 42);
+            // W^X: the code pages are no longer writable.
+            assert!(write_faults(exec.ptr().as_ptr()));
+            allocator.free(exec.ptr()).unwrap();
+
+            let block = allocator.alloc_by_size::<u8>(64).unwrap();
+            let subheap = ExecAlloc { ptr: block, len: 64 };
+            assert!(allocator.make_executable(&subheap).is_err());
+            allocator.free(block).unwrap();
+        }
+    }
+
+    #[test]
+    fn hugepage_blocks_are_aligned_and_reused() {
+        // Only a reserved hugetlb pool can back the blocks; without one they
+        // fall back to normal pages.
+        let meminfo = std::fs::read_to_string("/proc/meminfo").unwrap();
+        let free_hugepages = meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix("HugePages_Free:"))
+            .map_or(0, |count| count.trim().parse::<usize>().unwrap());
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let block = allocator.alloc_hugepage_block().unwrap();
+            assert!((block.as_ptr() as usize).is_multiple_of(HUGEPAGE_SIZE));
+            assert_eq!(allocator.usable_size(block), HUGEPAGE_SIZE);
+            block.as_ptr().write_bytes(0x42, HUGEPAGE_SIZE);
+
+            if free_hugepages > 0 {
+                let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+                let mapping = format!("{:x}-", block.as_ptr() as usize);
+                let entry = smaps.split_once(&mapping).unwrap().1;
+                let page_size = entry.lines().find_map(|line| line.strip_prefix("KernelPageSize:")).unwrap();
+                assert_eq!(page_size.trim(), "2048 kB");
+                assert_eq!(allocator.hugepage_fallbacks(), 0);
+            } else {
+                assert_eq!(allocator.hugepage_fallbacks(), 1);
+            }
+
+            allocator.free(block).unwrap();
+            assert_eq!(allocator.external_count(), 0);
+            let reused = allocator.alloc_hugepage_block().unwrap();
+            assert_eq!(reused, block);
+            allocator.free(reused).unwrap();
+        }
+    }
+
+    #[test]
+    fn free_list_utilization_follows_the_freed_fraction() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            assert_eq!(allocator.free_list_utilization(), 0.0);
+            // Enough blocks that the uncarved end of the last commit hardly counts.
+            let count = 64 * allocator.page_size() / carved_size_of_subheap(2);
+            let ptrs: Vec<NonNull<[u8; 64]>> = (0..count).map(|_| allocator.alloc().unwrap()).collect();
+            assert!(allocator.free_list_utilization() < 0.05);
+
+            allocator.free_many(&ptrs[..count / 2]).unwrap();
+            assert!((allocator.free_list_utilization() - 0.5).abs() < 0.05);
+            allocator.free_many(&ptrs[count / 2..]).unwrap();
+            assert!(allocator.free_list_utilization() > 0.95);
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn hand_built_free_lists_pop_in_lifo_order() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let first: NonNull<u64> = allocator.alloc().unwrap();
+            let second: NonNull<u64> = allocator.alloc().unwrap();
+            let node = |ptr: NonNull<u64>| header_of(ptr).cast::<FreeHeader>();
+
+            // Setting the lists leaves the bytes alone, so give them up as `free` would.
+            allocator.live_subheap_bytes -= 2 * carved_size_of_subheap(0);
+            let mut free_lists = [std::ptr::null_mut(); SUBHEAP_COUNT];
+            allocator.set_next(node(first), std::ptr::null_mut());
+            allocator.set_next(node(second), node(first).as_ptr());
+            free_lists[0] = node(second).as_ptr();
+            allocator.set_free_list_state_for_testing(free_lists);
+            assert_eq!(allocator.clone_free_list_state_for_testing(), free_lists);
+
+            assert_eq!(allocator.alloc_on_subheap::<u64>(0).unwrap(), second);
+            assert_eq!(allocator.alloc_on_subheap::<u64>(0).unwrap(), first);
+            assert!(allocator.clone_free_list_state_for_testing()[0].is_null());
+            allocator.free_many(&[first, second]).unwrap();
+            assert_eq!(allocator.used_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn bad_munmap_sizes_surface_as_release_failures() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let ptr: NonNull<u8> = allocator.alloc_by_size(5000).unwrap();
+            let size = header_of(ptr).as_ref().size_or_class_of_subheap;
+            // A length running past the end of the address space.
+            header_of(ptr).as_mut().size_or_class_of_subheap = usize::MAX & !(allocator.page_size() - 1);
+            let error = allocator.free(ptr).unwrap_err();
+            assert!(matches!(error.downcast_ref::<AllocError>(), Some(AllocError::ReleaseFailed(_))), "{}", error);
+            assert_eq!(allocator.external_count(), 1);
+
+            header_of(ptr).as_mut().size_or_class_of_subheap = size;
+            allocator.free(ptr).unwrap();
+            assert_eq!(allocator.external_count(), 0);
+        }
+    }
+
+    #[test]
+    fn zero_on_free_clears_a_block_before_it_is_reused() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_zero_on_free(true);
+            let ptr: NonNull<u8> = allocator.alloc_by_size(200).unwrap();
+            let len = allocator.usable_size(ptr);
+            ptr.as_ptr().write_bytes(0xa5, len);
+            allocator.free(ptr).unwrap();
+
+            let reused: NonNull<u8> = allocator.alloc_by_size(200).unwrap();
+            assert_eq!(reused, ptr);
+            // Only the free list link, and its canary, were written back.
+            let link = size_of::<FreeHeader>() - size_of::<Header>();
+            let data = std::slice::from_raw_parts(reused.as_ptr(), len);
+            assert!(data[link..].iter().all(|&byte| byte == 0));
+            allocator.free(reused).unwrap();
+        }
+    }
+
+    #[test]
+    fn largest_available_block_follows_free_lists_and_the_committed_tail() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            assert_eq!(allocator.largest_available_block(), 0);
+
+            // Blocks filling the first page leave a committed tail too short
+            // for any block.
+            let page_size = allocator.page_size();
+            allocator.reserve_for(0, page_size / carved_size_of_subheap(0)).unwrap();
+            assert_eq!(allocator.largest_available_block(), block_size_of_subheap(0));
+
+            // The next commit leaves most of a page past the bump pointer.
+            allocator.reserve_for(3, 1).unwrap();
+            let top_class = allocator.subheap_count() - 1;
+            assert_eq!(allocator.largest_available_block(), block_size_of_subheap(top_class));
+            let ptr: NonNull<u8> = allocator.alloc_by_size(block_size_of_subheap(top_class)).unwrap();
+            #[cfg(feature = "profile")]
+            assert_eq!(allocator.syscall_counts().commit, 2);
+            allocator.free(ptr).unwrap();
+        }
+    }
+
+    #[test]
+    fn placed_blocks_sit_at_their_offsets_and_reject_overlaps() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            let heap_begin = allocator.heap_begin.as_ptr() as usize;
+            let first = allocator.allocate_at(16 * pagesize, 2 * pagesize).unwrap();
+            let second = allocator.allocate_at(32 * pagesize, pagesize).unwrap();
+            assert_eq!(first.as_ptr() as usize - heap_begin, 16 * pagesize);
+            assert_eq!(second.as_ptr() as usize - heap_begin, 32 * pagesize);
+            first.as_ptr().write_bytes(1, 2 * pagesize);
+            second.as_ptr().write_bytes(2, pagesize);
+
+            assert!(allocator.allocate_at(17 * pagesize, pagesize).is_err());
+            assert!(allocator.allocate_at(14 * pagesize, 3 * pagesize).is_err());
+            assert!(allocator.allocate_at(31 * pagesize, 2 * pagesize).is_err());
+            // Carved pages are off limits too.
+            let ptr: NonNull<u8> = allocator.alloc().unwrap();
+            assert!(allocator.allocate_at(0, pagesize).is_err());
+            allocator.allocate_at(18 * pagesize, 14 * pagesize).unwrap();
+            allocator.free(ptr).unwrap();
+        }
+    }
+
+    #[test]
+    fn commits_failing_partway_are_rolled_back() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let pagesize = allocator.page_size();
+            allocator.set_commit_granularity(4 * pagesize).unwrap();
+            let committed = allocator.commited_heap_end;
+            sys::COMMIT_FAILS_AFTER.set(Some(pagesize));
+            let error = allocator.alloc::<u64>().unwrap_err();
+            assert!(matches!(error.downcast_ref::<AllocError>(), Some(AllocError::CommitFailed(_))), "{}", error);
+            assert_eq!(allocator.commited_heap_end, committed);
+            assert!(write_faults(committed.as_ptr() as *mut u8));
+
+            let ptr: NonNull<u64> = allocator.alloc().unwrap();
+            assert_eq!(allocator.commited_heap_end.as_ptr() as usize - committed.as_ptr() as usize, 4 * pagesize);
+            allocator.free(ptr).unwrap();
+        }
+    }
+
+    #[test]
+    fn live_class_histogram_counts_the_blocks_left_allocated() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            let mut small: Vec<NonNull<u8>> = (0..10).map(|_| allocator.alloc_by_size(16).unwrap()).collect();
+            let mut medium: Vec<NonNull<u8>> = (0..6).map(|_| allocator.alloc_by_size(100).unwrap()).collect();
+            let large: NonNull<u8> = allocator.alloc_by_size(1000).unwrap();
+            let external: NonNull<u8> = allocator.alloc_by_size(5000).unwrap();
+            for ptr in small.drain(..4).chain(medium.drain(..6)) {
+                allocator.free(ptr).unwrap();
+            }
+
+            let mut expected = [0; SUBHEAP_COUNT];
+            expected[0] = 6;
+            expected[6] = 1;
+            assert_eq!(allocator.live_class_histogram(), expected);
+            assert_eq!(allocator.external_count(), 1);
+
+            for ptr in small.into_iter().chain([large, external]) {
+                allocator.free(ptr).unwrap();
+            }
+            assert_eq!(allocator.live_class_histogram(), [0; SUBHEAP_COUNT]);
+            assert_eq!(allocator.external_count(), 0);
+        }
+    }
+
+    #[test]
+    fn lazily_committed_heaps_grow_without_commits() {
+        unsafe {
+            let mut allocator = Allocator::init().unwrap();
+            allocator.set_lazy_commit().unwrap();
+            let commit_calls = sys::COMMIT_CALLS.get();
+            #[cfg(feature = "profile")]
+            let counts = allocator.syscall_counts();
+            let ptrs: Vec<NonNull<u8>> = (0..200_000)
+                .map(|i| {
+                    let ptr: NonNull<u8> = allocator.alloc_by_size(16 << (i % 7)).unwrap();
+                    ptr.as_ptr().write(1);
+                    ptr
+                })
+                .collect();
+            assert!(allocator.committed_subheap_bytes() > 8 << 20);
+            assert_eq!(sys::COMMIT_CALLS.get(), commit_calls);
+            #[cfg(feature = "profile")]
+            assert_eq!(allocator.syscall_counts().commit, counts.commit);
+            for ptr in ptrs {
+                allocator.free(ptr).unwrap();
+            }
+        }
+    }
+}
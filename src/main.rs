@@ -15,6 +15,8 @@ unsafe fn unsafe_main() {
 
     println!("Check value: {:?}, {:?}", ptr1, ptr2[10]);
 
+    // Heap allocated on purpose, next to the allocator's blocks.
+    #[allow(clippy::useless_vec)]
     let mut item1 = vec![1, 2, 3, 4, 5, 6];
     item1[2] = 333;
 